@@ -2,6 +2,7 @@
 
 use crate::rest;
 
+use chrono::{DateTime, Utc};
 use hyper::{header, Method, StatusCode};
 use mullvad_types::{location, relay_list};
 use talpid_types::net::wireguard;
@@ -10,7 +11,7 @@ use std::{
     collections::BTreeMap,
     future::Future,
     net::{Ipv4Addr, Ipv6Addr},
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
 /// Fetches relay list from https://api.mullvad.net/app/v1/relays
@@ -93,11 +94,15 @@ impl ServerRelayList {
             match split_location_code(&code) {
                 Some((country_code, city_code)) => {
                     let country_code = country_code.to_lowercase();
-                    let city_code = city_code.to_lowercase();
+                    let city = location_to_city(&location, city_code.to_lowercase());
+                    if let Err(error) = city.validate() {
+                        log::error!("Ignoring location {}: {}", code, error);
+                        continue;
+                    }
                     let country = countries
                         .entry(country_code.clone())
                         .or_insert_with(|| location_to_country(&location, country_code));
-                    country.cities.push(location_to_city(&location, city_code));
+                    country.cities.push(city);
                 }
                 None => {
                     log::error!("Bad location code:{}", code);
@@ -121,6 +126,7 @@ impl ServerRelayList {
                 .into_iter()
                 .map(|(_key, country)| country)
                 .collect(),
+            fetched_at: Some(SystemTime::now()),
         }
     }
 
@@ -175,13 +181,16 @@ impl ServerRelayList {
             relays,
         } = wireguard;
 
-        let wireguard_endpoint_data =
-            |public_key: wireguard::PublicKey| relay_list::WireguardEndpointData {
-                port_ranges: port_ranges.clone(),
-                ipv4_gateway,
-                ipv6_gateway,
-                public_key,
-            };
+        let wireguard_endpoint_data = |relay: &WireGuardRelay| relay_list::WireguardEndpointData {
+            port_ranges: relay
+                .port_ranges
+                .clone()
+                .unwrap_or_else(|| port_ranges.clone()),
+            gateways: vec![(ipv4_gateway, ipv6_gateway)],
+            public_key: relay.public_key.clone(),
+            daita: relay.daita,
+            feature_version: relay.feature_version,
+        };
 
         for mut wireguard_relay in relays {
             wireguard_relay.relay.convert_to_lowercase();
@@ -210,12 +219,12 @@ impl ServerRelayList {
                             Some(relay) => relay
                                 .tunnels
                                 .wireguard
-                                .push(wireguard_endpoint_data(wireguard_relay.public_key)),
+                                .push(wireguard_endpoint_data(&wireguard_relay)),
                             None => {
+                                let endpoint_data = wireguard_endpoint_data(&wireguard_relay);
                                 let mut relay = relay(wireguard_relay.relay, location);
                                 relay.ipv6_addr_in = Some(wireguard_relay.ipv6_addr_in);
-                                relay.tunnels.wireguard =
-                                    vec![wireguard_endpoint_data(wireguard_relay.public_key)];
+                                relay.tunnels.wireguard = vec![endpoint_data];
                                 city.relays.push(relay);
                             }
                         };
@@ -311,10 +320,17 @@ fn relay(relay: Relay, location: location::Location) -> relay_list::Relay {
         owned: relay.owned,
         provider: relay.provider,
         weight: relay.weight,
+        tags: relay.tags,
+        bandwidth_mbps: relay.bandwidth_mbps,
+        asn: relay.asn,
+        channel: relay.channel,
         tunnels: Default::default(),
         bridges: Default::default(),
         obfuscators: Default::default(),
         location: Some(location),
+        congestion: None,
+        rtt: None,
+        added_at: relay.added_at,
     }
 }
 
@@ -342,6 +358,16 @@ struct Relay {
     ipv4_addr_in: Ipv4Addr,
     weight: u64,
     include_in_country: bool,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    bandwidth_mbps: Option<u32>,
+    #[serde(default)]
+    asn: Option<u32>,
+    #[serde(default)]
+    channel: Option<String>,
+    #[serde(default)]
+    added_at: Option<DateTime<Utc>>,
 }
 
 impl Relay {
@@ -365,6 +391,13 @@ struct WireGuardRelay {
     relay: Relay,
     ipv6_addr_in: Ipv6Addr,
     public_key: wireguard::PublicKey,
+    #[serde(default)]
+    daita: bool,
+    #[serde(default)]
+    feature_version: u32,
+    /// Ports offered by this relay. Overrides the port ranges shared by all WireGuard relays.
+    #[serde(default)]
+    port_ranges: Option<Vec<(u16, u16)>>,
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -372,3 +405,107 @@ struct Bridges {
     shadowsocks: Vec<relay_list::ShadowsocksEndpointData>,
     relays: Vec<Relay>,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_relay_specific_port_ranges() {
+        let relay = |hostname: &str, port_ranges: Option<Vec<(u16, u16)>>| {
+            let mut relay = serde_json::json!({
+                "hostname": hostname,
+                "active": true,
+                "owned": true,
+                "location": "se-got",
+                "provider": "31173",
+                "ipv4_addr_in": "185.213.154.68",
+                "weight": 1,
+                "include_in_country": true,
+                "ipv6_addr_in": "2a03:1b20:5:f011::a09f",
+                "public_key": "BLNHNoGO88LjV/wDBa7CUUwUzPq/fO2UwcGLy56hKy4=",
+            });
+            if let Some(port_ranges) = port_ranges {
+                relay["port_ranges"] = serde_json::json!(port_ranges);
+            }
+            relay
+        };
+        let server_relay_list: ServerRelayList = serde_json::from_value(serde_json::json!({
+            "locations": {
+                "se-got": {
+                    "city": "Gothenburg",
+                    "country": "Sweden",
+                    "latitude": 57.70887,
+                    "longitude": 11.97456,
+                },
+            },
+            "openvpn": { "ports": [], "relays": [] },
+            "wireguard": {
+                "port_ranges": [[53, 53], [4000, 33433]],
+                "ipv4_gateway": "10.64.0.1",
+                "ipv6_gateway": "fc00:bbbb:bbbb:bb01::1",
+                "relays": [
+                    relay("se9-wireguard", None),
+                    relay("se10-wireguard", Some(vec![(443, 443), (51820, 51820)])),
+                ],
+            },
+            "bridge": { "shadowsocks": [], "relays": [] },
+        }))
+        .unwrap();
+
+        let relay_list = server_relay_list.into_relay_list(None);
+        let port_ranges = |hostname: &str| {
+            relay_list
+                .relays()
+                .find(|relay| relay.hostname == hostname)
+                .unwrap()
+                .tunnels
+                .wireguard[0]
+                .port_ranges
+                .clone()
+        };
+        assert_eq!(port_ranges("se9-wireguard"), vec![(53, 53), (4000, 33433)]);
+        assert_eq!(
+            port_ranges("se10-wireguard"),
+            vec![(443, 443), (51820, 51820)]
+        );
+    }
+
+    #[test]
+    fn test_invalid_coordinates() {
+        let location = |latitude: f64, longitude: f64| {
+            serde_json::json!({
+                "city": "Gothenburg",
+                "country": "Sweden",
+                "latitude": latitude,
+                "longitude": longitude,
+            })
+        };
+        let server_relay_list: ServerRelayList = serde_json::from_value(serde_json::json!({
+            "locations": {
+                "se-got": location(57.70887, 11.97456),
+                "se-sto": location(95.0, 18.0),
+                "no-osl": location(59.9, 190.0),
+            },
+            "openvpn": { "ports": [], "relays": [] },
+            "wireguard": {
+                "port_ranges": [],
+                "ipv4_gateway": "10.64.0.1",
+                "ipv6_gateway": "fc00:bbbb:bbbb:bb01::1",
+                "relays": [],
+            },
+            "bridge": { "shadowsocks": [], "relays": [] },
+        }))
+        .unwrap();
+
+        // Locations with out-of-range coordinates are ignored
+        let relay_list = server_relay_list.into_relay_list(None);
+        assert_eq!(relay_list.countries.len(), 1);
+        let cities: Vec<&str> = relay_list.countries[0]
+            .cities
+            .iter()
+            .map(|city| city.code.as_str())
+            .collect();
+        assert_eq!(cities, vec!["got"]);
+    }
+}
@@ -620,6 +620,12 @@ impl From<mullvad_types::relay_constraints::RelaySettings> for RelaySettings {
                             .entry_location
                             .option()
                             .map(RelayLocation::from),
+                        exit_port: u32::from(
+                            constraints.wireguard_constraints.exit_port.unwrap_or(0),
+                        ),
+                        require_different_country: constraints
+                            .wireguard_constraints
+                            .require_different_country,
                     }),
 
                     openvpn_constraints: Some(OpenvpnConstraints {
@@ -755,8 +761,8 @@ impl From<mullvad_types::relay_list::Relay> for Relay {
                             .collect();
                         WireguardEndpointData {
                             port_ranges,
-                            ipv4_gateway: endpoint.ipv4_gateway.to_string(),
-                            ipv6_gateway: endpoint.ipv6_gateway.to_string(),
+                            ipv4_gateway: endpoint.ipv4_gateway().to_string(),
+                            ipv6_gateway: endpoint.ipv6_gateway().to_string(),
                             public_key: endpoint.public_key.as_bytes().to_vec(),
                         }
                     })
@@ -872,6 +878,12 @@ impl TryFrom<&WireguardConstraints> for mullvad_types::relay_constraints::Wiregu
                 .clone()
                 .map(Constraint::<mullvad_types::relay_constraints::LocationConstraint>::from)
                 .unwrap_or(Constraint::Any),
+            exit_port: if constraints.exit_port == 0 {
+                Constraint::Any
+            } else {
+                Constraint::Only(constraints.exit_port as u16)
+            },
+            require_different_country: constraints.require_different_country,
         })
     }
 }
@@ -19,23 +19,25 @@ use mullvad_types::{
 use parking_lot::{Mutex, MutexGuard};
 use rand::{self, seq::SliceRandom, Rng};
 use std::{
+    collections::{HashMap, HashSet},
     io,
     net::{IpAddr, SocketAddr},
     path::Path,
     sync::Arc,
-    time::{self, SystemTime},
+    time::{self, Duration, Instant, SystemTime},
 };
 use talpid_types::{
     net::{
-        obfuscation::ObfuscatorConfig, openvpn::ProxySettings, wireguard, IpVersion,
-        TransportProtocol, TunnelType,
+        obfuscation::ObfuscatorConfig,
+        openvpn::{ProxySettings, SHADOWSOCKS_CIPHERS},
+        wireguard, IpVersion, TransportProtocol, TunnelType,
     },
     ErrorExt,
 };
 
 use self::matcher::{RelayMatcher, TunnelMatcher, WireguardMatcher};
 
-mod matcher;
+pub mod matcher;
 pub mod updater;
 
 const DATE_TIME_FORMAT_STR: &str = "%Y-%m-%d %H:%M:%S%.3f";
@@ -46,6 +48,14 @@ const WIREGUARD_EXIT_CONSTRAINTS: WireguardMatcher = WireguardMatcher {
     peer: None,
     port: Constraint::Only(DEFAULT_WIREGUARD_PORT),
     ip_version: Constraint::Only(IpVersion::V4),
+    key_index: None,
+    prefer_local_peer: false,
+    require_different_country: false,
+    require_daita: false,
+    port_scores: None,
+    source_port: None,
+    min_feature_version: 0,
+    port_usage: None,
 };
 
 const UDP2TCP_PORTS: [u16; 3] = [80, 443, 5001];
@@ -55,6 +65,26 @@ const UDP2TCP_PORTS: [u16; 3] = [80, 443, 5001];
 /// Its final weight equals `(base weight) ^ BRIDGE_PROXIMITY_BIAS`.
 const BRIDGE_PROXIMITY_BIAS: u32 = 3;
 
+/// Default factor that the weight of favorite relays is multiplied by.
+const DEFAULT_FAVORITE_WEIGHT_FACTOR: u64 = 10;
+
+/// Default number of distinct relays from a provider that must fail for the provider to be put
+/// under cooldown.
+const DEFAULT_PROVIDER_COOLDOWN_THRESHOLD: usize = 2;
+
+/// Default duration that a provider is under cooldown for.
+const DEFAULT_PROVIDER_COOLDOWN_DURATION: Duration = Duration::from_secs(10 * 60);
+
+/// Default factor that the weight of relays from providers under cooldown is divided by.
+const DEFAULT_PROVIDER_COOLDOWN_WEIGHT_DIVISOR: u64 = 10;
+
+/// Shadowsocks cipher to prefer when selecting a bridge endpoint. ChaCha20 is faster than AES on
+/// devices without hardware AES support, which is common on mobile.
+#[cfg(target_os = "android")]
+const PREFERRED_SHADOWSOCKS_CIPHER: Option<&str> = Some("chacha20-ietf-poly1305");
+#[cfg(not(target_os = "android"))]
+const PREFERRED_SHADOWSOCKS_CIPHER: Option<&str> = None;
+
 #[derive(err_derive::Error, Debug)]
 #[error(no_from)]
 pub enum Error {
@@ -67,6 +97,9 @@ pub enum Error {
     #[error(display = "No relays matching current constraints")]
     NoRelay,
 
+    #[error(display = "The relay list is empty")]
+    EmptyRelayList,
+
     #[error(display = "No bridges matching current constraints")]
     NoBridge,
 
@@ -197,6 +230,13 @@ impl ParsedRelays {
         self.last_updated
     }
 
+    /// Records that the relay list was confirmed to be current at `fetched_at`, e.g. because the
+    /// API responded that it has not been modified since it was downloaded.
+    pub fn mark_fetched(&mut self, fetched_at: SystemTime) {
+        self.last_updated = fetched_at;
+        self.locations.fetched_at = Some(fetched_at);
+    }
+
     pub fn locations(&self) -> &RelayList {
         &self.locations
     }
@@ -218,10 +258,124 @@ pub struct SelectorConfig {
     pub obfuscation_settings: ObfuscationSettings,
 }
 
+/// Relays that the user has marked as favorites. Favorite relays are preferred, but not forced,
+/// when selecting tunnel relays.
+#[derive(Debug, Clone)]
+pub struct RelayFavorites {
+    /// Hostnames of the favorite relays.
+    pub hostnames: HashSet<String>,
+    /// Factor that the weight of favorite relays is multiplied by during selection.
+    pub weight_factor: u64,
+}
+
+impl Default for RelayFavorites {
+    fn default() -> Self {
+        RelayFavorites {
+            hostnames: HashSet::new(),
+            weight_factor: DEFAULT_FAVORITE_WEIGHT_FACTOR,
+        }
+    }
+}
+
+/// Keeps track of relays that failed to connect, grouped by provider. When relays from the same
+/// provider keep failing, the provider is put under cooldown, during which the weight of all its
+/// relays is reduced. Failures and cooldowns expire after `duration`.
+#[derive(Debug, Clone)]
+pub struct ProviderCooldown {
+    /// Number of distinct relays from a provider that must fail within `duration` for the
+    /// provider to be put under cooldown.
+    pub threshold: usize,
+    /// How long failures are remembered, and how long a provider stays under cooldown.
+    pub duration: Duration,
+    /// Factor that the weight of relays from providers under cooldown is divided by. Relays with
+    /// a nonzero weight keep a weight of at least 1.
+    pub weight_divisor: u64,
+    /// Time of the latest failure of each relay, by provider and hostname.
+    failures: HashMap<String, HashMap<String, Instant>>,
+    /// Time at which the cooldown of each provider ends.
+    cooldowns: HashMap<String, Instant>,
+}
+
+impl ProviderCooldown {
+    pub fn new(threshold: usize, duration: Duration, weight_divisor: u64) -> Self {
+        ProviderCooldown {
+            threshold,
+            duration,
+            weight_divisor,
+            failures: HashMap::new(),
+            cooldowns: HashMap::new(),
+        }
+    }
+
+    /// Records that `relay` failed to connect, putting its provider under cooldown if enough of
+    /// its relays have failed recently.
+    pub fn record_failure(&mut self, relay: &Relay) {
+        let now = Instant::now();
+        self.expire(now);
+
+        let failures = self.failures.entry(relay.provider.clone()).or_default();
+        failures.insert(relay.hostname.clone(), now);
+        if failures.len() >= self.threshold {
+            log::debug!(
+                "Putting provider {} under cooldown after {} relays failed",
+                relay.provider,
+                failures.len()
+            );
+            self.failures.remove(&relay.provider);
+            self.cooldowns
+                .insert(relay.provider.clone(), now + self.duration);
+        }
+    }
+
+    /// Returns whether `provider` is under cooldown.
+    pub fn is_cooling_down(&self, provider: &str) -> bool {
+        self.is_cooling_down_at(provider, Instant::now())
+    }
+
+    fn is_cooling_down_at(&self, provider: &str, now: Instant) -> bool {
+        self.cooldowns
+            .get(provider)
+            .map(|until| now < *until)
+            .unwrap_or(false)
+    }
+
+    /// Returns the weight to use for a relay from `provider` with the given weight at `now`.
+    fn adjust_weight(&self, provider: &str, weight: u64, now: Instant) -> u64 {
+        if weight == 0 || !self.is_cooling_down_at(provider, now) {
+            return weight;
+        }
+        (weight / self.weight_divisor.max(1)).max(1)
+    }
+
+    /// Forgets failures and cooldowns that have expired.
+    fn expire(&mut self, now: Instant) {
+        let duration = self.duration;
+        self.cooldowns.retain(|_, until| now < *until);
+        for failures in self.failures.values_mut() {
+            failures.retain(|_, failed_at| now.duration_since(*failed_at) < duration);
+        }
+        self.failures.retain(|_, failures| !failures.is_empty());
+    }
+}
+
+impl Default for ProviderCooldown {
+    fn default() -> Self {
+        ProviderCooldown::new(
+            DEFAULT_PROVIDER_COOLDOWN_THRESHOLD,
+            DEFAULT_PROVIDER_COOLDOWN_DURATION,
+            DEFAULT_PROVIDER_COOLDOWN_WEIGHT_DIVISOR,
+        )
+    }
+}
+
 #[derive(Clone)]
 pub struct RelaySelector {
     config: Arc<Mutex<SelectorConfig>>,
     parsed_relays: Arc<Mutex<ParsedRelays>>,
+    favorites: Arc<Mutex<RelayFavorites>>,
+    avoided_provider: Arc<Mutex<Option<String>>>,
+    multihop_entry_policy: Arc<Mutex<MultihopEntryPolicy>>,
+    provider_cooldown: Arc<Mutex<ProviderCooldown>>,
 }
 
 impl RelaySelector {
@@ -247,6 +401,10 @@ impl RelaySelector {
         RelaySelector {
             config: Arc::new(Mutex::new(config)),
             parsed_relays: Arc::new(Mutex::new(unsynchronized_parsed_relays)),
+            favorites: Arc::new(Mutex::new(RelayFavorites::default())),
+            avoided_provider: Arc::new(Mutex::new(None)),
+            multihop_entry_policy: Arc::new(Mutex::new(MultihopEntryPolicy::default())),
+            provider_cooldown: Arc::new(Mutex::new(ProviderCooldown::default())),
         }
     }
 
@@ -254,6 +412,36 @@ impl RelaySelector {
         *self.config.lock() = config;
     }
 
+    /// Sets the relays that are preferred when selecting a tunnel relay. Favorites that do not
+    /// match the constraints are never selected.
+    pub fn set_favorites(&mut self, favorites: RelayFavorites) {
+        *self.favorites.lock() = favorites;
+    }
+
+    /// Sets a provider to avoid when selecting tunnel relays, such as the provider used in the
+    /// previous session. Relays from the avoided provider are only selected if no relays from
+    /// other providers match the constraints.
+    pub fn set_avoided_provider(&mut self, provider: Option<String>) {
+        *self.avoided_provider.lock() = provider;
+    }
+
+    /// Replaces the provider cooldown state, e.g. to change its parameters or to reset it.
+    pub fn set_provider_cooldown(&mut self, provider_cooldown: ProviderCooldown) {
+        *self.provider_cooldown.lock() = provider_cooldown;
+    }
+
+    /// Records that `relay` failed to connect. Relays from providers with several recent
+    /// failures are de-weighted for a while. See [`ProviderCooldown`].
+    pub fn record_relay_failure(&mut self, relay: &Relay) {
+        self.provider_cooldown.lock().record_failure(relay);
+    }
+
+    /// Sets how the entry relay of a WireGuard multihop tunnel is picked among the matching
+    /// relays. The exit relay is always picked by weight.
+    pub fn set_multihop_entry_policy(&mut self, policy: MultihopEntryPolicy) {
+        *self.multihop_entry_policy.lock() = policy;
+    }
+
     /// Returns all countries and cities. The cities in the object returned does not have any
     /// relays in them.
     pub fn get_locations(&mut self) -> RelayList {
@@ -278,6 +466,7 @@ impl RelaySelector {
                 Ok((SelectedRelay::Custom(custom_relay.clone()), None, None))
             }
             RelaySettings::Normal(constraints) => {
+                self.ensure_relays_available()?;
                 let relay =
                     self.get_tunnel_endpoint(constraints, config.bridge_state, retry_attempt)?;
                 let bridge = match relay.endpoint {
@@ -311,6 +500,76 @@ impl RelaySelector {
         }
     }
 
+    /// Returns a random relay and relay endpoint matching the given constraints, along with a
+    /// snapshot of the constraints that were actually applied. Unlike `relay_constraints`, the
+    /// snapshot reflects any preferences or relaxations that were applied during the selection,
+    /// such as the tunnel protocol and port that ended up being used.
+    pub fn select_resolved(
+        &self,
+        relay_constraints: &RelayConstraints,
+        retry_attempt: u32,
+    ) -> Result<ResolvedSelection, Error> {
+        self.ensure_relays_available()?;
+        let bridge_state = self.config.lock().bridge_state;
+        let selected_relay =
+            self.get_tunnel_endpoint(relay_constraints, bridge_state, retry_attempt)?;
+        let applied_constraints = Self::applied_constraints(relay_constraints, &selected_relay);
+
+        Ok(ResolvedSelection {
+            relay: selected_relay.exit_relay,
+            endpoint: selected_relay.endpoint,
+            applied_constraints,
+        })
+    }
+
+    /// Returns [`Error::EmptyRelayList`] if there are no relays to select from, so that selection
+    /// can bail out before running any matchers.
+    fn ensure_relays_available(&self) -> Result<(), Error> {
+        if self.parsed_relays.lock().relays().is_empty() {
+            return Err(Error::EmptyRelayList);
+        }
+        Ok(())
+    }
+
+    /// Narrows `relay_constraints` down to the tunnel protocol, port and IP version used by
+    /// `selected_relay`.
+    fn applied_constraints(
+        relay_constraints: &RelayConstraints,
+        selected_relay: &NormalSelectedRelay,
+    ) -> RelayConstraints {
+        let mut applied_constraints = relay_constraints.clone();
+        let endpoint = selected_relay.endpoint.to_endpoint();
+        match selected_relay.endpoint {
+            MullvadEndpoint::OpenVpn(_) => {
+                applied_constraints.tunnel_protocol = Constraint::Only(TunnelType::OpenVpn);
+                applied_constraints.openvpn_constraints.port = Constraint::Only(TransportPort {
+                    protocol: endpoint.protocol,
+                    port: Constraint::Only(endpoint.address.port()),
+                });
+            }
+            MullvadEndpoint::Wireguard(_) => {
+                let wireguard_constraints = &mut applied_constraints.wireguard_constraints;
+                applied_constraints.tunnel_protocol = Constraint::Only(TunnelType::Wireguard);
+                wireguard_constraints.port = Constraint::Only(endpoint.address.port());
+                wireguard_constraints.ip_version = Constraint::Only(match endpoint.address {
+                    SocketAddr::V4(_) => IpVersion::V4,
+                    SocketAddr::V6(_) => IpVersion::V6,
+                });
+                if let Some(entry_relay) = &selected_relay.entry_relay {
+                    if let Some(location) = &entry_relay.location {
+                        wireguard_constraints.entry_location =
+                            Constraint::Only(LocationConstraint::Hostname(
+                                location.country_code.clone(),
+                                location.city_code.clone(),
+                                entry_relay.hostname.clone(),
+                            ));
+                    }
+                }
+            }
+        }
+        applied_constraints
+    }
+
     /// Returns a random relay and relay endpoint matching the given constraints and with
     /// preferences applied.
     fn get_tunnel_endpoint(
@@ -345,7 +604,7 @@ impl RelaySelector {
     /// Returns the average location of relays that match the given constraints.
     /// This returns none if the location is `any` or if no relays match the constraints.
     pub fn get_relay_midpoint(&self, relay_constraints: &RelayConstraints) -> Option<Coordinates> {
-        if relay_constraints.location.is_any() {
+        if relay_constraints.location.is_any() || self.ensure_relays_available().is_err() {
             return None;
         }
 
@@ -381,12 +640,12 @@ impl RelaySelector {
         bridge_state: BridgeState,
         retry_attempt: u32,
     ) -> Result<NormalSelectedRelay, Error> {
-        let mut relay_matcher = RelayMatcher {
-            location: location.clone(),
-            providers: providers.clone(),
-            ownership: *ownership,
-            tunnel: openvpn_constraints,
-        };
+        let mut relay_matcher = RelayMatcher::new(
+            location.clone(),
+            providers.clone(),
+            *ownership,
+            openvpn_constraints,
+        );
 
         if relay_matcher.tunnel.port.is_any() && bridge_state == BridgeState::On {
             relay_matcher.tunnel.port = Constraint::Only(TransportPort {
@@ -427,14 +686,23 @@ impl RelaySelector {
         }
     }
 
+    /// Selects an entry and an exit relay for a WireGuard multihop tunnel. The entry relay is
+    /// connected to on the port constrained by `entry_matcher`, and the exit relay is reached
+    /// through the entry relay on `exit_port`, or on the default WireGuard port if it is
+    /// unconstrained.
     fn get_wireguard_multi_hop_endpoint(
         &self,
         mut entry_matcher: RelayMatcher<WireguardMatcher>,
         exit_location: Constraint<LocationConstraint>,
+        exit_port: Constraint<u16>,
     ) -> Result<NormalSelectedRelay, Error> {
         let mut exit_matcher = RelayMatcher {
             location: exit_location,
-            tunnel: WIREGUARD_EXIT_CONSTRAINTS.clone(),
+            tunnel: WireguardMatcher {
+                port: exit_port.or(WIREGUARD_EXIT_CONSTRAINTS.port),
+                require_different_country: entry_matcher.tunnel.require_different_country,
+                ..WIREGUARD_EXIT_CONSTRAINTS.clone()
+            },
             ..entry_matcher.clone()
         };
 
@@ -489,12 +757,12 @@ impl RelaySelector {
         wireguard_constraints: &WireguardConstraints,
         retry_attempt: u32,
     ) -> Result<NormalSelectedRelay, Error> {
-        let mut entry_relay_matcher = RelayMatcher {
-            location: location.clone(),
-            providers: providers.clone(),
-            ownership: *ownership,
-            tunnel: wireguard_constraints.clone().into(),
-        };
+        let mut entry_relay_matcher = RelayMatcher::new(
+            location.clone(),
+            providers.clone(),
+            *ownership,
+            wireguard_constraints.clone().into(),
+        );
 
         let mut preferred_matcher: RelayMatcher<WireguardMatcher> = entry_relay_matcher.clone();
         preferred_matcher.tunnel.port = preferred_matcher
@@ -513,7 +781,11 @@ impl RelaySelector {
             .tunnel
             .port
             .or(Self::preferred_wireguard_port(retry_attempt));
-        self.get_wireguard_multi_hop_endpoint(entry_relay_matcher, location.clone())
+        self.get_wireguard_multi_hop_endpoint(
+            entry_relay_matcher,
+            location.clone(),
+            wireguard_constraints.exit_port,
+        )
     }
 
     /// Like [Self::get_tunnel_endpoint_internal] but also selects an entry endpoint if applicable.
@@ -536,7 +808,16 @@ impl RelaySelector {
 
         // Pick the entry relay first if its location constraint is a subset of the exit location.
         if relay_constraints.wireguard_constraints.use_multihop {
-            matcher.tunnel.wireguard = WIREGUARD_EXIT_CONSTRAINTS.clone();
+            matcher.tunnel.wireguard = WireguardMatcher {
+                port: relay_constraints
+                    .wireguard_constraints
+                    .exit_port
+                    .or(WIREGUARD_EXIT_CONSTRAINTS.port),
+                require_different_country: relay_constraints
+                    .wireguard_constraints
+                    .require_different_country,
+                ..WIREGUARD_EXIT_CONSTRAINTS.clone()
+            };
             if relay_constraints
                 .wireguard_constraints
                 .entry_location
@@ -617,6 +898,22 @@ impl RelaySelector {
                 retry_attempt
             );
             Ok(result)
+        } else if let Ok(result) = self.get_openvpn_endpoint(
+            // WireGuard constraints that cannot be satisfied, such as a port outside of all port
+            // ranges or a multihop entry location without relays, must not prevent OpenVPN from
+            // being used when the tunnel protocol isn't constrained.
+            &relay_constraints.location,
+            &relay_constraints.providers,
+            &relay_constraints.ownership,
+            relay_constraints.openvpn_constraints,
+            bridge_state,
+            retry_attempt,
+        ) {
+            log::debug!(
+                "No WireGuard relay matched, falling back to OpenVPN for retry attempt {}",
+                retry_attempt
+            );
+            Ok(result)
         } else {
             log::warn!("No relays matching {}", &relay_constraints);
             Err(Error::NoRelay)
@@ -713,9 +1010,22 @@ impl RelaySelector {
             .filter(|relay| relay.active)
             .filter_map(|relay| matcher.filter_matching_relay(relay))
             .collect();
+        let matching_relays = match &matcher.tunnel.peer {
+            Some(peer) if matcher.tunnel.prefer_local_peer => {
+                Self::prefer_local_relays(matching_relays, peer)
+            }
+            _ => matching_relays,
+        };
 
-        let relay = self
-            .pick_random_relay(&matching_relays)
+        let lowest_latency_relay = match *self.multihop_entry_policy.lock() {
+            MultihopEntryPolicy::Weighted => None,
+            MultihopEntryPolicy::LowestLatency => matching_relays
+                .iter()
+                .filter(|relay| relay.rtt.is_some())
+                .min_by_key(|relay| relay.rtt),
+        };
+        let relay = lowest_latency_relay
+            .or_else(|| self.pick_random_tunnel_relay(&matching_relays, matcher))
             .cloned()
             .ok_or(Error::NoRelay)?;
         let endpoint = matcher
@@ -727,6 +1037,34 @@ impl RelaySelector {
         Ok((relay, endpoint))
     }
 
+    /// Returns the relays in the same /24 subnet as `peer` if there are any, or otherwise the
+    /// relays in the same city as `peer`. Returns all `relays` if neither exist.
+    fn prefer_local_relays(relays: Vec<Relay>, peer: &Relay) -> Vec<Relay> {
+        let peer_subnet = &peer.ipv4_addr_in.octets()[..3];
+        let same_subnet: Vec<Relay> = relays
+            .iter()
+            .filter(|relay| &relay.ipv4_addr_in.octets()[..3] == peer_subnet)
+            .cloned()
+            .collect();
+        if !same_subnet.is_empty() {
+            return same_subnet;
+        }
+
+        let same_city: Vec<Relay> = relays
+            .iter()
+            .filter(|relay| match (&relay.location, &peer.location) {
+                (Some(location), Some(peer_location)) => location.has_same_city(peer_location),
+                _ => false,
+            })
+            .cloned()
+            .collect();
+        if !same_city.is_empty() {
+            return same_city;
+        }
+
+        relays
+    }
+
     fn set_entry_peers(
         exit_peer: &wireguard::PeerConfig,
         entry_endpoint: &mut MullvadWireguardEndpoint,
@@ -749,6 +1087,8 @@ impl RelaySelector {
                     ownership: settings.ownership,
                     // FIXME: This is temporary while talpid-core only supports TCP proxies
                     transport_protocol: Constraint::Only(TransportProtocol::Tcp),
+                    port: Constraint::Any,
+                    preferred_cipher: PREFERRED_SHADOWSOCKS_CIPHER.map(str::to_string),
                 };
                 match config.bridge_state {
                     BridgeState::On => {
@@ -780,6 +1120,7 @@ impl RelaySelector {
 
     /// Returns a bridge based on the relay and bridge constraints, ignoring the bridge state.
     pub fn get_bridge_forced(&self) -> Option<ProxySettings> {
+        self.ensure_relays_available().ok()?;
         let config = self.config.lock();
 
         let near_location = match &config.relay_settings {
@@ -793,12 +1134,16 @@ impl RelaySelector {
                 providers: settings.providers.clone(),
                 ownership: settings.ownership,
                 transport_protocol: Constraint::Only(TransportProtocol::Tcp),
+                port: Constraint::Any,
+                preferred_cipher: PREFERRED_SHADOWSOCKS_CIPHER.map(str::to_string),
             },
             BridgeSettings::Custom(_bridge_settings) => InternalBridgeConstraints {
                 location: Constraint::Any,
                 providers: Constraint::Any,
                 ownership: Constraint::Any,
                 transport_protocol: Constraint::Only(TransportProtocol::Tcp),
+                port: Constraint::Any,
+                preferred_cipher: PREFERRED_SHADOWSOCKS_CIPHER.map(str::to_string),
             },
         };
 
@@ -849,8 +1194,13 @@ impl RelaySelector {
             self.pick_random_relay(&matching_relays)
         };
         relay.and_then(|relay| {
-            self.pick_random_bridge(relay)
-                .map(|bridge| (bridge, relay.clone()))
+            self.pick_random_bridge(
+                relay,
+                constraints.transport_protocol,
+                constraints.port,
+                constraints.preferred_cipher.as_deref(),
+            )
+            .map(|bridge| (bridge, relay.clone()))
         })
     }
 
@@ -863,6 +1213,83 @@ impl RelaySelector {
         self.get_obfuscator_inner(&self.config.lock(), relay, endpoint, retry_attempt)
     }
 
+    /// Picks a relay in `list` that matches the current constraints, and returns its endpoint
+    /// together with the obfuscator that the tunnel traffic should be wrapped in according to the
+    /// current obfuscation settings and `retry_attempt`. The selection is done the same way as in
+    /// [`Self::get_relay`], so multihop, the bridge state and per-attempt preferences apply. When
+    /// multihop is used, the obfuscator wraps the traffic to the entry relay. The relays in
+    /// `list` are prepared the same way as relay lists loaded by the selector. Returns `None` if
+    /// no relay matches, or if a custom tunnel endpoint is used.
+    pub fn select_obfuscated(
+        &self,
+        list: &RelayList,
+        retry_attempt: u32,
+    ) -> Option<SelectedEndpoint> {
+        let selector = self.with_relay_list(list);
+        let config = self.config.lock();
+        let mut constraints = match &config.relay_settings {
+            RelaySettings::Normal(constraints) => constraints.clone(),
+            RelaySettings::CustomTunnelEndpoint(_) => return None,
+        };
+        if config.obfuscation_settings.selected_obfuscation == SelectedObfuscation::Udp2Tcp {
+            // Only WireGuard relays can be reached through udp2tcp
+            constraints.tunnel_protocol = Constraint::Only(TunnelType::Wireguard);
+        }
+
+        let relay = selector
+            .get_tunnel_endpoint(&constraints, config.bridge_state, retry_attempt)
+            .ok()?;
+        let obfuscation = match &relay.endpoint {
+            MullvadEndpoint::Wireguard(endpoint) => {
+                let obfuscator_relay = relay.entry_relay.as_ref().unwrap_or(&relay.exit_relay);
+                self.get_obfuscator_inner(&config, obfuscator_relay, endpoint, retry_attempt)
+                    .ok()?
+            }
+            MullvadEndpoint::OpenVpn(_) => None,
+        };
+        Some(SelectedEndpoint {
+            inner: relay.endpoint,
+            obfuscation,
+        })
+    }
+
+    /// Picks a relay in `list` using the first set of `constraints` that yields a relay, so that
+    /// the sets can be ordered from the most to the least specific, e.g. a city, then its country
+    /// and then any location. Each set is tried the same way as in [`Self::get_relay`], so
+    /// multihop, the bridge state and per-attempt preferences apply. Returns the selected relay
+    /// together with the index of the set that was used, or `None` if no set yields a relay.
+    pub fn select_with_fallbacks(
+        &self,
+        list: &RelayList,
+        constraints: &[RelayConstraints],
+        retry_attempt: u32,
+    ) -> Option<(NormalSelectedRelay, usize)> {
+        let selector = self.with_relay_list(list);
+        let bridge_state = self.config.lock().bridge_state;
+        constraints
+            .iter()
+            .enumerate()
+            .find_map(|(index, constraints)| {
+                selector
+                    .get_tunnel_endpoint(constraints, bridge_state, retry_attempt)
+                    .ok()
+                    .map(|relay| (relay, index))
+            })
+    }
+
+    /// Returns a selector that shares the configuration and state of this one, but selects
+    /// relays from `list`. The relays in `list` are prepared the same way as relay lists loaded
+    /// by the selector.
+    fn with_relay_list(&self, list: &RelayList) -> RelaySelector {
+        RelaySelector {
+            parsed_relays: Arc::new(Mutex::new(ParsedRelays::from_relay_list(
+                list.clone(),
+                SystemTime::now(),
+            ))),
+            ..self.clone()
+        }
+    }
+
     fn get_obfuscator_inner(
         &self,
         config: &MutexGuard<'_, SelectorConfig>,
@@ -890,6 +1317,31 @@ impl RelaySelector {
         }
     }
 
+    /// Picks an obfuscation method supported by `relay`, trying each kind of obfuscation in the
+    /// order given by `preference`. Returns `None` if the relay supports none of them.
+    pub fn select_obfuscation(
+        &self,
+        relay: &Relay,
+        preference: &[ObfuscationKind],
+    ) -> Option<ObfuscationMethod> {
+        preference.iter().find_map(|kind| match kind {
+            ObfuscationKind::Udp2Tcp => relay
+                .obfuscators
+                .udp2tcp
+                .choose(&mut rand::thread_rng())
+                .map(|endpoint| ObfuscationMethod::Udp2Tcp(endpoint.port)),
+            ObfuscationKind::Shadowsocks => self
+                // FIXME: This is temporary while talpid-core only supports TCP proxies
+                .pick_random_bridge(
+                    relay,
+                    Constraint::Only(TransportProtocol::Tcp),
+                    Constraint::Any,
+                    PREFERRED_SHADOWSOCKS_CIPHER,
+                )
+                .map(ObfuscationMethod::Shadowsocks),
+        })
+    }
+
     fn get_auto_obfuscator(
         &self,
         obfuscation_settings: &ObfuscationSettings,
@@ -1058,7 +1510,7 @@ impl RelaySelector {
             .filter_map(|relay| matcher.filter_matching_relay(relay))
             .collect();
 
-        self.pick_random_relay(&matching_relays)
+        self.pick_random_tunnel_relay(&matching_relays, matcher)
             .and_then(|selected_relay| {
                 let endpoint = matcher.mullvad_endpoint(selected_relay);
                 let addr_in = endpoint
@@ -1083,10 +1535,10 @@ impl RelaySelector {
         }
 
         let mut filtered_relay = relay.clone();
-        filtered_relay
-            .bridges
-            .shadowsocks
-            .retain(|bridge| constraints.transport_protocol.matches_eq(&bridge.protocol));
+        filtered_relay.bridges.shadowsocks.retain(|bridge| {
+            constraints.transport_protocol.matches_eq(&bridge.protocol)
+                && constraints.port.matches_eq(&bridge.port)
+        });
         if filtered_relay.bridges.shadowsocks.is_empty() {
             return None;
         }
@@ -1100,6 +1552,32 @@ impl RelaySelector {
         self.pick_random_relay_fn(relays, |_index, relay| relay.weight)
     }
 
+    /// Picks a relay using [Self::pick_random_relay_fn], using the selection weight given by
+    /// `matcher` as the weight function. The weight of favorite relays is multiplied by the
+    /// favorite weight factor, the weight of relays from providers under cooldown is reduced, and
+    /// relays from the avoided provider get a weight of 0.
+    fn pick_random_tunnel_relay<'a, T: TunnelMatcher>(
+        &self,
+        relays: &'a [Relay],
+        matcher: &RelayMatcher<T>,
+    ) -> Option<&'a Relay> {
+        let favorites = self.favorites.lock();
+        let avoided_provider = self.avoided_provider.lock();
+        let provider_cooldown = self.provider_cooldown.lock();
+        let now = Instant::now();
+        self.pick_random_relay_fn(relays, |_index, relay| {
+            let weight = matcher.selection_weight(relay);
+            let weight = if avoided_provider.as_ref() == Some(&relay.provider) {
+                0
+            } else if favorites.hostnames.contains(&relay.hostname) {
+                weight.saturating_mul(favorites.weight_factor)
+            } else {
+                weight
+            };
+            provider_cooldown.adjust_weight(&relay.provider, weight, now)
+        })
+    }
+
     /// Pick a random relay from the given slice. Will return `None` if the given slice is empty.
     /// If all of the relays have a weight of 0, one will be picked at random without bias,
     /// otherwise roulette wheel selection will be used to pick only relays with non-zero
@@ -1135,11 +1613,51 @@ impl RelaySelector {
         }
     }
 
-    /// Picks a random bridge from a relay.
-    fn pick_random_bridge(&self, relay: &Relay) -> Option<ProxySettings> {
-        relay
+    /// Picks a random bridge from a relay. Only bridges using `transport_protocol` and `port` are
+    /// considered. Bridges using `preferred_cipher` are picked if the relay has any, otherwise any
+    /// bridge may be picked.
+    fn pick_random_bridge(
+        &self,
+        relay: &Relay,
+        transport_protocol: Constraint<TransportProtocol>,
+        port: Constraint<u16>,
+        preferred_cipher: Option<&str>,
+    ) -> Option<ProxySettings> {
+        let supported_bridges: Vec<_> = relay
             .bridges
             .shadowsocks
+            .iter()
+            .filter(|endpoint| {
+                transport_protocol.matches_eq(&endpoint.protocol) && port.matches_eq(&endpoint.port)
+            })
+            .collect();
+        if supported_bridges.len() < relay.bridges.shadowsocks.len() {
+            log::debug!(
+                "Rejected {} Shadowsocks bridges on {} with unsupported transport protocols or ports",
+                relay.bridges.shadowsocks.len() - supported_bridges.len(),
+                relay.hostname
+            );
+        }
+
+        let preferred_cipher = preferred_cipher.filter(|cipher| {
+            let is_supported = SHADOWSOCKS_CIPHERS.contains(cipher);
+            if !is_supported {
+                log::warn!("Ignoring unsupported Shadowsocks cipher \"{}\"", cipher);
+            }
+            is_supported
+        });
+        let preferred_bridges: Vec<_> = supported_bridges
+            .iter()
+            .copied()
+            .filter(|endpoint| Some(endpoint.cipher.as_str()) == preferred_cipher)
+            .collect();
+        let bridges = if preferred_bridges.is_empty() {
+            supported_bridges
+        } else {
+            preferred_bridges
+        };
+
+        bridges
             .choose(&mut rand::thread_rng())
             .map(|shadowsocks_endpoint| {
                 log::info!(
@@ -1149,9 +1667,7 @@ impl RelaySelector {
                     shadowsocks_endpoint.port,
                     shadowsocks_endpoint.protocol
                 );
-                shadowsocks_endpoint
-                    .clone()
-                    .to_proxy_settings(relay.ipv4_addr_in.into())
+                shadowsocks_endpoint.to_proxy_settings(relay.ipv4_addr_in.into())
             })
     }
 
@@ -1208,12 +1724,57 @@ pub struct NormalSelectedRelay {
     pub entry_relay: Option<Relay>,
 }
 
+/// A relay selection along with the constraints that were applied to make it. Returned by
+/// [`RelaySelector::select_resolved`].
+#[derive(Debug)]
+pub struct ResolvedSelection {
+    pub relay: Relay,
+    pub endpoint: MullvadEndpoint,
+    pub applied_constraints: RelayConstraints,
+}
+
+/// How the entry relay of a WireGuard multihop tunnel is picked among the relays matching the
+/// constraints. Set using [`RelaySelector::set_multihop_entry_policy`].
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum MultihopEntryPolicy {
+    /// Pick a random relay, weighted like any other tunnel relay.
+    #[default]
+    Weighted,
+    /// Pick the relay with the lowest round-trip time. Falls back to [`Self::Weighted`] if no
+    /// matching relay has a known round-trip time.
+    LowestLatency,
+}
+
+/// A kind of obfuscation that [`RelaySelector::select_obfuscation`] may pick.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ObfuscationKind {
+    Udp2Tcp,
+    Shadowsocks,
+}
+
+/// An obfuscation method picked by [`RelaySelector::select_obfuscation`].
+#[derive(Debug, Clone)]
+pub enum ObfuscationMethod {
+    /// Use udp2tcp on the given port of the relay.
+    Udp2Tcp(u16),
+    /// Use the given Shadowsocks bridge of the relay.
+    Shadowsocks(ProxySettings),
+}
+
 #[derive(Debug)]
 pub struct SelectedObfuscator {
     pub config: ObfuscatorConfig,
     pub relay: Relay,
 }
 
+/// A tunnel endpoint along with the obfuscator, if any, that wraps the tunnel traffic. Returned
+/// by [`RelaySelector::select_obfuscated`].
+#[derive(Debug)]
+pub struct SelectedEndpoint {
+    pub inner: MullvadEndpoint,
+    pub obfuscation: Option<SelectedObfuscator>,
+}
+
 impl NormalSelectedRelay {
     fn new(endpoint: MullvadEndpoint, exit_relay: Relay) -> Self {
         Self {
@@ -1243,14 +1804,16 @@ mod test {
         relay_constraints::{BridgeConstraints, RelayConstraints},
         relay_list::{
             OpenVpnEndpointData, Relay, RelayBridges, RelayListCity, RelayListCountry,
-            RelayObfuscators, RelayTunnels, WireguardEndpointData,
+            RelayObfuscators, RelayTunnels, ShadowsocksEndpointData, WireguardEndpointData,
         },
     };
+    use std::time::Duration;
     use talpid_types::net::wireguard::PublicKey;
 
     lazy_static::lazy_static! {
         static ref RELAYS: RelayList = RelayList {
             etag: None,
+            fetched_at: None,
             countries: vec![
                 RelayListCountry {
                     name: "Sweden".to_string(),
@@ -1271,14 +1834,22 @@ mod test {
                                     owned: true,
                                     provider: "31173".to_string(),
                                     weight: 1,
+                                    tags: vec![],
+                                    bandwidth_mbps: None,
+                                    asn: None,
+                                    channel: None,
                                     tunnels: RelayTunnels {
                                         openvpn: vec![],
                                         wireguard: vec![
                                             WireguardEndpointData {
                                                 port_ranges: vec![(53, 53), (4000, 33433), (33565, 51820), (52000, 60000)],
-                                                ipv4_gateway: "10.64.0.1".parse().unwrap(),
-                                                ipv6_gateway: "fc00:bbbb:bbbb:bb01::1".parse().unwrap(),
+                                                gateways: vec![(
+                                                    "10.64.0.1".parse().unwrap(),
+                                                    "fc00:bbbb:bbbb:bb01::1".parse().unwrap(),
+                                                )],
                                                 public_key: PublicKey::from_base64("BLNHNoGO88LjV/wDBa7CUUwUzPq/fO2UwcGLy56hKy4=").unwrap(),
+                                                daita: false,
+                                                feature_version: 0,
                                             },
                                         ],
                                     },
@@ -1289,6 +1860,9 @@ mod test {
                                         udp2tcp: vec![],
                                     },
                                     location: None,
+                                    congestion: None,
+                                    rtt: None,
+                                    added_at: None,
                                 },
                                 Relay {
                                     hostname: "se10-wireguard".to_string(),
@@ -1299,14 +1873,22 @@ mod test {
                                     owned: false,
                                     provider: "31173".to_string(),
                                     weight: 1,
+                                    tags: vec![],
+                                    bandwidth_mbps: None,
+                                    asn: None,
+                                    channel: None,
                                     tunnels: RelayTunnels {
                                         openvpn: vec![],
                                         wireguard: vec![
                                             WireguardEndpointData {
                                                 port_ranges: vec![(53, 53), (4000, 33433), (33565, 51820), (52000, 60000)],
-                                                ipv4_gateway: "10.64.0.1".parse().unwrap(),
-                                                ipv6_gateway: "fc00:bbbb:bbbb:bb01::1".parse().unwrap(),
+                                                gateways: vec![(
+                                                    "10.64.0.1".parse().unwrap(),
+                                                    "fc00:bbbb:bbbb:bb01::1".parse().unwrap(),
+                                                )],
                                                 public_key: PublicKey::from_base64("veGD6/aEY6sMfN3Ls7YWPmNgu3AheO7nQqsFT47YSws=").unwrap(),
+                                                daita: false,
+                                                feature_version: 0,
                                             },
                                         ],
                                     },
@@ -1317,6 +1899,9 @@ mod test {
                                         udp2tcp: vec![],
                                     },
                                     location: None,
+                                    congestion: None,
+                                    rtt: None,
+                                    added_at: None,
                                 },
                                 Relay {
                                     hostname: "se-got-001".to_string(),
@@ -1327,6 +1912,10 @@ mod test {
                                     owned: true,
                                     provider: "31173".to_string(),
                                     weight: 1,
+                                    tags: vec![],
+                                    bandwidth_mbps: None,
+                                    asn: None,
+                                    channel: None,
                                     tunnels: RelayTunnels {
                                         openvpn: vec![
                                             OpenVpnEndpointData {
@@ -1351,6 +1940,9 @@ mod test {
                                         udp2tcp: vec![],
                                     },
                                     location: None,
+                                    congestion: None,
+                                    rtt: None,
+                                    added_at: None,
                                 },
                                 Relay {
                                     hostname: "se11-wireguard-filtered".to_string(),
@@ -1361,14 +1953,22 @@ mod test {
                                     owned: true,
                                     provider: "31173".to_string(),
                                     weight: 1,
+                                    tags: vec![],
+                                    bandwidth_mbps: None,
+                                    asn: None,
+                                    channel: None,
                                     tunnels: RelayTunnels {
                                         openvpn: vec![],
                                         wireguard: vec![
                                             WireguardEndpointData {
                                                 port_ranges: vec![],
-                                                ipv4_gateway: "10.64.0.1".parse().unwrap(),
-                                                ipv6_gateway: "fc00:bbbb:bbbb:bb01::1".parse().unwrap(),
+                                                gateways: vec![(
+                                                    "10.64.0.1".parse().unwrap(),
+                                                    "fc00:bbbb:bbbb:bb01::1".parse().unwrap(),
+                                                )],
                                                 public_key: PublicKey::from_base64("veGD6/aEY6sMfN3Ls7YWPmNgu3AheO7nQqsFT47YSws=").unwrap(),
+                                                daita: false,
+                                                feature_version: 0,
                                             },
                                         ],
                                     },
@@ -1379,6 +1979,9 @@ mod test {
                                         udp2tcp: vec![],
                                     },
                                     location: None,
+                                    congestion: None,
+                                    rtt: None,
+                                    added_at: None,
                                 },
                                 Relay {
                                     hostname: "se-got-010-filtered".to_string(),
@@ -1389,6 +1992,10 @@ mod test {
                                     owned: true,
                                     provider: "31173".to_string(),
                                     weight: 1,
+                                    tags: vec![],
+                                    bandwidth_mbps: None,
+                                    asn: None,
+                                    channel: None,
                                     tunnels: RelayTunnels {
                                         openvpn: vec![OpenVpnEndpointData{
                                             port: 0,
@@ -1403,6 +2010,9 @@ mod test {
                                         udp2tcp: vec![],
                                     },
                                     location: None,
+                                    congestion: None,
+                                    rtt: None,
+                                    added_at: None,
                                 }
                             ],
                         },
@@ -1430,6 +2040,10 @@ mod test {
                 },
                 bridge_state: BridgeState::Auto,
             })),
+            favorites: Arc::new(Mutex::new(RelayFavorites::default())),
+            avoided_provider: Arc::new(Mutex::new(None)),
+            multihop_entry_policy: Arc::new(Mutex::new(MultihopEntryPolicy::default())),
+            provider_cooldown: Arc::new(Mutex::new(ProviderCooldown::default())),
         }
     }
 
@@ -1733,6 +2347,8 @@ mod test {
             port: Constraint::Any,
             ip_version: Constraint::Any,
             entry_location: Constraint::Any,
+            exit_port: Constraint::Any,
+            require_different_country: false,
         },
         tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
         openvpn_constraints: OpenVpnConstraints {
@@ -1749,6 +2365,8 @@ mod test {
             port: Constraint::Any,
             ip_version: Constraint::Any,
             entry_location: Constraint::Any,
+            exit_port: Constraint::Any,
+            require_different_country: false,
         },
         tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
         openvpn_constraints: OpenVpnConstraints {
@@ -1796,6 +2414,101 @@ mod test {
         ));
     }
 
+    #[test]
+    fn test_select_obfuscated() {
+        let relay_selector = new_relay_selector();
+
+        // Without obfuscation, the endpoint is used directly
+        for _ in 0..100 {
+            let selected = relay_selector.select_obfuscated(&RELAYS, 0).unwrap();
+            assert!(selected.obfuscation.is_none());
+        }
+
+        relay_selector.config.lock().obfuscation_settings = ObfuscationSettings {
+            selected_obfuscation: SelectedObfuscation::Udp2Tcp,
+            ..ObfuscationSettings::default()
+        };
+        for _ in 0..100 {
+            let selected = relay_selector.select_obfuscated(&RELAYS, 0).unwrap();
+            let endpoint = match selected.inner {
+                MullvadEndpoint::Wireguard(endpoint) => endpoint,
+                MullvadEndpoint::OpenVpn(_) => panic!("Expected a WireGuard endpoint"),
+            };
+            let obfuscator = selected
+                .obfuscation
+                .expect("Expected the endpoint to be obfuscated");
+            let ObfuscatorConfig::Udp2Tcp {
+                endpoint: obfuscator_endpoint,
+            } = obfuscator.config;
+            assert_eq!(obfuscator_endpoint.ip(), endpoint.peer.endpoint.ip());
+            assert!(obfuscator
+                .relay
+                .obfuscators
+                .udp2tcp
+                .iter()
+                .any(|udp2tcp| udp2tcp.port == obfuscator_endpoint.port()));
+        }
+
+        relay_selector.config.lock().relay_settings = RelaySettings::Normal(RelayConstraints {
+            location: Constraint::Only(LocationConstraint::Country("no".to_owned())),
+            ..RelayConstraints::default()
+        });
+        assert!(relay_selector.select_obfuscated(&RELAYS, 0).is_none());
+    }
+
+    #[test]
+    fn test_select_obfuscated_multihop() {
+        let relay_selector = new_relay_selector();
+        relay_selector.config.lock().relay_settings =
+            RelaySettings::Normal(WIREGUARD_MULTIHOP_CONSTRAINTS);
+        relay_selector.config.lock().obfuscation_settings = ObfuscationSettings {
+            selected_obfuscation: SelectedObfuscation::Udp2Tcp,
+            ..ObfuscationSettings::default()
+        };
+
+        for _ in 0..100 {
+            let selected = relay_selector.select_obfuscated(&RELAYS, 0).unwrap();
+            let endpoint = match selected.inner {
+                MullvadEndpoint::Wireguard(endpoint) => endpoint,
+                MullvadEndpoint::OpenVpn(_) => panic!("Expected a WireGuard endpoint"),
+            };
+            let exit_peer = endpoint.exit_peer.expect("Expected a multihop endpoint");
+            assert_ne!(exit_peer.endpoint.ip(), endpoint.peer.endpoint.ip());
+
+            // The obfuscator wraps the traffic to the entry relay
+            let obfuscator = selected
+                .obfuscation
+                .expect("Expected the endpoint to be obfuscated");
+            let ObfuscatorConfig::Udp2Tcp {
+                endpoint: obfuscator_endpoint,
+            } = obfuscator.config;
+            assert_eq!(obfuscator_endpoint.ip(), endpoint.peer.endpoint.ip());
+        }
+    }
+
+    #[test]
+    fn test_select_obfuscated_auto() {
+        let relay_selector = new_relay_selector();
+        relay_selector.config.lock().relay_settings =
+            RelaySettings::Normal(WIREGUARD_SINGLEHOP_CONSTRAINTS);
+        relay_selector.config.lock().obfuscation_settings = ObfuscationSettings {
+            selected_obfuscation: SelectedObfuscation::Auto,
+            ..ObfuscationSettings::default()
+        };
+
+        for retry_attempt in 0..8 {
+            let selected = relay_selector
+                .select_obfuscated(&RELAYS, retry_attempt)
+                .unwrap();
+            assert_eq!(
+                selected.obfuscation.is_some(),
+                retry_attempt % 4 >= 2,
+                "Unexpected obfuscation on attempt {}",
+                retry_attempt
+            );
+        }
+    }
+
     #[test]
     fn test_selecting_wg_endpoint_with_auto_obfuscation() {
         let relay_selector = new_relay_selector();
@@ -1925,4 +2638,907 @@ mod test {
             ));
         }
     }
+
+    #[test]
+    fn test_openvpn_transport_protocol_constraint() {
+        let relay_selector = new_relay_selector();
+        let tcp_constraint = OpenVpnConstraints {
+            port: Constraint::Only(TransportPort {
+                protocol: TransportProtocol::Tcp,
+                port: Constraint::Any,
+            }),
+        };
+
+        for tunnel_protocol in [Constraint::Any, Constraint::Only(TunnelType::OpenVpn)] {
+            let constraints = RelayConstraints {
+                location: Constraint::Only(LocationConstraint::Hostname(
+                    "se".to_string(),
+                    "got".to_string(),
+                    "se-got-001".to_string(),
+                )),
+                tunnel_protocol,
+                openvpn_constraints: tcp_constraint,
+                ..RelayConstraints::default()
+            };
+
+            for attempt in 0..10 {
+                for bridge_state in [BridgeState::Off, BridgeState::Auto, BridgeState::On] {
+                    let result = relay_selector
+                        .get_tunnel_endpoint(&constraints, bridge_state, attempt)
+                        .expect("Failed to select an OpenVPN relay");
+                    match result.endpoint {
+                        MullvadEndpoint::OpenVpn(endpoint) => {
+                            assert_eq!(endpoint.protocol, TransportProtocol::Tcp)
+                        }
+                        MullvadEndpoint::Wireguard(_) => panic!("Selected a WireGuard endpoint"),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_preferred_shadowsocks_cipher() {
+        let relay_selector = new_relay_selector();
+        let mut relay = RELAYS.countries[0].cities[0].relays[0].clone();
+        relay.bridges.shadowsocks = ["aes-256-gcm", "chacha20-ietf-poly1305"]
+            .iter()
+            .map(|cipher| ShadowsocksEndpointData {
+                port: 443,
+                cipher: cipher.to_string(),
+                password: "mullvad".to_string(),
+                protocol: TransportProtocol::Tcp,
+            })
+            .collect();
+
+        let selected_cipher = |relay: &Relay, preferred_cipher| match relay_selector
+            .pick_random_bridge(
+                relay,
+                Constraint::Only(TransportProtocol::Tcp),
+                Constraint::Any,
+                preferred_cipher,
+            ) {
+            Some(ProxySettings::Shadowsocks(settings)) => settings.cipher,
+            settings => panic!("Expected Shadowsocks settings, got {:?}", settings),
+        };
+
+        for _ in 0..10 {
+            assert_eq!(
+                selected_cipher(&relay, Some("chacha20-ietf-poly1305")),
+                "chacha20-ietf-poly1305"
+            );
+        }
+
+        // Fall back to any bridge if none use the preferred cipher
+        relay.bridges.shadowsocks.pop();
+        assert_eq!(
+            selected_cipher(&relay, Some("chacha20-ietf-poly1305")),
+            "aes-256-gcm"
+        );
+        assert_eq!(selected_cipher(&relay, Some("not-a-cipher")), "aes-256-gcm");
+    }
+
+    #[test]
+    fn test_select_resolved() {
+        let relay_selector = new_relay_selector();
+
+        // Port 53 is preferred on the third attempt
+        let constraints = RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
+        };
+        let selection = relay_selector
+            .select_resolved(&constraints, 2)
+            .expect("Failed to select a WireGuard relay");
+        let applied_constraints = selection.applied_constraints;
+        assert_eq!(
+            applied_constraints.tunnel_protocol,
+            Constraint::Only(TunnelType::Wireguard)
+        );
+        assert_eq!(
+            applied_constraints.wireguard_constraints.port,
+            Constraint::Only(53)
+        );
+        assert_eq!(
+            applied_constraints.wireguard_constraints.ip_version,
+            Constraint::Only(IpVersion::V4)
+        );
+        assert_eq!(applied_constraints.location, constraints.location);
+
+        // TCP port 443 is preferred on the third attempt, and only OpenVPN is available
+        let constraints = RelayConstraints {
+            location: Constraint::Only(LocationConstraint::Hostname(
+                "se".to_string(),
+                "got".to_string(),
+                "se-got-001".to_string(),
+            )),
+            ..RelayConstraints::default()
+        };
+        let selection = relay_selector
+            .select_resolved(&constraints, 2)
+            .expect("Failed to select an OpenVPN relay");
+        assert_eq!(selection.relay.hostname, "se-got-001");
+        assert_eq!(
+            selection.applied_constraints.tunnel_protocol,
+            Constraint::Only(TunnelType::OpenVpn)
+        );
+        assert_eq!(
+            selection.applied_constraints.openvpn_constraints.port,
+            Constraint::Only(TransportPort {
+                protocol: TransportProtocol::Tcp,
+                port: Constraint::Only(443),
+            })
+        );
+    }
+
+    #[test]
+    fn test_empty_relay_list() {
+        let relay_selector = new_relay_selector();
+        *relay_selector.parsed_relays.lock() = ParsedRelays::empty();
+
+        assert!(matches!(
+            relay_selector.get_relay(0),
+            Err(Error::EmptyRelayList)
+        ));
+        assert!(matches!(
+            relay_selector.select_resolved(&RelayConstraints::default(), 0),
+            Err(Error::EmptyRelayList)
+        ));
+        assert!(relay_selector.get_bridge_forced().is_none());
+    }
+
+    #[test]
+    fn test_prefer_local_multihop_entry() {
+        let relay_selector = new_relay_selector();
+        let mut relay_list = RELAYS.clone();
+        let relays = &mut relay_list.countries[0].cities[0].relays;
+        // Move se10-wireguard out of the /24 subnet of se9-wireguard
+        relays[1].ipv4_addr_in = "185.213.155.69".parse().unwrap();
+        *relay_selector.parsed_relays.lock() =
+            ParsedRelays::from_relay_list(relay_list, SystemTime::now());
+
+        let mut exit_relay = relay_selector.parsed_relays.lock().relays()[0].clone();
+        exit_relay.hostname = "se-exit-wireguard".to_string();
+        exit_relay.ipv4_addr_in = "185.213.154.100".parse().unwrap();
+
+        let mut entry_matcher =
+            RelayMatcher::from(RelayConstraints::default()).into_wireguard_matcher();
+        entry_matcher.tunnel.prefer_local_peer = true;
+        entry_matcher.set_peer(exit_relay.clone());
+        for _ in 0..10 {
+            let (entry_relay, _) = relay_selector
+                .get_entry_endpoint(&entry_matcher)
+                .expect("Failed to select an entry relay");
+            assert_eq!(entry_relay.hostname, "se9-wireguard");
+        }
+
+        // Fall back to any entry relay if none are in the same subnet or city
+        exit_relay.ipv4_addr_in = "193.138.218.1".parse().unwrap();
+        exit_relay.location = Some(Location {
+            country: "Norway".to_string(),
+            country_code: "no".to_string(),
+            city: "Oslo".to_string(),
+            city_code: "osl".to_string(),
+            latitude: 59.91273,
+            longitude: 10.74609,
+        });
+        entry_matcher.set_peer(exit_relay);
+        assert!(relay_selector.get_entry_endpoint(&entry_matcher).is_ok());
+    }
+
+    #[test]
+    fn test_select_obfuscation() {
+        let relay_selector = new_relay_selector();
+        let mut relay = relay_selector.parsed_relays.lock().relays()[0].clone();
+        relay.bridges.shadowsocks = vec![ShadowsocksEndpointData {
+            port: 443,
+            cipher: "aes-256-gcm".to_string(),
+            password: "mullvad".to_string(),
+            protocol: TransportProtocol::Tcp,
+        }];
+        assert!(!relay.obfuscators.udp2tcp.is_empty());
+
+        assert!(matches!(
+            relay_selector.select_obfuscation(
+                &relay,
+                &[ObfuscationKind::Udp2Tcp, ObfuscationKind::Shadowsocks]
+            ),
+            Some(ObfuscationMethod::Udp2Tcp(port)) if UDP2TCP_PORTS.contains(&port)
+        ));
+        assert!(matches!(
+            relay_selector.select_obfuscation(
+                &relay,
+                &[ObfuscationKind::Shadowsocks, ObfuscationKind::Udp2Tcp]
+            ),
+            Some(ObfuscationMethod::Shadowsocks(ProxySettings::Shadowsocks(
+                _
+            )))
+        ));
+
+        // Fall through to the next kind of obfuscation if the relay does not support one
+        relay.obfuscators.udp2tcp.clear();
+        assert!(matches!(
+            relay_selector.select_obfuscation(
+                &relay,
+                &[ObfuscationKind::Udp2Tcp, ObfuscationKind::Shadowsocks]
+            ),
+            Some(ObfuscationMethod::Shadowsocks(_))
+        ));
+
+        relay.bridges.shadowsocks.clear();
+        assert!(relay_selector
+            .select_obfuscation(
+                &relay,
+                &[ObfuscationKind::Udp2Tcp, ObfuscationKind::Shadowsocks]
+            )
+            .is_none());
+        assert!(relay_selector.select_obfuscation(&relay, &[]).is_none());
+    }
+
+    #[test]
+    fn test_favorite_relays() {
+        let mut relay_selector = new_relay_selector();
+        let mut relay_list = RELAYS.clone();
+        // se10-wireguard has a higher weight than se9-wireguard
+        relay_list.countries[0].cities[0].relays[1].weight = 10;
+        *relay_selector.parsed_relays.lock() =
+            ParsedRelays::from_relay_list(relay_list, SystemTime::now());
+        relay_selector.set_favorites(RelayFavorites {
+            hostnames: ["se9-wireguard".to_string(), "se-got-001".to_string()]
+                .into_iter()
+                .collect(),
+            weight_factor: 1000,
+        });
+
+        let constraints = RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
+        };
+        let mut favorite_selections = 0;
+        for _ in 0..100 {
+            let result = relay_selector
+                .get_tunnel_endpoint(&constraints, BridgeState::Off, 0)
+                .expect("Failed to select a WireGuard relay");
+            // se-got-001 is a favorite, but does not match the constraints
+            assert_ne!(result.exit_relay.hostname, "se-got-001");
+            if result.exit_relay.hostname == "se9-wireguard" {
+                favorite_selections += 1;
+            }
+        }
+        assert!(favorite_selections > 90);
+    }
+
+    #[test]
+    fn test_weight_floor() {
+        let relay_selector = new_relay_selector();
+        let mut relays: Vec<Relay> = RELAYS.countries[0].cities[0].relays[..2].to_vec();
+        relays[0].weight = 1;
+        relays[1].weight = 1000;
+        let mut zero_weight_relay = relays[0].clone();
+        zero_weight_relay.hostname = "se11-wireguard".to_string();
+        zero_weight_relay.weight = 0;
+        relays.push(zero_weight_relay);
+
+        let mut matcher = RelayMatcher::from(RelayConstraints::default()).into_wireguard_matcher();
+        let low_weight_selections = |matcher: &RelayMatcher<WireguardMatcher>| {
+            let mut selections = 0;
+            for _ in 0..1000 {
+                let relay = relay_selector
+                    .pick_random_tunnel_relay(&relays, matcher)
+                    .unwrap();
+                assert_ne!(relay.hostname, "se11-wireguard");
+                if relay.hostname == "se9-wireguard" {
+                    selections += 1;
+                }
+            }
+            selections
+        };
+
+        assert!(low_weight_selections(&matcher) < 20);
+        // se9-wireguard now has a weight of 500 and should be picked about a third of the time
+        matcher.weight_floor = 500;
+        assert!(low_weight_selections(&matcher) > 250);
+    }
+
+    #[test]
+    fn test_bridge_transport_protocol() {
+        let relay_selector = new_relay_selector();
+        let mut relay = RELAYS.countries[0].cities[0].relays[0].clone();
+        relay.bridges.shadowsocks = vec![ShadowsocksEndpointData {
+            port: 443,
+            cipher: "aes-256-gcm".to_string(),
+            password: "mullvad".to_string(),
+            protocol: TransportProtocol::Udp,
+        }];
+
+        let tcp = Constraint::Only(TransportProtocol::Tcp);
+        assert!(relay_selector
+            .pick_random_bridge(&relay, tcp, Constraint::Any, None)
+            .is_none());
+        assert!(relay_selector
+            .pick_random_bridge(
+                &relay,
+                Constraint::Only(TransportProtocol::Udp),
+                Constraint::Any,
+                None
+            )
+            .is_some());
+
+        relay.bridges.shadowsocks[0].protocol = TransportProtocol::Tcp;
+        assert!(relay_selector
+            .pick_random_bridge(&relay, tcp, Constraint::Any, None)
+            .is_some());
+        assert!(relay_selector
+            .select_obfuscation(&relay, &[ObfuscationKind::Shadowsocks])
+            .is_some());
+    }
+
+    #[test]
+    fn test_bridge_port() {
+        let relay_selector = new_relay_selector();
+        let mut relay = RELAYS.countries[0].cities[0].relays[0].clone();
+        relay.bridges.shadowsocks = [443, 1234]
+            .iter()
+            .map(|port| ShadowsocksEndpointData {
+                port: *port,
+                cipher: "aes-256-gcm".to_string(),
+                password: "mullvad".to_string(),
+                protocol: TransportProtocol::Tcp,
+            })
+            .collect();
+        let tcp = Constraint::Only(TransportProtocol::Tcp);
+        let selected_port = |port| match relay_selector.pick_random_bridge(&relay, tcp, port, None)
+        {
+            Some(ProxySettings::Shadowsocks(settings)) => Some(settings.peer.port()),
+            Some(settings) => panic!("Expected Shadowsocks settings, got {:?}", settings),
+            None => None,
+        };
+
+        for _ in 0..10 {
+            assert_eq!(selected_port(Constraint::Only(1234)), Some(1234));
+        }
+        assert_eq!(selected_port(Constraint::Only(80)), None);
+
+        let mut picked_ports = HashSet::new();
+        for _ in 0..100 {
+            picked_ports.insert(selected_port(Constraint::Any).unwrap());
+        }
+        assert_eq!(picked_ports, HashSet::from([443, 1234]));
+
+        // Relays without a bridge on the pinned port are not matched
+        let constraints = InternalBridgeConstraints {
+            location: Constraint::Any,
+            providers: Constraint::Any,
+            ownership: Constraint::Any,
+            transport_protocol: tcp,
+            port: Constraint::Only(80),
+            preferred_cipher: None,
+        };
+        assert!(RelaySelector::matching_bridge_relay(&relay, &constraints).is_none());
+        let constraints = InternalBridgeConstraints {
+            port: Constraint::Only(443),
+            ..constraints
+        };
+        let filtered_relay = RelaySelector::matching_bridge_relay(&relay, &constraints).unwrap();
+        assert_eq!(filtered_relay.bridges.shadowsocks.len(), 1);
+        assert_eq!(filtered_relay.bridges.shadowsocks[0].port, 443);
+    }
+
+    #[test]
+    fn test_require_daita() {
+        let relay_selector = new_relay_selector();
+        let mut relay_list = RELAYS.clone();
+        relay_list.countries[0].cities[0].relays[1]
+            .tunnels
+            .wireguard[0]
+            .daita = true;
+        *relay_selector.parsed_relays.lock() =
+            ParsedRelays::from_relay_list(relay_list, SystemTime::now());
+
+        let mut matcher = RelayMatcher::from(RelayConstraints::default()).into_wireguard_matcher();
+        matcher.tunnel.require_daita = true;
+        for _ in 0..10 {
+            let result = relay_selector
+                .get_tunnel_endpoint_internal(&matcher)
+                .expect("Failed to select a DAITA relay");
+            assert_eq!(result.exit_relay.hostname, "se10-wireguard");
+        }
+
+        // Only the multihop entry relay has to support DAITA
+        for _ in 0..10 {
+            let result = relay_selector
+                .get_wireguard_multi_hop_endpoint(matcher.clone(), Constraint::Any, Constraint::Any)
+                .expect("Failed to select a multihop relay");
+            assert_eq!(result.entry_relay.unwrap().hostname, "se10-wireguard");
+            assert_eq!(result.exit_relay.hostname, "se9-wireguard");
+        }
+
+        *relay_selector.parsed_relays.lock() =
+            ParsedRelays::from_relay_list(RELAYS.clone(), SystemTime::now());
+        assert!(relay_selector
+            .get_tunnel_endpoint_internal(&matcher)
+            .is_err());
+        assert!(relay_selector
+            .get_wireguard_multi_hop_endpoint(matcher, Constraint::Any, Constraint::Any)
+            .is_err());
+    }
+
+    #[test]
+    fn test_multihop_exit_port_constraint() {
+        let relay_selector = new_relay_selector();
+        let mut constraints = RelayConstraints {
+            wireguard_constraints: WireguardConstraints {
+                use_multihop: true,
+                exit_port: Constraint::Only(4000),
+                ..WireguardConstraints::default()
+            },
+            ..WIREGUARD_MULTIHOP_CONSTRAINTS.clone()
+        };
+        let exit_port = |result: &NormalSelectedRelay| {
+            let endpoint = result.endpoint.unwrap_wireguard();
+            endpoint.exit_peer.as_ref().unwrap().endpoint.port()
+        };
+
+        for tunnel_protocol in [Constraint::Only(TunnelType::Wireguard), Constraint::Any] {
+            constraints.tunnel_protocol = tunnel_protocol;
+            for retry_attempt in 0..10 {
+                let result = relay_selector
+                    .get_tunnel_endpoint(&constraints, BridgeState::Off, retry_attempt)
+                    .expect("Failed to select a multihop relay");
+                if let MullvadEndpoint::Wireguard(_) = result.endpoint {
+                    assert_eq!(exit_port(&result), 4000);
+                }
+            }
+        }
+
+        // The exit port defaults to the default WireGuard port
+        constraints.wireguard_constraints.exit_port = Constraint::Any;
+        constraints.tunnel_protocol = Constraint::Only(TunnelType::Wireguard);
+        let result = relay_selector
+            .get_tunnel_endpoint(&constraints, BridgeState::Off, 0)
+            .expect("Failed to select a multihop relay");
+        assert_eq!(exit_port(&result), DEFAULT_WIREGUARD_PORT);
+    }
+
+    #[test]
+    fn test_openvpn_fallback_for_unsatisfiable_wireguard_constraints() {
+        let relay_selector = new_relay_selector();
+        let mut constraints = RelayConstraints {
+            location: Constraint::Only(LocationConstraint::Country("se".to_owned())),
+            tunnel_protocol: Constraint::Any,
+            wireguard_constraints: WireguardConstraints {
+                // No relay has this port in its port ranges
+                port: Constraint::Only(1),
+                ..WireguardConstraints::default()
+            },
+            ..RelayConstraints::default()
+        };
+
+        for use_multihop in [false, true] {
+            constraints.wireguard_constraints.use_multihop = use_multihop;
+            for retry_attempt in 0..10 {
+                let result = relay_selector
+                    .get_any_tunnel_endpoint(&constraints, BridgeState::Auto, retry_attempt)
+                    .expect("Failed to fall back to OpenVPN");
+                assert!(matches!(result.endpoint, MullvadEndpoint::OpenVpn(_)));
+                assert!(result.entry_relay.is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_provider_cooldown() {
+        let mut relay_selector = new_relay_selector();
+        relay_selector.set_provider_cooldown(ProviderCooldown::new(
+            2,
+            Duration::from_millis(200),
+            10,
+        ));
+        let mut relays: Vec<Relay> = RELAYS.countries[0].cities[0].relays[..2].to_vec();
+        relays[0].weight = 100;
+        relays[0].provider = "31173".to_string();
+        relays[1].weight = 100;
+        relays[1].provider = "M247".to_string();
+        let mut other_relay = relays[0].clone();
+        other_relay.hostname = "se11-wireguard".to_string();
+
+        let matcher = RelayMatcher::from(RelayConstraints::default()).into_wireguard_matcher();
+        let selections = |relay_selector: &RelaySelector| {
+            (0..1000)
+                .filter(|_| {
+                    relay_selector
+                        .pick_random_tunnel_relay(&relays, &matcher)
+                        .unwrap()
+                        .provider
+                        == "31173"
+                })
+                .count()
+        };
+
+        // A single failure is not enough
+        relay_selector.record_relay_failure(&relays[0]);
+        relay_selector.record_relay_failure(&relays[0]);
+        assert!(!relay_selector
+            .provider_cooldown
+            .lock()
+            .is_cooling_down("31173"));
+        assert!(selections(&relay_selector) > 350);
+
+        relay_selector.record_relay_failure(&other_relay);
+        assert!(relay_selector
+            .provider_cooldown
+            .lock()
+            .is_cooling_down("31173"));
+        assert!(!relay_selector
+            .provider_cooldown
+            .lock()
+            .is_cooling_down("M247"));
+        // The effective weights are 10 and 100
+        assert!(selections(&relay_selector) < 200);
+
+        std::thread::sleep(Duration::from_millis(250));
+        assert!(!relay_selector
+            .provider_cooldown
+            .lock()
+            .is_cooling_down("31173"));
+        assert!(selections(&relay_selector) > 350);
+    }
+
+    #[test]
+    fn test_avoided_provider() {
+        let mut relay_selector = new_relay_selector();
+        let mut relay_list = RELAYS.clone();
+        relay_list.countries[0].cities[0].relays[1].provider = "M247".to_string();
+        *relay_selector.parsed_relays.lock() =
+            ParsedRelays::from_relay_list(relay_list, SystemTime::now());
+        relay_selector.set_avoided_provider(Some("31173".to_string()));
+
+        let mut constraints = RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
+        };
+        for _ in 0..10 {
+            let result = relay_selector
+                .get_tunnel_endpoint(&constraints, BridgeState::Off, 0)
+                .expect("Failed to select a WireGuard relay");
+            assert_eq!(result.exit_relay.hostname, "se10-wireguard");
+        }
+
+        // The avoided provider is used if it is the only one matching the constraints
+        constraints.location = Constraint::Only(LocationConstraint::Hostname(
+            "se".to_string(),
+            "got".to_string(),
+            "se9-wireguard".to_string(),
+        ));
+        let result = relay_selector
+            .get_tunnel_endpoint(&constraints, BridgeState::Off, 0)
+            .expect("Failed to select a relay from the avoided provider");
+        assert_eq!(result.exit_relay.provider, "31173");
+    }
+
+    #[test]
+    fn test_prefer_dual_stack() {
+        let relay_selector = new_relay_selector();
+        let mut relay_list = RELAYS.clone();
+        // se10-wireguard has the same weight as se9-wireguard, but no IPv6 address
+        relay_list.countries[0].cities[0].relays[1].ipv6_addr_in = None;
+        *relay_selector.parsed_relays.lock() =
+            ParsedRelays::from_relay_list(relay_list, SystemTime::now());
+
+        let mut matcher = RelayMatcher::from(RelayConstraints {
+            location: Constraint::Only(LocationConstraint::Country("se".to_owned())),
+            ..RelayConstraints::default()
+        })
+        .into_wireguard_matcher();
+        matcher.prefer_dual_stack = true;
+
+        let mut dual_stack_selections = 0;
+        for _ in 0..300 {
+            let result = relay_selector
+                .get_tunnel_endpoint_internal(&matcher)
+                .expect("Failed to select a WireGuard relay");
+            if result.exit_relay.hostname == "se9-wireguard" {
+                dual_stack_selections += 1;
+            }
+        }
+        // The dual-stack relay is expected to be picked 80% of the time
+        assert!(dual_stack_selections > 180);
+        assert!(dual_stack_selections < 300);
+    }
+
+    #[test]
+    fn test_wireguard_public_keys() {
+        let relay_selector = new_relay_selector();
+        let public_key = |relay: &Relay| relay.tunnels.wireguard[0].public_key.clone();
+
+        let matcher = RelayMatcher::from(RelayConstraints::default()).into_wireguard_matcher();
+        let result = relay_selector
+            .get_tunnel_endpoint_internal(&matcher)
+            .expect("Failed to select a WireGuard relay");
+        let endpoint = result.endpoint.unwrap_wireguard();
+        assert_eq!(endpoint.peer_public_key(), &public_key(&result.exit_relay));
+        assert_eq!(endpoint.exit_public_key(), &public_key(&result.exit_relay));
+
+        let result = relay_selector
+            .get_wireguard_multi_hop_endpoint(matcher, Constraint::Any, Constraint::Any)
+            .expect("Failed to select a multihop relay");
+        let endpoint = result.endpoint.unwrap_wireguard();
+        let entry_relay = result.entry_relay.as_ref().unwrap();
+        assert_eq!(endpoint.peer_public_key(), &public_key(entry_relay));
+        assert_eq!(endpoint.exit_public_key(), &public_key(&result.exit_relay));
+        assert_ne!(endpoint.peer_public_key(), endpoint.exit_public_key());
+    }
+
+    #[test]
+    fn test_multihop_entry_policy_lowest_latency() {
+        let mut relay_selector = new_relay_selector();
+        let mut relay_list = RELAYS.clone();
+        let relays = &mut relay_list.countries[0].cities[0].relays;
+        relays[0].rtt = Some(Duration::from_millis(40));
+        relays[1].rtt = Some(Duration::from_millis(15));
+        *relay_selector.parsed_relays.lock() =
+            ParsedRelays::from_relay_list(relay_list, SystemTime::now());
+        relay_selector.set_multihop_entry_policy(MultihopEntryPolicy::LowestLatency);
+
+        let mut matcher = RelayMatcher::from(RelayConstraints::default()).into_wireguard_matcher();
+        for _ in 0..10 {
+            let result = relay_selector
+                .get_wireguard_multi_hop_endpoint(matcher.clone(), Constraint::Any, Constraint::Any)
+                .expect("Failed to select a multihop relay");
+            assert_eq!(result.entry_relay.unwrap().hostname, "se10-wireguard");
+            assert_eq!(result.exit_relay.hostname, "se9-wireguard");
+        }
+
+        // The entry relay is picked last if its location is not a subset of the exit location
+        matcher.location = Constraint::Only(LocationConstraint::Country("se".to_owned()));
+        for _ in 0..10 {
+            let result = relay_selector
+                .get_wireguard_multi_hop_endpoint(
+                    matcher.clone(),
+                    Constraint::Only(LocationConstraint::Hostname(
+                        "se".to_string(),
+                        "got".to_string(),
+                        "se10-wireguard".to_string(),
+                    )),
+                    Constraint::Any,
+                )
+                .expect("Failed to select a multihop relay");
+            assert_eq!(result.entry_relay.unwrap().hostname, "se9-wireguard");
+            assert_eq!(result.exit_relay.hostname, "se10-wireguard");
+        }
+    }
+
+    #[test]
+    fn test_multihop_entry_and_exit_ports() {
+        let relay_selector = new_relay_selector();
+        let mut matcher = RelayMatcher::from(RelayConstraints::default()).into_wireguard_matcher();
+
+        let ports = |result: &NormalSelectedRelay| {
+            let endpoint = result.endpoint.unwrap_wireguard();
+            (
+                endpoint.peer.endpoint.port(),
+                endpoint.exit_peer.as_ref().unwrap().endpoint.port(),
+            )
+        };
+
+        // The exit port defaults to the default WireGuard port
+        matcher.tunnel.port = Constraint::Only(53);
+        let result = relay_selector
+            .get_wireguard_multi_hop_endpoint(matcher.clone(), Constraint::Any, Constraint::Any)
+            .expect("Failed to select a multihop relay");
+        assert_eq!(ports(&result), (53, DEFAULT_WIREGUARD_PORT));
+
+        let result = relay_selector
+            .get_wireguard_multi_hop_endpoint(
+                matcher.clone(),
+                Constraint::Any,
+                Constraint::Only(4000),
+            )
+            .expect("Failed to select a multihop relay");
+        assert_eq!(ports(&result), (53, 4000));
+
+        matcher.tunnel.port = Constraint::Any;
+        for _ in 0..10 {
+            let result = relay_selector
+                .get_wireguard_multi_hop_endpoint(
+                    matcher.clone(),
+                    Constraint::Any,
+                    Constraint::Only(53),
+                )
+                .expect("Failed to select a multihop relay");
+            assert_eq!(ports(&result).1, 53);
+        }
+
+        // Neither relay has the requested entry port in its port ranges
+        matcher.tunnel.port = Constraint::Only(52);
+        assert!(relay_selector
+            .get_wireguard_multi_hop_endpoint(matcher.clone(), Constraint::Any, Constraint::Any)
+            .is_err());
+
+        // Only the exit relay has the requested port
+        let mut relay_list = RELAYS.clone();
+        relay_list.countries[0].cities[0].relays[1]
+            .tunnels
+            .wireguard[0]
+            .port_ranges = vec![(53, 53)];
+        *relay_selector.parsed_relays.lock() =
+            ParsedRelays::from_relay_list(relay_list, SystemTime::now());
+        matcher.tunnel.port = Constraint::Only(4000);
+        matcher.location = Constraint::Only(LocationConstraint::Hostname(
+            "se".to_string(),
+            "got".to_string(),
+            "se10-wireguard".to_string(),
+        ));
+        assert!(relay_selector
+            .get_wireguard_multi_hop_endpoint(matcher.clone(), Constraint::Any, Constraint::Any)
+            .is_err());
+        assert!(relay_selector
+            .get_wireguard_multi_hop_endpoint(
+                matcher.clone(),
+                Constraint::Any,
+                Constraint::Only(4000),
+            )
+            .is_err());
+        matcher.tunnel.port = Constraint::Only(53);
+        let result = relay_selector
+            .get_wireguard_multi_hop_endpoint(matcher, Constraint::Any, Constraint::Only(4000))
+            .expect("Failed to select a multihop relay");
+        assert_eq!(ports(&result), (53, 4000));
+        assert_eq!(result.entry_relay.unwrap().hostname, "se10-wireguard");
+    }
+
+    #[test]
+    fn test_multihop_require_different_country() {
+        let relay_selector = new_relay_selector();
+        let mut constraints = RelayConstraints {
+            wireguard_constraints: WireguardConstraints {
+                use_multihop: true,
+                require_different_country: true,
+                ..WireguardConstraints::default()
+            },
+            ..WIREGUARD_MULTIHOP_CONSTRAINTS.clone()
+        };
+
+        // All relays are in Sweden
+        assert!(relay_selector
+            .get_tunnel_endpoint(&constraints, BridgeState::Off, 0)
+            .is_err());
+
+        // Add a WireGuard relay in Norway, and remove the OpenVPN relays so that WireGuard is
+        // used even when the tunnel protocol isn't constrained
+        let mut relay_list = RELAYS.clone();
+        let relays = &mut relay_list.countries[0].cities[0].relays;
+        relays.retain(|relay| relay.tunnels.openvpn.is_empty());
+        let mut relay = relays[1].clone();
+        relay.hostname = "no-osl-wg-001".to_string();
+        relay.ipv4_addr_in = "185.213.154.200".parse().unwrap();
+        relay_list.countries.push(RelayListCountry {
+            name: "Norway".to_string(),
+            code: "no".to_string(),
+            cities: vec![RelayListCity {
+                name: "Oslo".to_string(),
+                code: "osl".to_string(),
+                latitude: 59.9,
+                longitude: 10.75,
+                relays: vec![relay],
+            }],
+        });
+        *relay_selector.parsed_relays.lock() =
+            ParsedRelays::from_relay_list(relay_list, SystemTime::now());
+
+        let country = |relay: &Relay| relay.location.as_ref().unwrap().country_code.clone();
+        for tunnel_protocol in [Constraint::Only(TunnelType::Wireguard), Constraint::Any] {
+            constraints.tunnel_protocol = tunnel_protocol;
+            for retry_attempt in 0..20 {
+                let result = relay_selector
+                    .get_tunnel_endpoint(&constraints, BridgeState::Off, retry_attempt)
+                    .expect("Failed to select a multihop relay");
+                assert_ne!(
+                    country(&result.entry_relay.unwrap()),
+                    country(&result.exit_relay)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_not_modified_relay_list_is_fresh() {
+        let hour = Duration::from_secs(60 * 60);
+        let mut relay_list = RELAYS.clone();
+        relay_list.fetched_at = Some(SystemTime::now() - 2 * hour);
+        let mut parsed_relays =
+            ParsedRelays::from_relay_list(relay_list, SystemTime::now() - 2 * hour);
+        let matcher = RelayMatcher::from(WIREGUARD_SINGLEHOP_CONSTRAINTS.clone());
+        assert!(matcher
+            .select_if_fresh(parsed_relays.locations(), hour)
+            .is_err());
+
+        // The API responds that the relay list has not been modified
+        let now = SystemTime::now();
+        parsed_relays.mark_fetched(now);
+        assert_eq!(parsed_relays.last_updated(), now);
+        assert!(matcher
+            .select_if_fresh(parsed_relays.locations(), hour)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_select_with_fallbacks() {
+        let relay_selector = new_relay_selector();
+        // Two countries with one city each, with a WireGuard and an OpenVPN relay per city
+        let list = RelayList::mock(2, 1, 2);
+        let location = list.relays().next().unwrap().location.clone().unwrap();
+        let city = LocationConstraint::City(location.country_code.clone(), location.city_code);
+        let wireguard_in = |location| RelayConstraints {
+            location,
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
+        };
+        let constraints = [
+            wireguard_in(Constraint::Only(LocationConstraint::City(
+                location.country_code,
+                "nowhere".to_string(),
+            ))),
+            wireguard_in(Constraint::Only(LocationConstraint::Country(
+                "nowhere".to_string(),
+            ))),
+            wireguard_in(Constraint::Only(city.clone())),
+            wireguard_in(Constraint::Any),
+        ];
+
+        for retry_attempt in 0..10 {
+            let (relay, index) = relay_selector
+                .select_with_fallbacks(&list, &constraints, retry_attempt)
+                .unwrap();
+            assert_eq!(index, 2);
+            assert!(matches!(relay.endpoint, MullvadEndpoint::Wireguard(_)));
+            assert!(city.matches(&relay.exit_relay));
+            assert!(relay.entry_relay.is_none());
+        }
+
+        let (_, index) = relay_selector
+            .select_with_fallbacks(&list, &constraints[1..], 0)
+            .unwrap();
+        assert_eq!(index, 1);
+        assert!(relay_selector
+            .select_with_fallbacks(&list, &constraints[..2], 0)
+            .is_none());
+        assert!(relay_selector
+            .select_with_fallbacks(&list, &[], 0)
+            .is_none());
+    }
+
+    #[test]
+    fn test_select_with_fallbacks_multihop() {
+        let relay_selector = new_relay_selector();
+        // Two countries with one WireGuard relay each. The default exit port is outside of the
+        // port ranges of the relays.
+        let list = RelayList::mock(2, 1, 1);
+        let multihop_via = |entry_location| RelayConstraints {
+            wireguard_constraints: WireguardConstraints {
+                use_multihop: true,
+                entry_location,
+                exit_port: Constraint::Only(4000),
+                ..WireguardConstraints::default()
+            },
+            ..WIREGUARD_MULTIHOP_CONSTRAINTS.clone()
+        };
+        let constraints = [
+            multihop_via(Constraint::Only(LocationConstraint::Country(
+                "nowhere".to_string(),
+            ))),
+            multihop_via(Constraint::Any),
+        ];
+
+        for retry_attempt in 0..10 {
+            let (relay, index) = relay_selector
+                .select_with_fallbacks(&list, &constraints, retry_attempt)
+                .unwrap();
+            assert_eq!(index, 1);
+            let entry_relay = relay.entry_relay.expect("Expected a multihop relay");
+            assert_ne!(entry_relay.hostname, relay.exit_relay.hostname);
+            assert!(relay.endpoint.unwrap_wireguard().exit_peer.is_some());
+        }
+    }
 }
@@ -1,35 +1,164 @@
+use chrono::Utc;
 use mullvad_types::{
-    endpoint::{MullvadEndpoint, MullvadWireguardEndpoint},
+    endpoint::MullvadEndpoint,
+    location::{CityCode, Coordinates, CountryCode, Location},
     relay_constraints::{
         Constraint, LocationConstraint, Match, OpenVpnConstraints, Ownership, Providers,
         RelayConstraints, WireguardConstraints,
     },
-    relay_list::{Relay, RelayTunnels, WireguardEndpointData},
+    relay_list::{
+        Relay, RelayList, RelayListCity, RelayListCountry, RelayTunnels, WireguardEndpointData,
+    },
+};
+use parking_lot::Mutex;
+use rand::{
+    distributions::uniform::SampleUniform, rngs::StdRng, seq::SliceRandom, Rng, SeedableRng,
 };
-use rand::{seq::SliceRandom, Rng};
-use std::net::{IpAddr, SocketAddr};
-use talpid_types::net::{all_of_the_internet, wireguard, IpVersion, TunnelType};
+#[cfg(feature = "regex")]
+use regex::{Regex, RegexBuilder};
+use std::{
+    cmp,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::{self, Write},
+    net::IpAddr,
+    ops::AddAssign,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use talpid_types::net::{IpVersion, TransportProtocol, TunnelType};
 
 #[derive(Clone)]
 pub struct RelayMatcher<T: TunnelMatcher> {
     pub location: Constraint<LocationConstraint>,
     pub providers: Constraint<Providers>,
     pub ownership: Constraint<Ownership>,
+    /// Relays with a [`Congestion`] score above this value are never selected. Relays without a
+    /// known score are not filtered out. The filtering is done before the weighted random
+    /// selection, so the weights only decide between relays that are below the threshold.
+    ///
+    /// [`Congestion`]: mullvad_types::relay_list::Congestion
+    pub max_congestion: Option<u8>,
+    /// Tags that a relay must have all of to be matched.
+    pub required_tags: Vec<String>,
+    /// Minimum advertised bandwidth in Mbps. Relays with an unknown bandwidth are not matched
+    /// when this is set.
+    pub min_bandwidth: Option<u32>,
+    /// Relays in these countries are never matched, even if they are covered by the location
+    /// constraint. Relays without a known location are not matched when this is non-empty.
+    pub excluded_countries: HashSet<CountryCode>,
+    /// If non-empty, only relays in one of these cities, given as country and city codes, are
+    /// matched. This allows picking among cities in different countries, and applies in
+    /// addition to the location constraint. Relays without a known location are not matched
+    /// when this is non-empty.
+    pub allowed_cities: HashSet<(CountryCode, CityCode)>,
+    /// Only match relays hosted in this autonomous system.
+    pub asn: Constraint<u32>,
+    /// Relays hosted in these autonomous systems are never matched. Relays with an unknown
+    /// autonomous system are not matched when this is non-empty, or when `asn` is constrained.
+    pub excluded_asns: HashSet<u32>,
+    /// Multiply the weight of relays that have both an IPv4 and an IPv6 address by
+    /// [`DUAL_STACK_WEIGHT_FACTOR`] during weighted selection. IPv4-only relays can still be
+    /// selected.
+    pub prefer_dual_stack: bool,
+    /// Multiply the weight of relays that were added within this duration by
+    /// [`RECENTLY_ADDED_WEIGHT_FACTOR`] during weighted selection, since newer relays tend to be
+    /// less loaded. Relays without a known introduction date keep their weight.
+    pub prefer_added_within: Option<Duration>,
+    /// Nonzero relay weights below this value are raised to it during weighted selection, so
+    /// that relays with a very low weight are still picked now and then. Relays with a weight of
+    /// 0 are not affected.
+    pub weight_floor: u64,
+    /// Only match relays in the stable release channel. See [`Relay::is_stable`].
+    pub require_stable: bool,
+    /// Relays with a hostname matching this pattern are never matched. Use
+    /// [`hostname_exclude_regex`] to compile a pattern with a bounded size.
+    #[cfg(feature = "regex")]
+    pub hostname_exclude: Option<Regex>,
+    /// Only match relays that can be reached using this transport protocol. When TCP is
+    /// required, only OpenVPN TCP endpoints are kept, and WireGuard endpoints are only kept if
+    /// the relay has a udp2tcp obfuscator. When UDP is required, OpenVPN TCP endpoints are
+    /// removed.
+    pub reachable_transport: Constraint<TransportProtocol>,
     pub tunnel: T,
 }
 
+/// Factor that the weight of dual-stack relays is multiplied by when
+/// [`RelayMatcher::prefer_dual_stack`] is set.
+pub const DUAL_STACK_WEIGHT_FACTOR: u64 = 4;
+
+/// Factor that the weight of recently added relays is multiplied by when
+/// [`RelayMatcher::prefer_added_within`] is set.
+pub const RECENTLY_ADDED_WEIGHT_FACTOR: u64 = 2;
+
+/// Maximum size in bytes of a compiled [`RelayMatcher::hostname_exclude`] pattern. Matching runs
+/// in linear time, but nested repetitions such as `(a{1000}){1000}` can still compile into a
+/// program that is too large to hold in memory.
+#[cfg(feature = "regex")]
+pub const HOSTNAME_EXCLUDE_SIZE_LIMIT: usize = 64 * 1024;
+
+/// Compiles `pattern` for use as [`RelayMatcher::hostname_exclude`]. Fails if the pattern is
+/// invalid or if it would exceed [`HOSTNAME_EXCLUDE_SIZE_LIMIT`].
+#[cfg(feature = "regex")]
+pub fn hostname_exclude_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern)
+        .size_limit(HOSTNAME_EXCLUDE_SIZE_LIMIT)
+        .dfa_size_limit(HOSTNAME_EXCLUDE_SIZE_LIMIT)
+        .build()
+}
+
+/// The checks that [`RelayMatcher::filter_matching_relay`] applies to each relay before filtering
+/// its endpoints, in order, identified by the reason a relay is rejected for failing them.
+const RELAY_CHECKS: &[MatchRejection] = &[
+    MatchRejection::Location,
+    MatchRejection::Providers,
+    MatchRejection::Ownership,
+    MatchRejection::Congestion,
+    MatchRejection::Tags,
+    MatchRejection::Bandwidth,
+    MatchRejection::ExcludedCountry,
+    MatchRejection::AllowedCities,
+    MatchRejection::Asn,
+    MatchRejection::UnstableChannel,
+    #[cfg(feature = "regex")]
+    MatchRejection::ExcludedHostname,
+];
+
+/// Returns every check that a relay must pass to be selected, in order. This is whether the relay
+/// is active, followed by [`RELAY_CHECKS`] and the checks of its endpoints.
+fn all_checks() -> impl Iterator<Item = MatchRejection> {
+    std::iter::once(MatchRejection::Inactive)
+        .chain(RELAY_CHECKS.iter().copied())
+        .chain([MatchRejection::Tunnel, MatchRejection::ReachableTransport])
+}
+
+/// Picks a random item from `items`, weighted by `weight`. If all items have a weight of 0, one is
+/// picked without bias. Returns `None` if `items` is empty.
+fn pick_weighted<'a, T, X>(
+    items: &'a [T],
+    rng: &mut impl Rng,
+    weight: impl Fn(&T) -> X,
+) -> Option<&'a T>
+where
+    X: SampleUniform + for<'b> AddAssign<&'b X> + PartialOrd + Clone + Default,
+{
+    items
+        .choose_weighted(rng, weight)
+        .ok()
+        .or_else(|| items.choose(rng))
+}
+
 impl From<RelayConstraints> for RelayMatcher<AnyTunnelMatcher> {
     fn from(constraints: RelayConstraints) -> Self {
-        Self {
-            location: constraints.location,
-            providers: constraints.providers,
-            ownership: constraints.ownership,
-            tunnel: AnyTunnelMatcher {
+        Self::new(
+            constraints.location,
+            constraints.providers,
+            constraints.ownership,
+            AnyTunnelMatcher {
                 wireguard: constraints.wireguard_constraints.into(),
                 openvpn: constraints.openvpn_constraints,
                 tunnel_type: constraints.tunnel_protocol,
             },
-        }
+        )
     }
 }
 
@@ -40,8 +169,47 @@ impl RelayMatcher<AnyTunnelMatcher> {
             location: self.location,
             providers: self.providers,
             ownership: self.ownership,
+            max_congestion: self.max_congestion,
+            required_tags: self.required_tags,
+            min_bandwidth: self.min_bandwidth,
+            excluded_countries: self.excluded_countries,
+            allowed_cities: self.allowed_cities,
+            asn: self.asn,
+            excluded_asns: self.excluded_asns,
+            prefer_dual_stack: self.prefer_dual_stack,
+            prefer_added_within: self.prefer_added_within,
+            weight_floor: self.weight_floor,
+            require_stable: self.require_stable,
+            #[cfg(feature = "regex")]
+            hostname_exclude: self.hostname_exclude,
+            reachable_transport: self.reachable_transport,
         }
     }
+
+    /// Returns the first tunnel type for which there is an active relay in `location` that
+    /// matches the remaining constraints. The constrained tunnel type is tried first, which
+    /// lets a frontend suggest another tunnel protocol when the selected one isn't available
+    /// in a location. Returns `None` if no tunnel type is available in the location.
+    pub fn first_available_tunnel_in_location(
+        &self,
+        relays: &[Relay],
+        location: &LocationConstraint,
+    ) -> Option<TunnelType> {
+        let tunnel_types = match self.tunnel.tunnel_type {
+            Constraint::Only(TunnelType::OpenVpn) => [TunnelType::OpenVpn, TunnelType::Wireguard],
+            _ => [TunnelType::Wireguard, TunnelType::OpenVpn],
+        };
+
+        tunnel_types.into_iter().find(|tunnel_type| {
+            let mut matcher = self.clone();
+            matcher.location = Constraint::Only(location.clone());
+            matcher.tunnel.tunnel_type = Constraint::Only(*tunnel_type);
+            relays
+                .iter()
+                .filter(|relay| relay.active)
+                .any(|relay| matcher.filter_matching_relay(relay).is_some())
+        })
+    }
 }
 
 impl RelayMatcher<WireguardMatcher> {
@@ -51,237 +219,1030 @@ impl RelayMatcher<WireguardMatcher> {
 }
 
 impl<T: TunnelMatcher> RelayMatcher<T> {
+    /// Returns a matcher with the given location, provider, ownership and tunnel constraints.
+    /// None of the other filters are applied, except that only relays in the stable release
+    /// channel are matched.
+    pub fn new(
+        location: Constraint<LocationConstraint>,
+        providers: Constraint<Providers>,
+        ownership: Constraint<Ownership>,
+        tunnel: T,
+    ) -> Self {
+        RelayMatcher {
+            location,
+            providers,
+            ownership,
+            max_congestion: None,
+            required_tags: vec![],
+            min_bandwidth: None,
+            excluded_countries: HashSet::new(),
+            allowed_cities: HashSet::new(),
+            asn: Constraint::Any,
+            excluded_asns: HashSet::new(),
+            prefer_dual_stack: false,
+            prefer_added_within: None,
+            weight_floor: 0,
+            require_stable: true,
+            #[cfg(feature = "regex")]
+            hostname_exclude: None,
+            reachable_transport: Constraint::Any,
+            tunnel,
+        }
+    }
+
+    /// Returns a copy of the matcher without the location constraint and the allowed cities, so
+    /// that it matches relays in any location that satisfy the remaining constraints. Excluded
+    /// countries are still excluded. See [`RelayList::globally_nearest`].
+    pub fn without_location(&self) -> Self {
+        RelayMatcher {
+            location: Constraint::Any,
+            allowed_cities: HashSet::new(),
+            ..self.clone()
+        }
+    }
+
     /// Filter a relay and its endpoints based on constraints.
     /// Only matching endpoints are included in the returned Relay.
     pub fn filter_matching_relay(&self, relay: &Relay) -> Option<Relay> {
-        if !self.location.matches(relay)
-            || !self.providers.matches(relay)
-            || !self.ownership.matches(relay)
-        {
+        if !RELAY_CHECKS.iter().all(|check| self.passes(*check, relay)) {
             return None;
         }
 
-        self.tunnel.filter_matching_endpoints(relay)
+        self.tunnel
+            .filter_matching_endpoints(relay)
+            .and_then(|relay| self.filter_reachable_endpoints(relay))
     }
 
     pub fn mullvad_endpoint(&self, relay: &Relay) -> Option<MullvadEndpoint> {
         self.tunnel.mullvad_endpoint(relay)
     }
-}
 
-/// TunnelMatcher allows to abstract over different tunnel-specific constraints,
-/// as to not have false dependencies on OpenVpn specific constraints when
-/// selecting only WireGuard tunnels.
-pub trait TunnelMatcher: Clone {
-    /// Filter a relay and its endpoints based on constraints.
-    /// Only matching endpoints are included in the returned Relay.
-    fn filter_matching_endpoints(&self, relay: &Relay) -> Option<Relay>;
-    /// Constructs a MullvadEndpoint for a given Relay using extra data from the relay matcher
-    /// itself.
-    fn mullvad_endpoint(&self, relay: &Relay) -> Option<MullvadEndpoint>;
-}
+    /// Returns the weight to use for `relay` during weighted selection.
+    pub fn selection_weight(&self, relay: &Relay) -> u64 {
+        let mut weight = match relay.weight {
+            0 => 0,
+            weight => weight.max(self.weight_floor),
+        };
+        if self.prefer_dual_stack && relay.ipv6_addr_in.is_some() {
+            weight = weight.saturating_mul(DUAL_STACK_WEIGHT_FACTOR);
+        }
+        if self.is_recently_added(relay) {
+            weight = weight.saturating_mul(RECENTLY_ADDED_WEIGHT_FACTOR);
+        }
+        weight
+    }
 
-impl TunnelMatcher for OpenVpnMatcher {
-    fn filter_matching_endpoints(&self, relay: &Relay) -> Option<Relay> {
-        let tunnels = relay
+    fn is_recently_added(&self, relay: &Relay) -> bool {
+        match (self.prefer_added_within, relay.added_at) {
+            (Some(window), Some(added_at)) => {
+                // The conversion fails for relays added in the future, which count as recent
+                Utc::now()
+                    .signed_duration_since(added_at)
+                    .to_std()
+                    .map(|age| age <= window)
+                    .unwrap_or(true)
+            }
+            _ => false,
+        }
+    }
+
+    fn matches_congestion(&self, relay: &Relay) -> bool {
+        match (self.max_congestion, relay.congestion) {
+            (Some(max_congestion), Some(congestion)) => congestion.0 <= max_congestion,
+            _ => true,
+        }
+    }
+
+    fn matches_tags(&self, relay: &Relay) -> bool {
+        self.required_tags
+            .iter()
+            .all(|required_tag| relay.tags.contains(required_tag))
+    }
+
+    /// Removes the endpoints of `relay` that cannot be reached using `reachable_transport`.
+    /// Returns `None` if no endpoints remain.
+    fn filter_reachable_endpoints(&self, mut relay: Relay) -> Option<Relay> {
+        let protocol = match self.reachable_transport {
+            Constraint::Any => return Some(relay),
+            Constraint::Only(protocol) => protocol,
+        };
+        relay
             .tunnels
             .openvpn
-            .iter()
-            .filter(|endpoint| self.matches(endpoint))
-            .cloned()
-            .collect::<Vec<_>>();
-        if tunnels.is_empty() {
+            .retain(|endpoint| endpoint.protocol == protocol);
+        if protocol == TransportProtocol::Tcp && relay.obfuscators.udp2tcp.is_empty() {
+            relay.tunnels.wireguard.clear();
+        }
+        if relay.tunnels.is_empty() {
             return None;
         }
-        let mut relay = relay.clone();
-        relay.tunnels = RelayTunnels {
-            openvpn: tunnels,
-            wireguard: vec![],
-        };
         Some(relay)
     }
 
-    fn mullvad_endpoint(&self, relay: &Relay) -> Option<MullvadEndpoint> {
-        relay
-            .tunnels
-            .openvpn
-            .choose(&mut rand::thread_rng())
-            .cloned()
-            .map(|endpoint| endpoint.into_mullvad_endpoint(relay.ipv4_addr_in.into()))
+    fn is_in_excluded_country(&self, relay: &Relay) -> bool {
+        if self.excluded_countries.is_empty() {
+            return false;
+        }
+        match &relay.location {
+            Some(location) => self.excluded_countries.contains(&location.country_code),
+            None => true,
+        }
     }
-}
 
-pub type OpenVpnMatcher = OpenVpnConstraints;
+    fn is_in_allowed_city(&self, relay: &Relay) -> bool {
+        if self.allowed_cities.is_empty() {
+            return true;
+        }
+        match &relay.location {
+            Some(location) => self.allowed_cities.iter().any(|(country_code, city_code)| {
+                location.country_code == *country_code && location.city_code == *city_code
+            }),
+            None => false,
+        }
+    }
 
-#[derive(Clone)]
-pub struct AnyTunnelMatcher {
-    pub wireguard: WireguardMatcher,
-    pub openvpn: OpenVpnMatcher,
-    /// in the case that a user hasn't specified a tunnel protocol, the relay
-    /// selector might still construct preferred constraints that do select a
-    /// specific tunnel protocol, which is why the tunnel type may be specified
-    /// in the `AnyTunnelMatcher`.
-    pub tunnel_type: Constraint<TunnelType>,
-}
+    fn matches_asn(&self, relay: &Relay) -> bool {
+        if !self.asn.is_only() && self.excluded_asns.is_empty() {
+            return true;
+        }
+        match relay.asn {
+            Some(asn) => self.asn.matches_eq(&asn) && !self.excluded_asns.contains(&asn),
+            None => false,
+        }
+    }
 
-impl TunnelMatcher for AnyTunnelMatcher {
-    fn filter_matching_endpoints(&self, relay: &Relay) -> Option<Relay> {
-        match self.tunnel_type {
-            Constraint::Any => {
-                let wireguard_relay = self.wireguard.filter_matching_endpoints(relay);
-                let openvpn_relay = self.openvpn.filter_matching_endpoints(relay);
+    fn matches_channel(&self, relay: &Relay) -> bool {
+        !self.require_stable || relay.is_stable()
+    }
 
-                match (wireguard_relay, openvpn_relay) {
-                    (Some(mut matched_relay), Some(openvpn_relay)) => {
-                        matched_relay.tunnels.openvpn = openvpn_relay.tunnels.openvpn;
-                        Some(matched_relay)
-                    }
-                    (Some(relay), None) | (None, Some(relay)) => Some(relay),
-                    _ => None,
-                }
-            }
-            Constraint::Only(TunnelType::OpenVpn) => self.openvpn.filter_matching_endpoints(relay),
-            Constraint::Only(TunnelType::Wireguard) => {
-                self.wireguard.filter_matching_endpoints(relay)
-            }
+    #[cfg(feature = "regex")]
+    fn is_excluded_hostname(&self, relay: &Relay) -> bool {
+        self.hostname_exclude
+            .as_ref()
+            .map(|pattern| pattern.is_match(&relay.hostname))
+            .unwrap_or(false)
+    }
+
+    /// Returns whether `relay` passes the check that rejects relays with `check`.
+    fn passes(&self, check: MatchRejection, relay: &Relay) -> bool {
+        match check {
+            // Not a property of individual relays
+            MatchRejection::NoRelays => true,
+            MatchRejection::Inactive => relay.active,
+            MatchRejection::Location => self.location.matches(relay),
+            MatchRejection::Providers => self.providers.matches(relay),
+            MatchRejection::Ownership => self.ownership.matches(relay),
+            MatchRejection::Congestion => self.matches_congestion(relay),
+            MatchRejection::Tags => self.matches_tags(relay),
+            MatchRejection::Bandwidth => self.matches_bandwidth(relay),
+            MatchRejection::ExcludedCountry => !self.is_in_excluded_country(relay),
+            MatchRejection::AllowedCities => self.is_in_allowed_city(relay),
+            MatchRejection::Asn => self.matches_asn(relay),
+            MatchRejection::UnstableChannel => self.matches_channel(relay),
+            #[cfg(feature = "regex")]
+            MatchRejection::ExcludedHostname => !self.is_excluded_hostname(relay),
+            MatchRejection::Tunnel => self.tunnel.filter_matching_endpoints(relay).is_some(),
+            // Only relays that match the tunnel constraints can be rejected based on reachability
+            MatchRejection::ReachableTransport => self
+                .tunnel
+                .filter_matching_endpoints(relay)
+                .map(|relay| self.filter_reachable_endpoints(relay).is_some())
+                .unwrap_or(true),
         }
     }
 
-    fn mullvad_endpoint(&self, relay: &Relay) -> Option<MullvadEndpoint> {
-        #[cfg(not(target_os = "android"))]
-        match self.tunnel_type {
-            Constraint::Any => vec![
-                self.openvpn.mullvad_endpoint(relay),
-                self.wireguard.mullvad_endpoint(relay),
-            ]
+    /// Returns whether the check that rejects relays with `check` can reject any relay with the
+    /// current constraints.
+    fn is_constrained(&self, check: MatchRejection) -> bool {
+        match check {
+            MatchRejection::NoRelays => false,
+            MatchRejection::Inactive | MatchRejection::Tunnel => true,
+            MatchRejection::Location => self.location.is_only(),
+            MatchRejection::Providers => self.providers.is_only(),
+            MatchRejection::Ownership => self.ownership.is_only(),
+            MatchRejection::Congestion => self.max_congestion.is_some(),
+            MatchRejection::Tags => !self.required_tags.is_empty(),
+            MatchRejection::Bandwidth => self.min_bandwidth.is_some(),
+            MatchRejection::ExcludedCountry => !self.excluded_countries.is_empty(),
+            MatchRejection::AllowedCities => !self.allowed_cities.is_empty(),
+            MatchRejection::Asn => self.asn.is_only() || !self.excluded_asns.is_empty(),
+            MatchRejection::UnstableChannel => self.require_stable,
+            #[cfg(feature = "regex")]
+            MatchRejection::ExcludedHostname => self.hostname_exclude.is_some(),
+            MatchRejection::ReachableTransport => self.reachable_transport.is_only(),
+        }
+    }
+
+    fn matches_bandwidth(&self, relay: &Relay) -> bool {
+        match self.min_bandwidth {
+            Some(min_bandwidth) => relay
+                .bandwidth_mbps
+                .map(|bandwidth| bandwidth >= min_bandwidth)
+                .unwrap_or(false),
+            None => true,
+        }
+    }
+
+    /// Picks a random active relay that matches the constraints, among those whose round-trip
+    /// time is within the `pct`th percentile (0 to 100) of the matching relays. This spreads
+    /// the load over several fast relays instead of always picking the single fastest one. The
+    /// percentile is computed using the nearest-rank method, so the fastest relay is always
+    /// eligible. Relays without a measured round-trip time are never picked.
+    pub fn pick_within_latency_percentile(
+        &self,
+        relays: &[Relay],
+        pct: f32,
+        rng: &mut impl Rng,
+    ) -> Option<Relay> {
+        let mut candidates: Vec<(Duration, Relay)> = relays
+            .iter()
+            .filter(|relay| relay.active)
+            .filter_map(|relay| Some((relay.rtt?, self.filter_matching_relay(relay)?)))
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        candidates.sort_by_key(|(rtt, _)| *rtt);
+
+        let rank = (pct.clamp(0.0, 100.0) / 100.0 * candidates.len() as f32).ceil() as usize;
+        let threshold = candidates[rank.max(1) - 1].0;
+        let eligible: Vec<Relay> = candidates
             .into_iter()
-            .flatten()
-            .collect::<Vec<_>>()
-            .choose(&mut rand::thread_rng())
-            .cloned(),
-            Constraint::Only(TunnelType::OpenVpn) => self.openvpn.mullvad_endpoint(relay),
-            Constraint::Only(TunnelType::Wireguard) => self.wireguard.mullvad_endpoint(relay),
+            .filter(|(rtt, _)| *rtt <= threshold)
+            .map(|(_, relay)| relay)
+            .collect();
+
+        pick_weighted(&eligible, rng, |relay| self.selection_weight(relay)).cloned()
+    }
+
+    /// Picks up to `n` active relays that match the constraints, each in a different city. Relays
+    /// are picked one at a time using weighted random selection, after which the remaining relays
+    /// in the same city are no longer considered. Fewer than `n` relays are returned if there are
+    /// not enough cities with matching relays. Relays without a known location are never picked.
+    pub fn select_n_distinct_cities(
+        &self,
+        relays: &[Relay],
+        n: usize,
+        rng: &mut impl Rng,
+    ) -> Vec<Relay> {
+        let mut candidates: Vec<Relay> = relays
+            .iter()
+            .filter(|relay| relay.active && relay.location.is_some())
+            .filter_map(|relay| self.filter_matching_relay(relay))
+            .collect();
+
+        let weight = |relay: &Relay| self.selection_weight(relay);
+        let mut selected: Vec<Relay> = Vec::with_capacity(n);
+        while selected.len() < n {
+            let relay = match pick_weighted(&candidates, rng, weight) {
+                Some(relay) => relay.clone(),
+                None => break,
+            };
+            let location = relay.location.as_ref().unwrap();
+            candidates
+                .retain(|candidate| !candidate.location.as_ref().unwrap().has_same_city(location));
+            selected.push(relay);
+        }
+        selected
+    }
+
+    /// Picks up to `n` distinct active relays that match the constraints. Relays are picked one
+    /// at a time using weighted random selection. If `per_country_cap` is set, at most that many
+    /// relays are picked from each country, and the rest are picked from other countries. Relays
+    /// without a known location are never picked when there is a cap. Fewer than `n` relays are
+    /// returned if there are not enough matching relays.
+    pub fn select_n(
+        &self,
+        relays: &[Relay],
+        n: usize,
+        per_country_cap: Option<usize>,
+        rng: &mut impl Rng,
+    ) -> Vec<Relay> {
+        if per_country_cap == Some(0) {
+            return vec![];
         }
+        let mut candidates: Vec<Relay> = relays
+            .iter()
+            .filter(|relay| relay.active)
+            .filter(|relay| per_country_cap.is_none() || relay.location.is_some())
+            .filter_map(|relay| self.filter_matching_relay(relay))
+            .collect();
 
-        #[cfg(target_os = "android")]
-        self.wireguard.mullvad_endpoint(relay)
+        let weight = |relay: &Relay| self.selection_weight(relay);
+        let mut selected: Vec<Relay> = Vec::with_capacity(n);
+        let mut country_counts: HashMap<CountryCode, usize> = HashMap::new();
+        while selected.len() < n {
+            let relay = match pick_weighted(&candidates, rng, weight) {
+                Some(relay) => relay.clone(),
+                None => break,
+            };
+            candidates.retain(|candidate| candidate.hostname != relay.hostname);
+            if let (Some(cap), Some(location)) = (per_country_cap, &relay.location) {
+                let count = country_counts
+                    .entry(location.country_code.clone())
+                    .or_insert(0);
+                *count += 1;
+                if *count >= cap {
+                    candidates.retain(|candidate| {
+                        candidate.location.as_ref().unwrap().country_code != location.country_code
+                    });
+                }
+            }
+            selected.push(relay);
+        }
+        selected
     }
-}
 
-#[derive(Clone)]
-pub struct WireguardMatcher {
-    /// The peer is an already selected peer relay to be used with multihop.
-    /// It's stored here so we can exclude it from further selections being made.
-    pub peer: Option<Relay>,
-    pub port: Constraint<u16>,
-    pub ip_version: Constraint<IpVersion>,
-}
+    /// Picks an active relay that matches the constraints using weighted selection, seeded from
+    /// `session_token`. The same token always yields the same relay for the same set of relays,
+    /// regardless of their order, while different tokens spread across the relays.
+    pub fn select_sticky(&self, relays: &[Relay], session_token: u64) -> Option<Relay> {
+        let mut candidates: Vec<Relay> = relays
+            .iter()
+            .filter(|relay| relay.active)
+            .filter_map(|relay| self.filter_matching_relay(relay))
+            .collect();
+        candidates.sort_by(|a, b| a.hostname.cmp(&b.hostname));
 
-impl WireguardMatcher {
-    fn wg_data_to_endpoint(
+        let mut rng = StdRng::seed_from_u64(session_token);
+        pick_weighted(&candidates, &mut rng, |relay| self.selection_weight(relay)).cloned()
+    }
+
+    /// Picks a random active relay that matches the constraints, where the weight of each relay
+    /// is divided by `1 + d`, `d` being its distance in kilometers from `origin`. Nearby relays
+    /// are thereby preferred, while relays further away can still be picked. Relays without a
+    /// known location are never picked.
+    pub fn pick_geo_weighted(
         &self,
-        relay: &Relay,
-        data: WireguardEndpointData,
-    ) -> Option<MullvadEndpoint> {
-        let host = self.get_address_for_wireguard_relay(relay)?;
-        let port = self.get_port_for_wireguard_relay(&data)?;
-        let peer_config = wireguard::PeerConfig {
-            public_key: data.public_key,
-            endpoint: SocketAddr::new(host, port),
-            allowed_ips: all_of_the_internet(),
-            psk: None,
-        };
-        Some(MullvadEndpoint::Wireguard(MullvadWireguardEndpoint {
-            peer: peer_config,
-            exit_peer: None,
-            ipv4_gateway: data.ipv4_gateway,
-            ipv6_gateway: data.ipv6_gateway,
-        }))
+        relays: &[Relay],
+        origin: &Coordinates,
+        rng: &mut impl Rng,
+    ) -> Option<Relay> {
+        let candidates: Vec<(f64, Relay)> = relays
+            .iter()
+            .filter(|relay| relay.active)
+            .filter_map(|relay| {
+                let distance = relay.location.as_ref()?.distance_from(origin);
+                let relay = self.filter_matching_relay(relay)?;
+                Some((
+                    self.selection_weight(&relay) as f64 / (1.0 + distance),
+                    relay,
+                ))
+            })
+            .collect();
+
+        pick_weighted(&candidates, rng, |(weight, _)| *weight).map(|(_, relay)| relay.clone())
     }
 
-    fn get_address_for_wireguard_relay(&self, relay: &Relay) -> Option<IpAddr> {
-        match self.ip_version {
-            Constraint::Any | Constraint::Only(IpVersion::V4) => Some(relay.ipv4_addr_in.into()),
-            Constraint::Only(IpVersion::V6) => relay.ipv6_addr_in.map(|addr| addr.into()),
+    /// Returns the active relay in `list` that matches the constraints and is closest to
+    /// `origin`, along with an endpoint for it. Distances are measured to the coordinates of the
+    /// city of each relay. Within the nearest city, the matching relay with the highest weight is
+    /// picked. Cities whose relay does not yield an endpoint are skipped.
+    pub fn select_nearest(
+        &self,
+        list: &RelayList,
+        origin: &Coordinates,
+    ) -> Option<(Relay, MullvadEndpoint)> {
+        list.nearest_cities(origin, usize::MAX, self)
+            .into_iter()
+            .find_map(|(_city, relay)| {
+                let relay = self.filter_matching_relay(&relay)?;
+                let endpoint = self.mullvad_endpoint(&relay)?;
+                Some((relay, endpoint))
+            })
+    }
+
+    /// Picks a random active relay in `list` that matches the constraints using weighted
+    /// selection, and returns it along with its country, its city and an endpoint for it. The
+    /// location of each relay is taken from the country and city it is listed in.
+    pub fn select_with_location(
+        &self,
+        list: &RelayList,
+    ) -> Option<(Relay, RelayListCountry, RelayListCity, MullvadEndpoint)> {
+        let (country, city, relay) = self.pick_from_list(list)?;
+        let endpoint = self.mullvad_endpoint(&relay)?;
+        Some((relay, country.clone(), city.clone(), endpoint))
+    }
+
+    /// Picks a random active relay in `list` that matches the constraints and returns it along
+    /// with an endpoint for it, like [`RelayMatcher::select_with_location`]. Fails if the list
+    /// was fetched more than `max_age` ago, or if it is unknown when it was fetched, to avoid
+    /// connecting to relays that may have been decommissioned since.
+    pub fn select_if_fresh(
+        &self,
+        list: &RelayList,
+        max_age: Duration,
+    ) -> Result<Option<(Relay, MullvadEndpoint)>, StaleRelayList> {
+        let age = list.fetched_at.map(|fetched_at| {
+            // Lists fetched in the future, e.g. due to clock adjustments, count as new
+            SystemTime::now()
+                .duration_since(fetched_at)
+                .unwrap_or_default()
+        });
+        match age {
+            Some(age) if age <= max_age => (),
+            _ => return Err(StaleRelayList { age, max_age }),
         }
+
+        Ok(self
+            .pick_from_list(list)
+            .and_then(|(_country, _city, relay)| {
+                let endpoint = self.mullvad_endpoint(&relay)?;
+                Some((relay, endpoint))
+            }))
     }
 
-    fn get_port_for_wireguard_relay(&self, data: &WireguardEndpointData) -> Option<u16> {
-        match self.port {
-            Constraint::Any => {
-                let get_port_amount =
-                    |range: &(u16, u16)| -> u64 { (1 + range.1 - range.0) as u64 };
-                let port_amount: u64 = data.port_ranges.iter().map(get_port_amount).sum();
+    /// Picks a random active relay in `list` that matches the constraints using weighted
+    /// selection. The location of each relay is taken from the country and city it is listed in.
+    fn pick_from_list<'a>(
+        &self,
+        list: &'a RelayList,
+    ) -> Option<(&'a RelayListCountry, &'a RelayListCity, Relay)> {
+        let candidates: Vec<(&RelayListCountry, &RelayListCity, Relay)> = list
+            .relays_with_location()
+            .filter(|(_, _, relay)| relay.active)
+            .filter_map(|(country, city, relay)| {
+                let mut relay = relay.clone();
+                relay.location = Some(Location {
+                    country: country.name.clone(),
+                    country_code: country.code.clone(),
+                    city: city.name.clone(),
+                    city_code: city.code.clone(),
+                    latitude: city.latitude,
+                    longitude: city.longitude,
+                });
+                let relay = self.filter_matching_relay(&relay)?;
+                Some((country, city, relay))
+            })
+            .collect();
 
-                if port_amount < 1 {
-                    return None;
-                }
+        let mut rng = rand::thread_rng();
+        pick_weighted(&candidates, &mut rng, |(_, _, relay)| {
+            self.selection_weight(relay)
+        })
+        .map(|(country, city, relay)| (*country, *city, relay.clone()))
+    }
+
+    /// Picks a random active relay that matches the constraints, trading off load balancing
+    /// against latency. The sampling weight of each relay is `weight^(1 - alpha) * (1 /
+    /// rtt)^alpha`, where `weight` is the [`RelayMatcher::selection_weight`] and the round-trip
+    /// time is in milliseconds. An `alpha` of 0 therefore only considers the weight of the
+    /// relays, and an `alpha` of 1 only their latency. `alpha` is clamped to `[0, 1]`. Relays
+    /// with a weight of 0 are only picked if no other relay matches. Relays without a measured
+    /// round-trip time are assumed to have the median round-trip time of the matching relays.
+    pub fn pick_blended(&self, relays: &[Relay], alpha: f32, rng: &mut impl Rng) -> Option<Relay> {
+        let alpha = f64::from(alpha.clamp(0.0, 1.0));
+        let candidates: Vec<Relay> = relays
+            .iter()
+            .filter(|relay| relay.active)
+            .filter_map(|relay| self.filter_matching_relay(relay))
+            .collect();
 
-                let mut port_index = rand::thread_rng().gen_range(0, port_amount);
+        // Avoid dividing by zero for sub-millisecond round-trip times
+        let rtt_ms = |rtt: Duration| (rtt.as_secs_f64() * 1000.0).max(1.0);
+        let mut measured_rtts: Vec<f64> = candidates
+            .iter()
+            .filter_map(|relay| relay.rtt.map(rtt_ms))
+            .collect();
+        measured_rtts.sort_by(|a, b| a.partial_cmp(b).unwrap_or(cmp::Ordering::Equal));
+        let middle = measured_rtts.len() / 2;
+        let median_rtt_ms = match measured_rtts.len() {
+            0 => 1.0,
+            len if len % 2 == 0 => (measured_rtts[middle - 1] + measured_rtts[middle]) / 2.0,
+            _ => measured_rtts[middle],
+        };
 
-                for range in data.port_ranges.iter() {
-                    let ports_in_range = get_port_amount(range);
-                    if port_index < ports_in_range {
-                        return Some(port_index as u16 + range.0);
+        let candidates: Vec<(f64, Relay)> = candidates
+            .into_iter()
+            .map(|relay| {
+                let blended_weight = match self.selection_weight(&relay) {
+                    // `0^0` is 1, so the weight would otherwise be ignored when `alpha` is 1
+                    0 => 0.0,
+                    weight => {
+                        let rtt_ms = relay.rtt.map(rtt_ms).unwrap_or(median_rtt_ms);
+                        (weight as f64).powf(1.0 - alpha) * rtt_ms.recip().powf(alpha)
                     }
-                    port_index -= ports_in_range;
+                };
+                (blended_weight, relay)
+            })
+            .collect();
+
+        pick_weighted(&candidates, rng, |(weight, _)| *weight).map(|(_, relay)| relay.clone())
+    }
+
+    /// Returns whether `endpoint` can still be used according to `relays`, e.g. after the relay
+    /// list has been updated. The relay is looked up by the address of the endpoint and has to
+    /// be active, match the constraints, and still offer the endpoint.
+    pub fn endpoint_still_valid(&self, endpoint: &MullvadEndpoint, relays: &[Relay]) -> bool {
+        let address = endpoint.to_endpoint().address;
+        relays
+            .iter()
+            .filter(|relay| relay.active)
+            .filter(|relay| {
+                IpAddr::from(relay.ipv4_addr_in) == address.ip()
+                    || relay.ipv6_addr_in.map(IpAddr::from) == Some(address.ip())
+            })
+            .filter_map(|relay| self.filter_matching_relay(relay))
+            .any(|relay| match endpoint {
+                MullvadEndpoint::OpenVpn(endpoint) => {
+                    relay.tunnels.openvpn.iter().any(|data| {
+                        data.port == address.port() && data.protocol == endpoint.protocol
+                    })
                 }
-                log::error!("Port selection algorithm is broken!");
-                None
+                MullvadEndpoint::Wireguard(endpoint) => {
+                    relay.tunnels.wireguard.iter().any(|data| {
+                        data.public_key == endpoint.peer.public_key
+                            && data
+                                .port_ranges
+                                .iter()
+                                .any(|range| range.0 <= address.port() && address.port() <= range.1)
+                    })
+                }
+            })
+    }
+
+    /// Resolves a hostname prefix, such as `se-got`, to an active relay that matches the
+    /// constraints. A hostname that matches the prefix exactly is always considered unique.
+    pub fn resolve_prefix(&self, relays: &[Relay], prefix: &str) -> ResolveResult {
+        let mut matching_relays: Vec<Relay> = relays
+            .iter()
+            .filter(|relay| relay.active && relay.hostname.starts_with(prefix))
+            .filter_map(|relay| self.filter_matching_relay(relay))
+            .collect();
+
+        if let Some(index) = matching_relays
+            .iter()
+            .position(|relay| relay.hostname == prefix)
+        {
+            return ResolveResult::Unique(Box::new(matching_relays.swap_remove(index)));
+        }
+
+        match matching_relays.len() {
+            0 => ResolveResult::NotFound,
+            1 => ResolveResult::Unique(Box::new(matching_relays.remove(0))),
+            _ => ResolveResult::Ambiguous(
+                matching_relays
+                    .into_iter()
+                    .map(|relay| relay.hostname)
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Picks a random active relay that matches the constraints, weighted by
+    /// [`RelayMatcher::selection_weight`], but stops scanning once `max` has elapsed and picks
+    /// among the relays visited so far. Returns `None` if the budget runs out before any matching
+    /// relay has been visited.
+    ///
+    /// The scan starts at a random offset into `relays` and uses weighted reservoir sampling, so
+    /// when the budget runs out, the result is a weighted pick from a random sample rather than
+    /// from the whole list. If all visited matching relays have a weight of 0, one of them is
+    /// picked without bias.
+    pub fn select_with_budget(&self, relays: &[Relay], max: Duration) -> Option<Relay> {
+        if relays.is_empty() {
+            return None;
+        }
+        let deadline = Instant::now() + max;
+        let mut rng = rand::thread_rng();
+        let start = rng.gen_range(0, relays.len());
+
+        let mut weighted_pick: Option<Relay> = None;
+        let mut total_weight: u64 = 0;
+        let mut uniform_pick: Option<Relay> = None;
+        let mut matched: u64 = 0;
+        for relay in relays.iter().cycle().skip(start).take(relays.len()) {
+            if Instant::now() >= deadline {
+                log::debug!("Relay selection budget of {:?} exceeded", max);
+                break;
             }
-            Constraint::Only(port) => {
-                if data
-                    .port_ranges
-                    .iter()
-                    .any(|range| (range.0 <= port && port <= range.1))
-                {
-                    Some(port)
-                } else {
-                    None
+            if !relay.active {
+                continue;
+            }
+            if let Some(relay) = self.filter_matching_relay(relay) {
+                let weight = self.selection_weight(&relay);
+                if weight > 0 {
+                    total_weight = total_weight.saturating_add(weight);
+                    if rng.gen_range(0, total_weight) < weight {
+                        weighted_pick = Some(relay.clone());
+                    }
+                }
+                matched += 1;
+                if rng.gen_range(0, matched) == 0 {
+                    uniform_pick = Some(relay);
                 }
             }
         }
+        weighted_pick.or(uniform_pick)
     }
-}
 
-impl From<WireguardConstraints> for WireguardMatcher {
-    fn from(constraints: WireguardConstraints) -> Self {
-        Self {
-            peer: None,
-            port: constraints.port,
-            ip_version: constraints.ip_version,
+    /// Returns `None` if any relay in `relays` matches the constraints. Otherwise, returns the
+    /// constraints that jointly caused all relays to be rejected, ordered by the number of relays
+    /// they rejected. Each relay is rejected by at least one of the returned reasons, and reasons
+    /// that only rejected relays already covered by a more common reason are left out. If
+    /// `relays` is empty, the only reason is [`MatchRejection::NoRelays`].
+    pub fn unsatisfiable_reason(&self, relays: &[Relay]) -> Option<Vec<MatchRejection>> {
+        if relays.is_empty() {
+            return Some(vec![MatchRejection::NoRelays]);
+        }
+
+        let mut rejected = vec![];
+        for relay in relays {
+            let rejections = self.rejections(relay);
+            if rejections.is_empty() {
+                return None;
+            }
+            rejected.push(rejections);
         }
+
+        let mut reasons = vec![];
+        while !rejected.is_empty() {
+            let mut counts: HashMap<MatchRejection, usize> = HashMap::new();
+            for rejection in rejected.iter().flatten() {
+                *counts.entry(*rejection).or_insert(0) += 1;
+            }
+            // Ties are broken in favor of the constraint that is checked first
+            let reason = counts
+                .into_iter()
+                .max_by_key(|(reason, count)| (*count, cmp::Reverse(*reason)))
+                .map(|(reason, _)| reason)?;
+            reasons.push(reason);
+            rejected.retain(|rejections| !rejections.contains(&reason));
+        }
+        Some(reasons)
     }
-}
 
-impl Match<WireguardEndpointData> for WireguardMatcher {
-    fn matches(&self, endpoint: &WireguardEndpointData) -> bool {
-        match self.port {
-            Constraint::Any => true,
-            Constraint::Only(port) => endpoint
-                .port_ranges
-                .iter()
-                .any(|range| (port >= range.0 && port <= range.1)),
-        }
+    fn rejections(&self, relay: &Relay) -> Vec<MatchRejection> {
+        all_checks()
+            .filter(|check| !self.passes(*check, relay))
+            .collect()
     }
 }
 
-impl TunnelMatcher for WireguardMatcher {
-    fn filter_matching_endpoints(&self, relay: &Relay) -> Option<Relay> {
-        if self
-            .peer
-            .as_ref()
-            .map(|peer_relay| peer_relay.hostname == relay.hostname)
-            .unwrap_or(false)
+impl<T: TunnelMatcher> Match<Relay> for RelayMatcher<T> {
+    fn matches(&self, relay: &Relay) -> bool {
+        self.filter_matching_relay(relay).is_some()
+    }
+}
+
+impl<T: TunnelMatcher + fmt::Display> RelayMatcher<T> {
+    /// Returns a human-readable, multi-line explanation of a relay selection. It lists the
+    /// constraints in effect, the number of candidate relays remaining after each filtering
+    /// stage, and the relay and endpoint that would be picked. Intended for debug output.
+    pub fn explain_selection(&self, relays: &[Relay]) -> String {
+        let mut explanation = String::new();
+        // Writing to a `String` cannot fail
+        let _ = self.write_explanation(&mut explanation, relays);
+        explanation
+    }
+
+    fn write_explanation(&self, out: &mut String, relays: &[Relay]) -> fmt::Result {
+        writeln!(out, "Constraints:")?;
+        match &self.location {
+            Constraint::Any => writeln!(out, "  location: any location")?,
+            Constraint::Only(location) => writeln!(out, "  location: {}", location)?,
+        }
+        match &self.providers {
+            Constraint::Any => writeln!(out, "  providers: any provider")?,
+            Constraint::Only(providers) => writeln!(out, "  providers: {}", providers)?,
+        }
+        match &self.ownership {
+            Constraint::Any => writeln!(out, "  ownership: any")?,
+            Constraint::Only(ownership) => writeln!(out, "  ownership: {}", ownership)?,
+        }
+        writeln!(out, "  tunnel: {}", self.tunnel)?;
+        if let Some(max_congestion) = self.max_congestion {
+            writeln!(out, "  max congestion: {}", max_congestion)?;
+        }
+        if !self.required_tags.is_empty() {
+            writeln!(out, "  required tags: {}", self.required_tags.join(", "))?;
+        }
+        if let Some(min_bandwidth) = self.min_bandwidth {
+            writeln!(out, "  min bandwidth: {} Mbps", min_bandwidth)?;
+        }
+        #[cfg(feature = "regex")]
+        if let Some(pattern) = &self.hostname_exclude {
+            writeln!(out, "  excluded hostnames: {}", pattern)?;
+        }
+        if let Constraint::Only(protocol) = self.reachable_transport {
+            writeln!(out, "  reachable over: {}", protocol)?;
+        }
+
+        writeln!(out, "Candidates:")?;
+        writeln!(out, "  {} relays in total", relays.len())?;
+        let mut candidates: Vec<&Relay> = relays.iter().collect();
+        for check in all_checks() {
+            if self.is_constrained(check) {
+                candidates.retain(|relay| self.passes(check, relay));
+                writeln!(out, "  {} {}", candidates.len(), check.description())?;
+            }
+        }
+        let candidates: Vec<Relay> = candidates
+            .into_iter()
+            .filter_map(|relay| self.filter_matching_relay(relay))
+            .collect();
+
+        let mut rng = rand::thread_rng();
+        let relay = match pick_weighted(&candidates, &mut rng, |relay| self.selection_weight(relay))
         {
-            return None;
+            Some(relay) => relay,
+            None => return write!(out, "No relay matches the constraints"),
+        };
+        let total_weight: u64 = candidates
+            .iter()
+            .map(|relay| self.selection_weight(relay))
+            .sum();
+        writeln!(
+            out,
+            "Picked {} with weight {} out of a total weight of {}",
+            relay.hostname,
+            self.selection_weight(relay),
+            total_weight
+        )?;
+        match self.tunnel.mullvad_endpoint(relay) {
+            Some(endpoint) => {
+                let endpoint = endpoint.to_endpoint();
+                write!(
+                    out,
+                    "Using port {} over {}, picked at random among the endpoints matching {}",
+                    endpoint.address.port(),
+                    endpoint.protocol,
+                    self.tunnel
+                )
+            }
+            None => write!(
+                out,
+                "No endpoint could be constructed for {}",
+                relay.hostname
+            ),
+        }
+    }
+}
+
+/// Builds a [`RelayMatcher`] incrementally. Any constraint that is not set matches all relays,
+/// and the tunnel matcher is only supplied in the final step, so a WireGuard-only matcher can be
+/// built without any OpenVPN constraints.
+#[derive(Clone, Default)]
+pub struct RelayMatcherBuilder {
+    location: Constraint<LocationConstraint>,
+    providers: Constraint<Providers>,
+    ownership: Constraint<Ownership>,
+    max_congestion: Option<u8>,
+    required_tags: Vec<String>,
+    min_bandwidth: Option<u32>,
+    excluded_countries: HashSet<CountryCode>,
+    allowed_cities: HashSet<(CountryCode, CityCode)>,
+    asn: Constraint<u32>,
+    excluded_asns: HashSet<u32>,
+    prefer_dual_stack: bool,
+    prefer_added_within: Option<Duration>,
+    weight_floor: u64,
+    allow_unstable: bool,
+    #[cfg(feature = "regex")]
+    hostname_exclude: Option<Regex>,
+    reachable_transport: Constraint<TransportProtocol>,
+}
+
+impl RelayMatcherBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn location(mut self, location: LocationConstraint) -> Self {
+        self.location = Constraint::Only(location);
+        self
+    }
+
+    pub fn providers(mut self, providers: Providers) -> Self {
+        self.providers = Constraint::Only(providers);
+        self
+    }
+
+    pub fn ownership(mut self, ownership: Ownership) -> Self {
+        self.ownership = Constraint::Only(ownership);
+        self
+    }
+
+    pub fn max_congestion(mut self, max_congestion: u8) -> Self {
+        self.max_congestion = Some(max_congestion);
+        self
+    }
+
+    pub fn required_tags(mut self, required_tags: Vec<String>) -> Self {
+        self.required_tags = required_tags;
+        self
+    }
+
+    pub fn min_bandwidth(mut self, min_bandwidth: u32) -> Self {
+        self.min_bandwidth = Some(min_bandwidth);
+        self
+    }
+
+    pub fn excluded_countries(mut self, excluded_countries: HashSet<CountryCode>) -> Self {
+        self.excluded_countries = excluded_countries;
+        self
+    }
+
+    pub fn allowed_cities(mut self, allowed_cities: HashSet<(CountryCode, CityCode)>) -> Self {
+        self.allowed_cities = allowed_cities;
+        self
+    }
+
+    pub fn asn(mut self, asn: u32) -> Self {
+        self.asn = Constraint::Only(asn);
+        self
+    }
+
+    pub fn excluded_asns(mut self, excluded_asns: HashSet<u32>) -> Self {
+        self.excluded_asns = excluded_asns;
+        self
+    }
+
+    pub fn prefer_dual_stack(mut self, prefer_dual_stack: bool) -> Self {
+        self.prefer_dual_stack = prefer_dual_stack;
+        self
+    }
+
+    pub fn prefer_added_within(mut self, prefer_added_within: Duration) -> Self {
+        self.prefer_added_within = Some(prefer_added_within);
+        self
+    }
+
+    pub fn weight_floor(mut self, weight_floor: u64) -> Self {
+        self.weight_floor = weight_floor;
+        self
+    }
+
+    /// Sets whether only stable relays are matched. This is the default.
+    pub fn require_stable(mut self, require_stable: bool) -> Self {
+        self.allow_unstable = !require_stable;
+        self
+    }
+
+    /// See [`hostname_exclude_regex`] for compiling the pattern.
+    #[cfg(feature = "regex")]
+    pub fn hostname_exclude(mut self, hostname_exclude: Regex) -> Self {
+        self.hostname_exclude = Some(hostname_exclude);
+        self
+    }
+
+    pub fn reachable_transport(mut self, reachable_transport: TransportProtocol) -> Self {
+        self.reachable_transport = Constraint::Only(reachable_transport);
+        self
+    }
+
+    /// Returns a matcher for any tunnel type, using `tunnel` for the tunnel constraints.
+    pub fn any_tunnel(self, tunnel: AnyTunnelMatcher) -> RelayMatcher<AnyTunnelMatcher> {
+        self.build(tunnel)
+    }
+
+    /// Returns a matcher that only matches WireGuard relays.
+    pub fn wireguard(self, tunnel: WireguardMatcher) -> RelayMatcher<WireguardMatcher> {
+        self.build(tunnel)
+    }
+
+    fn build<T: TunnelMatcher>(self, tunnel: T) -> RelayMatcher<T> {
+        RelayMatcher {
+            location: self.location,
+            providers: self.providers,
+            ownership: self.ownership,
+            max_congestion: self.max_congestion,
+            required_tags: self.required_tags,
+            min_bandwidth: self.min_bandwidth,
+            excluded_countries: self.excluded_countries,
+            allowed_cities: self.allowed_cities,
+            asn: self.asn,
+            excluded_asns: self.excluded_asns,
+            prefer_dual_stack: self.prefer_dual_stack,
+            prefer_added_within: self.prefer_added_within,
+            weight_floor: self.weight_floor,
+            require_stable: !self.allow_unstable,
+            #[cfg(feature = "regex")]
+            hostname_exclude: self.hostname_exclude,
+            reachable_transport: self.reachable_transport,
+            tunnel,
+        }
+    }
+}
+
+/// A reason for a relay to be rejected by a [`RelayMatcher`], as returned by
+/// [`RelayMatcher::unsatisfiable_reason`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MatchRejection {
+    /// There are no relays to select from, e.g. because the relay list has not been fetched.
+    NoRelays,
+    /// The relay is not active.
+    Inactive,
+    /// The relay is not in the requested location.
+    Location,
+    /// The relay is not run by any of the requested providers.
+    Providers,
+    /// The relay does not have the requested ownership.
+    Ownership,
+    /// The relay is more congested than allowed.
+    Congestion,
+    /// The relay lacks some of the required tags.
+    Tags,
+    /// The relay does not have enough bandwidth.
+    Bandwidth,
+    /// The relay is in an excluded country.
+    ExcludedCountry,
+    /// The relay is not in any of the allowed cities.
+    AllowedCities,
+    /// The relay is not hosted in an allowed autonomous system.
+    Asn,
+    /// The relay is not in the stable release channel.
+    UnstableChannel,
+    /// The hostname of the relay matches [`RelayMatcher::hostname_exclude`].
+    #[cfg(feature = "regex")]
+    ExcludedHostname,
+    /// The relay has no endpoints matching the tunnel constraints.
+    Tunnel,
+    /// The relay has no matching endpoints that are reachable using the required transport
+    /// protocol.
+    ReachableTransport,
+}
+
+impl MatchRejection {
+    /// Describes the relays that are not rejected for this reason, for
+    /// [`RelayMatcher::explain_selection`].
+    fn description(self) -> &'static str {
+        match self {
+            MatchRejection::NoRelays => "in the relay list",
+            MatchRejection::Inactive => "active",
+            MatchRejection::Location => "matching the location",
+            MatchRejection::Providers => "matching the providers",
+            MatchRejection::Ownership => "matching the ownership",
+            MatchRejection::Congestion => "below the congestion threshold",
+            MatchRejection::Tags => "with the required tags",
+            MatchRejection::Bandwidth => "with enough bandwidth",
+            MatchRejection::ExcludedCountry => "outside of the excluded countries",
+            MatchRejection::AllowedCities => "in the allowed cities",
+            MatchRejection::Asn => "in the allowed autonomous systems",
+            MatchRejection::UnstableChannel => "in the stable channel",
+            #[cfg(feature = "regex")]
+            MatchRejection::ExcludedHostname => "with hostnames not matching the excluded pattern",
+            MatchRejection::Tunnel => "with matching tunnel endpoints",
+            MatchRejection::ReachableTransport => "reachable over the required transport protocol",
+        }
+    }
+}
+
+/// Returned by [`RelayMatcher::select_if_fresh`] if the relay list is too old.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaleRelayList {
+    /// Time since the relay list was fetched, or `None` if it is unknown.
+    pub age: Option<Duration>,
+    /// The maximum age that was allowed.
+    pub max_age: Duration,
+}
+
+impl fmt::Display for StaleRelayList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.age {
+            Some(age) => write!(
+                f,
+                "The relay list was fetched {} seconds ago, which is more than the maximum of {} \
+                 seconds",
+                age.as_secs(),
+                self.max_age.as_secs()
+            ),
+            None => write!(f, "It is unknown when the relay list was fetched"),
         }
+    }
+}
+
+impl std::error::Error for StaleRelayList {}
+
+/// The result of resolving a hostname prefix using [`RelayMatcher::resolve_prefix`].
+#[derive(Debug)]
+pub enum ResolveResult {
+    /// A single relay matched the prefix.
+    Unique(Box<Relay>),
+    /// Multiple relays matched the prefix. Contains the matching hostnames.
+    Ambiguous(Vec<String>),
+    /// No relay matched the prefix.
+    NotFound,
+}
+
+/// TunnelMatcher allows to abstract over different tunnel-specific constraints,
+/// as to not have false dependencies on OpenVpn specific constraints when
+/// selecting only WireGuard tunnels.
+pub trait TunnelMatcher: Clone {
+    /// Filter a relay and its endpoints based on constraints.
+    /// Only matching endpoints are included in the returned Relay.
+    fn filter_matching_endpoints(&self, relay: &Relay) -> Option<Relay>;
+    /// Constructs a MullvadEndpoint for a given Relay using extra data from the relay matcher
+    /// itself.
+    fn mullvad_endpoint(&self, relay: &Relay) -> Option<MullvadEndpoint>;
+}
 
+impl TunnelMatcher for OpenVpnMatcher {
+    fn filter_matching_endpoints(&self, relay: &Relay) -> Option<Relay> {
         let tunnels = relay
             .tunnels
-            .wireguard
+            .openvpn
             .iter()
-            .filter(|endpoint| self.matches(*endpoint))
+            .filter(|endpoint| self.matches(endpoint))
             .cloned()
             .collect::<Vec<_>>();
         if tunnels.is_empty() {
@@ -289,17 +1250,2629 @@ impl TunnelMatcher for WireguardMatcher {
         }
         let mut relay = relay.clone();
         relay.tunnels = RelayTunnels {
-            wireguard: tunnels,
-            openvpn: vec![],
+            openvpn: tunnels,
+            wireguard: vec![],
         };
         Some(relay)
     }
 
     fn mullvad_endpoint(&self, relay: &Relay) -> Option<MullvadEndpoint> {
+        // The relay may not have been filtered by this matcher, so only endpoints matching the
+        // constraints may be picked here.
         relay
             .tunnels
-            .wireguard
+            .openvpn
+            .iter()
+            .filter(|endpoint| self.matches(*endpoint))
+            .collect::<Vec<_>>()
             .choose(&mut rand::thread_rng())
-            .and_then(|wg_tunnel| self.wg_data_to_endpoint(relay, (*wg_tunnel).clone()))
+            .map(|endpoint| endpoint.into_mullvad_endpoint(relay.ipv4_addr_in.into()))
+    }
+}
+
+pub type OpenVpnMatcher = OpenVpnConstraints;
+
+#[derive(Clone, Default)]
+pub struct AnyTunnelMatcher {
+    pub wireguard: WireguardMatcher,
+    pub openvpn: OpenVpnMatcher,
+    /// in the case that a user hasn't specified a tunnel protocol, the relay
+    /// selector might still construct preferred constraints that do select a
+    /// specific tunnel protocol, which is why the tunnel type may be specified
+    /// in the `AnyTunnelMatcher`.
+    pub tunnel_type: Constraint<TunnelType>,
+}
+
+impl fmt::Display for AnyTunnelMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.tunnel_type {
+            Constraint::Any => write!(
+                f,
+                "any tunnel protocol with OpenVPN through {} and WireGuard through {}",
+                self.openvpn, self.wireguard
+            ),
+            Constraint::Only(TunnelType::OpenVpn) => write!(f, "OpenVPN over {}", self.openvpn),
+            Constraint::Only(TunnelType::Wireguard) => {
+                write!(f, "WireGuard over {}", self.wireguard)
+            }
+        }
+    }
+}
+
+impl TunnelMatcher for AnyTunnelMatcher {
+    fn filter_matching_endpoints(&self, relay: &Relay) -> Option<Relay> {
+        match self.tunnel_type {
+            Constraint::Any => {
+                let wireguard_relay = self.wireguard.filter_matching_endpoints(relay);
+                let openvpn_relay = self.openvpn.filter_matching_endpoints(relay);
+
+                match (wireguard_relay, openvpn_relay) {
+                    (Some(mut matched_relay), Some(openvpn_relay)) => {
+                        matched_relay.tunnels.openvpn = openvpn_relay.tunnels.openvpn;
+                        Some(matched_relay)
+                    }
+                    (Some(relay), None) | (None, Some(relay)) => Some(relay),
+                    _ => None,
+                }
+            }
+            Constraint::Only(TunnelType::OpenVpn) => self.openvpn.filter_matching_endpoints(relay),
+            Constraint::Only(TunnelType::Wireguard) => {
+                self.wireguard.filter_matching_endpoints(relay)
+            }
+        }
+    }
+
+    fn mullvad_endpoint(&self, relay: &Relay) -> Option<MullvadEndpoint> {
+        self.restricted_mullvad_endpoint(relay, platform_tunnel_restriction())
+    }
+}
+
+impl AnyTunnelMatcher {
+    /// Constructs a MullvadEndpoint using one of the `allowed` tunnel types. If the constrained
+    /// tunnel type is not allowed, any of the allowed tunnel types may be used instead.
+    fn restricted_mullvad_endpoint(
+        &self,
+        relay: &Relay,
+        allowed: &[TunnelType],
+    ) -> Option<MullvadEndpoint> {
+        let tunnel_types = match &self.tunnel_type {
+            Constraint::Only(tunnel_type) if allowed.contains(tunnel_type) => {
+                std::slice::from_ref(tunnel_type)
+            }
+            _ => allowed,
+        };
+        tunnel_types
+            .iter()
+            .filter_map(|tunnel_type| match tunnel_type {
+                TunnelType::OpenVpn => self.openvpn.mullvad_endpoint(relay),
+                TunnelType::Wireguard => self.wireguard.mullvad_endpoint(relay),
+            })
+            .collect::<Vec<_>>()
+            .choose(&mut rand::thread_rng())
+            .cloned()
+    }
+}
+
+/// Returns the tunnel types that can be used on the current platform. Android only supports
+/// WireGuard, so WireGuard is used there even if the constraints specify OpenVPN.
+pub fn platform_tunnel_restriction() -> &'static [TunnelType] {
+    if cfg!(target_os = "android") {
+        &[TunnelType::Wireguard]
+    } else {
+        &[TunnelType::OpenVpn, TunnelType::Wireguard]
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct WireguardMatcher {
+    /// The peer is an already selected peer relay to be used with multihop.
+    /// It's stored here so we can exclude it from further selections being made.
+    pub peer: Option<Relay>,
+    pub port: Constraint<u16>,
+    pub ip_version: Constraint<IpVersion>,
+    /// Index of the WireGuard endpoint, and thereby the public key, to use for each relay. This
+    /// lets a client that is in the middle of a key rotation connect using a specific key
+    /// generation. Relays that do not publish a key at this index are not matched.
+    pub key_index: Option<usize>,
+    /// When selecting a multihop entry relay after the exit relay (`peer`), prefer entry relays
+    /// in the same /24 subnet as the exit relay, and otherwise relays in the same city. Any
+    /// matching relay is used if there are no such relays.
+    pub prefer_local_peer: bool,
+    /// When a peer is set, only match relays located in a different country than the peer.
+    /// Relays whose country cannot be compared with the peer's are not matched. For multihop,
+    /// this ensures that the entry and exit relays are in different countries.
+    pub require_different_country: bool,
+    /// Only match relays that support DAITA. For multihop, this only applies to the entry relay,
+    /// since that is the relay which the client exchanges padded traffic with. The exit relay is
+    /// selected using `WIREGUARD_EXIT_CONSTRAINTS`, which does not require DAITA.
+    pub require_daita: bool,
+    /// Reachability scores of ports, used to weight the random selection of a port when no port
+    /// is constrained. Higher scores make a port more likely to be picked, and ports without a
+    /// score are weighted by [`NEUTRAL_PORT_SCORE`]. Ports are picked uniformly if this is `None`.
+    pub port_scores: Option<HashMap<u16, f32>>,
+    /// Local port to bind the tunnel socket to, recorded on the constructed endpoints.
+    pub source_port: Option<u16>,
+    /// Only match relays whose advertised WireGuard feature version is at least this version.
+    pub min_feature_version: u32,
+    /// Ports recently used with each relay. When set and the port is not constrained, ports that
+    /// have not been used recently with the selected relay are preferred, and the picked port is
+    /// recorded.
+    pub port_usage: Option<PortUsage>,
+}
+
+/// Number of recently used ports that [`PortUsage`] remembers for each relay.
+pub const MAX_RECENT_PORTS_PER_RELAY: usize = 8;
+
+/// Keeps track of the ports recently used with each relay during a session, so that load is
+/// spread over the ports of a relay as well. Clones share the same state, which lets the
+/// matchers created for consecutive selections use the same `PortUsage`.
+#[derive(Clone, Default)]
+pub struct PortUsage {
+    recent_ports: Arc<Mutex<HashMap<String, VecDeque<u16>>>>,
+}
+
+impl PortUsage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the ports recently used with the relay, least recently used first.
+    pub fn recent_ports(&self, hostname: &str) -> Vec<u16> {
+        self.recent_ports
+            .lock()
+            .get(hostname)
+            .map(|ports| ports.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Records that `port` was used with the relay.
+    pub fn record(&self, hostname: &str, port: u16) {
+        let mut recent_ports = self.recent_ports.lock();
+        let ports = recent_ports.entry(hostname.to_owned()).or_default();
+        ports.retain(|recent_port| *recent_port != port);
+        if ports.len() >= MAX_RECENT_PORTS_PER_RELAY {
+            ports.pop_front();
+        }
+        ports.push_back(port);
+    }
+
+    /// Forgets all recorded ports, e.g. when a session ends.
+    pub fn clear(&self) {
+        self.recent_ports.lock().clear();
+    }
+}
+
+/// Score of ports that are not in [`WireguardMatcher::port_scores`].
+pub const NEUTRAL_PORT_SCORE: f32 = 1.0;
+
+impl WireguardMatcher {
+    /// Returns whether both relays have a known location and are in different countries.
+    fn in_different_countries(relay: &Relay, other: &Relay) -> bool {
+        match (&relay.location, &other.location) {
+            (Some(location), Some(other_location)) => {
+                location.country_code != other_location.country_code
+            }
+            _ => false,
+        }
+    }
+
+    fn wg_data_to_endpoint(
+        &self,
+        relay: &Relay,
+        data: WireguardEndpointData,
+    ) -> Option<MullvadEndpoint> {
+        let host = self.get_address_for_wireguard_relay(relay)?;
+        if data.gateways_for(host).is_none() {
+            log::warn!(
+                "Relay {} has no WireGuard gateways that can be used with {}",
+                relay.hostname,
+                host
+            );
+            return None;
+        }
+        let port = match (&self.port_usage, self.port) {
+            (Some(port_usage), Constraint::Any) => {
+                let port = self.get_least_recently_used_port(&relay.hostname, &data, port_usage)?;
+                port_usage.record(&relay.hostname, port);
+                port
+            }
+            _ => self.get_port_for_wireguard_relay(&data)?,
+        };
+        let mut endpoint = data.into_mullvad_endpoint(host, port);
+        if let MullvadEndpoint::Wireguard(endpoint) = &mut endpoint {
+            endpoint.source_port = self.source_port;
+        }
+        Some(endpoint)
+    }
+
+    /// Returns whether an endpoint could be constructed for `relay`, without constructing one.
+    /// This requires the relay to have a WireGuard endpoint that matches the constraints, an
+    /// address of the required IP version, a gateway for that IP version, and at least one port
+    /// satisfying the port constraint. Peer constraints are not considered.
+    pub fn can_serve(&self, relay: &Relay) -> bool {
+        let host = match self.get_address_for_wireguard_relay(relay) {
+            Some(host) => host,
+            None => return false,
+        };
+        let endpoints = match self.key_index {
+            Some(key_index) => relay
+                .tunnels
+                .wireguard
+                .get(key_index..=key_index)
+                .unwrap_or_default(),
+            None => &relay.tunnels.wireguard[..],
+        };
+        endpoints.iter().any(|data| {
+            self.matches(data)
+                && data.gateways_for(host).is_some()
+                && data.port_ranges.iter().any(|(start, end)| start <= end)
+        })
+    }
+
+    /// Returns the address to connect to for the given IP version constraint:
+    ///
+    /// | Constraint   | Address used                                      |
+    /// |--------------|---------------------------------------------------|
+    /// | `Any`        | IPv4. IPv6 is only used when explicitly requested |
+    /// | `Only(V4)`   | IPv4, never IPv6                                  |
+    /// | `Only(V6)`   | IPv6, or `None` if the relay has no IPv6 address  |
+    fn get_address_for_wireguard_relay(&self, relay: &Relay) -> Option<IpAddr> {
+        match self.ip_version {
+            Constraint::Any | Constraint::Only(IpVersion::V4) => Some(relay.ipv4_addr_in.into()),
+            Constraint::Only(IpVersion::V6) => relay.ipv6_addr_in.map(|addr| addr.into()),
+        }
+    }
+
+    /// Returns all addresses of the relay that satisfy the IP version constraint, in the order
+    /// they should be tried. The first address is the one returned by
+    /// `get_address_for_wireguard_relay`, so under `Any` the IPv6 address of the relay, if it has
+    /// one, follows the IPv4 address and can be used as a fallback.
+    pub fn get_addresses_for_wireguard_relay(&self, relay: &Relay) -> Vec<IpAddr> {
+        let ipv4 = IpAddr::from(relay.ipv4_addr_in);
+        let ipv6 = relay.ipv6_addr_in.map(IpAddr::from);
+        match self.ip_version {
+            Constraint::Any => std::iter::once(ipv4).chain(ipv6).collect(),
+            Constraint::Only(IpVersion::V4) => vec![ipv4],
+            Constraint::Only(IpVersion::V6) => ipv6.into_iter().collect(),
+        }
+    }
+
+    fn get_port_for_wireguard_relay(&self, data: &WireguardEndpointData) -> Option<u16> {
+        self.get_port_for_wireguard_relay_with_rng(data, &mut rand::thread_rng())
+    }
+
+    /// Like `get_port_for_wireguard_relay`, but picks the port using an RNG seeded from `seed`,
+    /// so that the same seed and endpoint data always yield the same port. Intended for
+    /// reproducible tests.
+    pub fn get_port_for_wireguard_relay_seeded(
+        &self,
+        data: &WireguardEndpointData,
+        seed: u64,
+    ) -> Option<u16> {
+        self.get_port_for_wireguard_relay_with_rng(data, &mut StdRng::seed_from_u64(seed))
+    }
+
+    fn get_port_for_wireguard_relay_with_rng(
+        &self,
+        data: &WireguardEndpointData,
+        rng: &mut impl Rng,
+    ) -> Option<u16> {
+        match self.port {
+            Constraint::Any => {
+                if let Some(port_scores) = &self.port_scores {
+                    return Self::pick_scored_port(&data.port_ranges, port_scores, rng);
+                }
+
+                let get_port_amount =
+                    |range: &(u16, u16)| -> u64 { (1 + range.1 - range.0) as u64 };
+                let port_amount: u64 = data.port_ranges.iter().map(get_port_amount).sum();
+
+                if port_amount < 1 {
+                    return None;
+                }
+
+                let mut port_index = rng.gen_range(0, port_amount);
+
+                for range in data.port_ranges.iter() {
+                    let ports_in_range = get_port_amount(range);
+                    if port_index < ports_in_range {
+                        return Some(port_index as u16 + range.0);
+                    }
+                    port_index -= ports_in_range;
+                }
+                log::error!("Port selection algorithm is broken!");
+                None
+            }
+            Constraint::Only(port) => {
+                if data
+                    .port_ranges
+                    .iter()
+                    .any(|range| (range.0 <= port && port <= range.1))
+                {
+                    Some(port)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Picks a port like `get_port_for_wireguard_relay`, but among the ports that have not been
+    /// used recently with the relay. If all ports have been used recently, the least recently
+    /// used ports are considered again. With no recorded ports, this is equivalent to
+    /// `get_port_for_wireguard_relay`.
+    fn get_least_recently_used_port(
+        &self,
+        hostname: &str,
+        data: &WireguardEndpointData,
+        port_usage: &PortUsage,
+    ) -> Option<u16> {
+        let recent_ports = port_usage.recent_ports(hostname);
+        let port_ranges = (0..=recent_ports.len())
+            .map(|skip| Self::exclude_ports(&data.port_ranges, &recent_ports[skip..]))
+            .find(|port_ranges| !port_ranges.is_empty())?;
+        let data = WireguardEndpointData {
+            port_ranges,
+            ..data.clone()
+        };
+        self.get_port_for_wireguard_relay(&data)
+    }
+
+    /// Returns `port_ranges` with `ports` removed, splitting ranges where needed.
+    fn exclude_ports(port_ranges: &[(u16, u16)], ports: &[u16]) -> Vec<(u16, u16)> {
+        let mut result = vec![];
+        for &(start, end) in port_ranges {
+            let mut excluded: Vec<u16> = ports
+                .iter()
+                .copied()
+                .filter(|port| start <= *port && *port <= end)
+                .collect();
+            excluded.sort_unstable();
+            excluded.dedup();
+
+            let mut start = u32::from(start);
+            for port in excluded.into_iter().map(u32::from) {
+                if start < port {
+                    result.push((start as u16, (port - 1) as u16));
+                }
+                start = port + 1;
+            }
+            if start <= u32::from(end) {
+                result.push((start as u16, end));
+            }
+        }
+        result
+    }
+
+    /// Returns up to `n` distinct ports of `data` to connect to, e.g. to race handshakes over
+    /// several ports. The ports are picked uniformly at random. If the port is constrained, only
+    /// that port is returned, provided that it is in the port ranges of `data`.
+    pub fn select_ports(
+        &self,
+        data: &WireguardEndpointData,
+        n: usize,
+        rng: &mut impl Rng,
+    ) -> Vec<u16> {
+        let ports_in_range = |range: &(u16, u16)| usize::from(range.1) + 1 - usize::from(range.0);
+        match self.port {
+            Constraint::Only(_) if n == 0 => vec![],
+            Constraint::Only(_) => self
+                .get_port_for_wireguard_relay(data)
+                .into_iter()
+                .collect(),
+            Constraint::Any => {
+                let port_amount: usize = data.port_ranges.iter().map(ports_in_range).sum();
+                rand::seq::index::sample(rng, port_amount, n.min(port_amount))
+                    .into_iter()
+                    .filter_map(|mut port_index| {
+                        data.port_ranges.iter().find_map(|range| {
+                            if port_index < ports_in_range(range) {
+                                Some(range.0 + port_index as u16)
+                            } else {
+                                port_index -= ports_in_range(range);
+                                None
+                            }
+                        })
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Picks a random port in `port_ranges`, weighted by `port_scores`. The ports with a score
+    /// are picked among individually, while the remaining ports share the neutral weight
+    /// and are picked uniformly. If all ports have a score of 0, a port is picked uniformly.
+    fn pick_scored_port(
+        port_ranges: &[(u16, u16)],
+        port_scores: &HashMap<u16, f32>,
+        rng: &mut impl Rng,
+    ) -> Option<u16> {
+        let in_range = |port: u16, range: &(u16, u16)| range.0 <= port && port <= range.1;
+        let mut scored_ports: Vec<(u16, f32)> = port_scores
+            .iter()
+            .filter(|(port, _)| port_ranges.iter().any(|range| in_range(**port, range)))
+            .map(|(port, score)| (*port, score.max(0.0)))
+            .collect();
+        scored_ports.sort_unstable_by_key(|(port, _)| *port);
+
+        let port_amount: u64 = port_ranges
+            .iter()
+            .map(|range| u64::from(range.1) + 1 - u64::from(range.0))
+            .sum();
+        let unscored_amount = port_amount - scored_ports.len() as u64;
+        let scored_weight: f32 = scored_ports.iter().map(|(_, score)| score).sum();
+        let total_weight = scored_weight + unscored_amount as f32 * NEUTRAL_PORT_SCORE;
+
+        if total_weight <= 0.0 {
+            let mut port_index = rng.gen_range(0, port_amount.max(1));
+            return port_ranges.iter().find_map(|range| {
+                let ports_in_range = u64::from(range.1) + 1 - u64::from(range.0);
+                if port_index < ports_in_range {
+                    Some(range.0 + port_index as u16)
+                } else {
+                    port_index -= ports_in_range;
+                    None
+                }
+            });
+        }
+
+        let mut pick = rng.gen_range(0.0, total_weight);
+        for (port, score) in &scored_ports {
+            if pick < *score {
+                return Some(*port);
+            }
+            pick -= score;
+        }
+        if unscored_amount == 0 {
+            // Only reachable due to rounding errors
+            return scored_ports.last().map(|(port, _)| *port);
+        }
+
+        // Pick among the ports that do not have a score, skipping the scored ones
+        let mut port_index = rng.gen_range(0, unscored_amount);
+        for range in port_ranges {
+            let scored_in_range: Vec<u16> = scored_ports
+                .iter()
+                .map(|(port, _)| *port)
+                .filter(|port| in_range(*port, range))
+                .collect();
+            let unscored_in_range =
+                u64::from(range.1) + 1 - u64::from(range.0) - scored_in_range.len() as u64;
+            if port_index < unscored_in_range {
+                let mut port = range.0 + port_index as u16;
+                for scored_port in scored_in_range {
+                    if scored_port <= port {
+                        port += 1;
+                    }
+                }
+                return Some(port);
+            }
+            port_index -= unscored_in_range;
+        }
+        log::error!("Port selection algorithm is broken!");
+        None
+    }
+}
+
+impl fmt::Display for WireguardMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            Constraint::Any => write!(f, "any port")?,
+            Constraint::Only(port) => write!(f, "port {}", port)?,
+        }
+        write!(f, " over ")?;
+        match self.ip_version {
+            Constraint::Any => write!(f, "IPv4 or IPv6")?,
+            Constraint::Only(ip_version) => write!(f, "{}", ip_version)?,
+        }
+        if let Some(key_index) = self.key_index {
+            write!(f, " using key {}", key_index)?;
+        }
+        if self.require_daita {
+            write!(f, " with DAITA")?;
+        }
+        if self.min_feature_version > 0 {
+            write!(
+                f,
+                " with feature version {} or later",
+                self.min_feature_version
+            )?;
+        }
+        if let Some(peer) = &self.peer {
+            write!(f, " with peer {}", peer.hostname)?;
+            if self.require_different_country {
+                write!(f, " in another country")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<WireguardConstraints> for WireguardMatcher {
+    fn from(constraints: WireguardConstraints) -> Self {
+        Self {
+            peer: None,
+            port: constraints.port,
+            ip_version: constraints.ip_version,
+            key_index: None,
+            prefer_local_peer: false,
+            require_different_country: constraints.require_different_country,
+            require_daita: false,
+            port_scores: None,
+            source_port: None,
+            min_feature_version: 0,
+            port_usage: None,
+        }
+    }
+}
+
+impl Match<WireguardEndpointData> for WireguardMatcher {
+    fn matches(&self, endpoint: &WireguardEndpointData) -> bool {
+        if self.require_daita && !endpoint.daita {
+            return false;
+        }
+        if endpoint.feature_version < self.min_feature_version {
+            return false;
+        }
+        match self.port {
+            Constraint::Any => true,
+            Constraint::Only(port) => endpoint
+                .port_ranges
+                .iter()
+                .any(|range| (port >= range.0 && port <= range.1)),
+        }
+    }
+}
+
+impl TunnelMatcher for WireguardMatcher {
+    fn filter_matching_endpoints(&self, relay: &Relay) -> Option<Relay> {
+        if self
+            .peer
+            .as_ref()
+            .map(|peer_relay| peer_relay.hostname == relay.hostname)
+            .unwrap_or(false)
+        {
+            return None;
+        }
+        if let Some(peer) = &self.peer {
+            if self.require_different_country && !Self::in_different_countries(peer, relay) {
+                return None;
+            }
+        }
+
+        let tunnels = match self.key_index {
+            Some(key_index) => match relay.tunnels.wireguard.get(key_index) {
+                Some(endpoint) if self.matches(endpoint) => vec![endpoint.clone()],
+                Some(_) => vec![],
+                None => {
+                    log::debug!(
+                        "Relay {} has no WireGuard key at index {}",
+                        relay.hostname,
+                        key_index
+                    );
+                    vec![]
+                }
+            },
+            None => relay
+                .tunnels
+                .wireguard
+                .iter()
+                .filter(|endpoint| self.matches(*endpoint))
+                .cloned()
+                .collect::<Vec<_>>(),
+        };
+        if tunnels.is_empty() {
+            return None;
+        }
+        let mut relay = relay.clone();
+        relay.tunnels = RelayTunnels {
+            wireguard: tunnels,
+            openvpn: vec![],
+        };
+        Some(relay)
+    }
+
+    fn mullvad_endpoint(&self, relay: &Relay) -> Option<MullvadEndpoint> {
+        relay
+            .tunnels
+            .wireguard
+            .choose(&mut rand::thread_rng())
+            .and_then(|wg_tunnel| self.wg_data_to_endpoint(relay, (*wg_tunnel).clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mullvad_types::{
+        relay_constraints::TransportPort,
+        relay_list::{
+            Congestion, OpenVpnEndpointData, RelayBridges, RelayObfuscators, Udp2TcpEndpointData,
+            STABLE_CHANNEL,
+        },
+    };
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use talpid_types::net::wireguard;
+
+    fn new_relay(hostname: &str, country_code: &str, city_code: &str) -> Relay {
+        Relay {
+            hostname: hostname.to_string(),
+            ipv4_addr_in: "185.213.154.68".parse().unwrap(),
+            ipv6_addr_in: Some("2a03:1b20:5:f011::a09f".parse().unwrap()),
+            include_in_country: true,
+            active: true,
+            owned: true,
+            provider: "31173".to_string(),
+            weight: 1,
+            tags: vec![],
+            bandwidth_mbps: None,
+            asn: None,
+            channel: None,
+            tunnels: RelayTunnels::default(),
+            bridges: RelayBridges::default(),
+            obfuscators: RelayObfuscators::default(),
+            location: Some(Location {
+                country: country_code.to_string(),
+                country_code: country_code.to_string(),
+                city: city_code.to_string(),
+                city_code: city_code.to_string(),
+                latitude: 57.70887,
+                longitude: 11.97456,
+            }),
+            congestion: None,
+            rtt: None,
+            added_at: None,
+        }
+    }
+
+    fn wireguard_relay(hostname: &str, country_code: &str, city_code: &str) -> Relay {
+        Relay {
+            tunnels: RelayTunnels {
+                openvpn: vec![],
+                wireguard: vec![WireguardEndpointData {
+                    port_ranges: vec![(53, 53), (4000, 33433), (33565, 51820), (52000, 60000)],
+                    gateways: vec![(
+                        "10.64.0.1".parse().unwrap(),
+                        "fc00:bbbb:bbbb:bb01::1".parse().unwrap(),
+                    )],
+                    public_key: wireguard::PublicKey::from_base64(
+                        "BLNHNoGO88LjV/wDBa7CUUwUzPq/fO2UwcGLy56hKy4=",
+                    )
+                    .unwrap(),
+                    daita: false,
+                    feature_version: 0,
+                }],
+            },
+            ..new_relay(hostname, country_code, city_code)
+        }
+    }
+
+    fn openvpn_relay(hostname: &str, country_code: &str, city_code: &str) -> Relay {
+        Relay {
+            tunnels: RelayTunnels {
+                openvpn: vec![
+                    OpenVpnEndpointData {
+                        port: 1194,
+                        protocol: TransportProtocol::Udp,
+                    },
+                    OpenVpnEndpointData {
+                        port: 443,
+                        protocol: TransportProtocol::Tcp,
+                    },
+                ],
+                wireguard: vec![],
+            },
+            ..new_relay(hostname, country_code, city_code)
+        }
+    }
+
+    fn any_tunnel_matcher(tunnel_type: Constraint<TunnelType>) -> RelayMatcher<AnyTunnelMatcher> {
+        RelayMatcher::from(RelayConstraints {
+            tunnel_protocol: tunnel_type,
+            ..RelayConstraints::default()
+        })
+    }
+
+    #[test]
+    fn test_first_available_tunnel_in_location() {
+        let relays = vec![
+            wireguard_relay("se-got-wg-001", "se", "got"),
+            openvpn_relay("se-got-001", "se", "got"),
+            openvpn_relay("de-fra-001", "de", "fra"),
+        ];
+        let sweden = LocationConstraint::Country("se".to_string());
+        let germany = LocationConstraint::Country("de".to_string());
+
+        let matcher = any_tunnel_matcher(Constraint::Only(TunnelType::Wireguard));
+        assert_eq!(
+            matcher.first_available_tunnel_in_location(&relays, &sweden),
+            Some(TunnelType::Wireguard)
+        );
+        assert_eq!(
+            matcher.first_available_tunnel_in_location(&relays, &germany),
+            Some(TunnelType::OpenVpn)
+        );
+
+        let matcher = any_tunnel_matcher(Constraint::Only(TunnelType::OpenVpn));
+        assert_eq!(
+            matcher.first_available_tunnel_in_location(&relays, &sweden),
+            Some(TunnelType::OpenVpn)
+        );
+
+        let norway = LocationConstraint::Country("no".to_string());
+        assert_eq!(
+            matcher.first_available_tunnel_in_location(&relays, &norway),
+            None
+        );
+    }
+
+    #[test]
+    fn test_select_nearest() {
+        let city = |code: &str, latitude: f64, longitude: f64, relays: Vec<Relay>| RelayListCity {
+            name: code.to_string(),
+            code: code.to_string(),
+            latitude,
+            longitude,
+            relays,
+        };
+        let country = |code: &str, cities: Vec<RelayListCity>| RelayListCountry {
+            name: code.to_string(),
+            code: code.to_string(),
+            cities,
+        };
+        let mut inactive_relay = wireguard_relay("se-got-wg-002", "se", "got");
+        inactive_relay.active = false;
+        let list = RelayList {
+            etag: None,
+            fetched_at: None,
+            countries: vec![
+                country(
+                    "se",
+                    vec![
+                        city(
+                            "got",
+                            57.70887,
+                            11.97456,
+                            vec![openvpn_relay("se-got-001", "se", "got"), inactive_relay],
+                        ),
+                        city(
+                            "sto",
+                            59.3289,
+                            18.0649,
+                            vec![
+                                Relay {
+                                    weight: 10,
+                                    ..wireguard_relay("se-sto-wg-001", "se", "sto")
+                                },
+                                Relay {
+                                    weight: 20,
+                                    ..wireguard_relay("se-sto-wg-002", "se", "sto")
+                                },
+                            ],
+                        ),
+                    ],
+                ),
+                country(
+                    "us",
+                    vec![city(
+                        "nyc",
+                        40.73061,
+                        -73.935242,
+                        vec![wireguard_relay("us-nyc-wg-001", "us", "nyc")],
+                    )],
+                ),
+            ],
+        };
+        let gothenburg = Coordinates {
+            latitude: 57.70887,
+            longitude: 11.97456,
+        };
+        let new_york = Coordinates {
+            latitude: 40.73061,
+            longitude: -73.935242,
+        };
+
+        // Gothenburg only has an OpenVPN relay and an inactive relay
+        let mut matcher = any_tunnel_matcher(Constraint::Only(TunnelType::Wireguard));
+        let (relay, endpoint) = matcher.select_nearest(&list, &gothenburg).unwrap();
+        assert_eq!(relay.hostname, "se-sto-wg-002");
+        assert!(matches!(endpoint, MullvadEndpoint::Wireguard(_)));
+
+        let (relay, _) = matcher.select_nearest(&list, &new_york).unwrap();
+        assert_eq!(relay.hostname, "us-nyc-wg-001");
+
+        matcher.tunnel.tunnel_type = Constraint::Any;
+        let (relay, endpoint) = matcher.select_nearest(&list, &gothenburg).unwrap();
+        assert_eq!(relay.hostname, "se-got-001");
+        assert!(matches!(endpoint, MullvadEndpoint::OpenVpn(_)));
+
+        // The location constraint is honored
+        matcher.location = Constraint::Only(LocationConstraint::Country("us".to_string()));
+        let (relay, _) = matcher.select_nearest(&list, &gothenburg).unwrap();
+        assert_eq!(relay.hostname, "us-nyc-wg-001");
+
+        matcher.location = Constraint::Only(LocationConstraint::Country("no".to_string()));
+        assert!(matcher.select_nearest(&list, &gothenburg).is_none());
+    }
+
+    #[test]
+    fn test_globally_nearest() {
+        let city = |code: &str, latitude: f64, longitude: f64, relays: Vec<Relay>| RelayListCity {
+            name: code.to_string(),
+            code: code.to_string(),
+            latitude,
+            longitude,
+            relays,
+        };
+        let country = |code: &str, cities: Vec<RelayListCity>| RelayListCountry {
+            name: code.to_string(),
+            code: code.to_string(),
+            cities,
+        };
+        let list = RelayList {
+            etag: None,
+            fetched_at: None,
+            countries: vec![
+                country(
+                    "se",
+                    vec![city(
+                        "got",
+                        57.70887,
+                        11.97456,
+                        vec![wireguard_relay("se-got-wg-001", "se", "got")],
+                    )],
+                ),
+                country(
+                    "dk",
+                    vec![city(
+                        "cph",
+                        55.676098,
+                        12.568337,
+                        vec![openvpn_relay("dk-cph-001", "dk", "cph")],
+                    )],
+                ),
+                country(
+                    "de",
+                    vec![city(
+                        "ber",
+                        52.520008,
+                        13.404954,
+                        vec![Relay {
+                            provider: "M247".to_string(),
+                            owned: false,
+                            ..wireguard_relay("de-ber-wg-001", "de", "ber")
+                        }],
+                    )],
+                ),
+            ],
+        };
+        let berlin = Coordinates {
+            latitude: 52.520008,
+            longitude: 13.404954,
+        };
+        let nearest = |matcher: &RelayMatcher<AnyTunnelMatcher>| {
+            list.globally_nearest(&berlin, matcher)
+                .map(|relay| relay.hostname)
+        };
+
+        let mut matcher = any_tunnel_matcher(Constraint::Only(TunnelType::Wireguard));
+        matcher.location = Constraint::Only(LocationConstraint::Country("se".to_string()));
+        assert_eq!(nearest(&matcher).unwrap(), "se-got-wg-001");
+
+        // The location constraint is ignored
+        assert_eq!(
+            nearest(&matcher.without_location()).unwrap(),
+            "de-ber-wg-001"
+        );
+
+        // The other constraints are still respected
+        let mut owned_matcher = matcher.without_location();
+        owned_matcher.ownership = Constraint::Only(Ownership::MullvadOwned);
+        assert_eq!(nearest(&owned_matcher).unwrap(), "se-got-wg-001");
+
+        let mut provider_matcher = matcher.without_location();
+        provider_matcher.providers =
+            Constraint::Only(Providers::new(["31173".to_string()].into_iter()).unwrap());
+        assert_eq!(nearest(&provider_matcher).unwrap(), "se-got-wg-001");
+        provider_matcher.tunnel.tunnel_type = Constraint::Only(TunnelType::OpenVpn);
+        assert_eq!(nearest(&provider_matcher).unwrap(), "dk-cph-001");
+
+        let mut excluded_matcher = matcher.without_location();
+        excluded_matcher.excluded_countries = ["de".to_string(), "se".to_string()].into();
+        assert_eq!(nearest(&excluded_matcher), None);
+    }
+
+    #[test]
+    fn test_select_with_location() {
+        let relay = |hostname: &str| Relay {
+            location: None,
+            ..wireguard_relay(hostname, "", "")
+        };
+        let city = |name: &str, code: &str, relays: Vec<Relay>| RelayListCity {
+            name: name.to_string(),
+            code: code.to_string(),
+            latitude: 0.0,
+            longitude: 0.0,
+            relays,
+        };
+        let list = RelayList {
+            etag: None,
+            fetched_at: None,
+            countries: vec![
+                RelayListCountry {
+                    name: "Sweden".to_string(),
+                    code: "se".to_string(),
+                    cities: vec![
+                        city("Gothenburg", "got", vec![relay("se-got-wg-001")]),
+                        city(
+                            "Stockholm",
+                            "sto",
+                            vec![relay("se-sto-wg-001"), relay("se-sto-wg-002")],
+                        ),
+                    ],
+                },
+                RelayListCountry {
+                    name: "Norway".to_string(),
+                    code: "no".to_string(),
+                    cities: vec![city("Oslo", "osl", vec![relay("no-osl-wg-001")])],
+                },
+            ],
+        };
+
+        let mut matcher = RelayMatcher::from(RelayConstraints::default()).into_wireguard_matcher();
+        for _ in 0..20 {
+            let (relay, country, city, endpoint) = matcher.select_with_location(&list).unwrap();
+            assert!(city.relays.iter().any(|r| r.hostname == relay.hostname));
+            assert!(country.cities.iter().any(|c| c.code == city.code));
+            assert!(relay
+                .hostname
+                .starts_with(&format!("{}-{}-", country.code, city.code)));
+            let location = relay.location.unwrap();
+            assert_eq!(location.country, country.name);
+            assert_eq!(location.city, city.name);
+            assert!(matches!(endpoint, MullvadEndpoint::Wireguard(_)));
+        }
+
+        matcher.location = Constraint::Only(LocationConstraint::Country("no".to_string()));
+        let (relay, country, city, _) = matcher.select_with_location(&list).unwrap();
+        assert_eq!(relay.hostname, "no-osl-wg-001");
+        assert_eq!(
+            (country.name.as_str(), city.name.as_str()),
+            ("Norway", "Oslo")
+        );
+
+        matcher.location = Constraint::Only(LocationConstraint::Country("de".to_string()));
+        assert!(matcher.select_with_location(&list).is_none());
+    }
+
+    #[test]
+    fn test_select_if_fresh() {
+        let mut list = RelayList {
+            etag: None,
+            countries: vec![RelayListCountry {
+                name: "Sweden".to_string(),
+                code: "se".to_string(),
+                cities: vec![RelayListCity {
+                    name: "Gothenburg".to_string(),
+                    code: "got".to_string(),
+                    latitude: 57.70887,
+                    longitude: 11.97456,
+                    relays: vec![wireguard_relay("se-got-wg-001", "se", "got")],
+                }],
+            }],
+            fetched_at: None,
+        };
+        let matcher = RelayMatcher::from(RelayConstraints::default()).into_wireguard_matcher();
+        let hour = Duration::from_secs(60 * 60);
+
+        assert_eq!(
+            matcher.select_if_fresh(&list, hour).unwrap_err(),
+            StaleRelayList {
+                age: None,
+                max_age: hour
+            }
+        );
+
+        list.fetched_at = Some(SystemTime::now() - 2 * hour);
+        let error = matcher.select_if_fresh(&list, hour).unwrap_err();
+        assert!(error.age.unwrap() >= 2 * hour);
+
+        list.fetched_at = Some(SystemTime::now() - hour / 2);
+        let (relay, endpoint) = matcher.select_if_fresh(&list, hour).unwrap().unwrap();
+        assert_eq!(relay.hostname, "se-got-wg-001");
+        assert!(matches!(endpoint, MullvadEndpoint::Wireguard(_)));
+
+        // A fresh list without matching relays is not an error
+        let mut matcher = matcher;
+        matcher.location = Constraint::Only(LocationConstraint::Country("no".to_string()));
+        assert_eq!(matcher.select_if_fresh(&list, hour), Ok(None));
+    }
+
+    #[test]
+    fn test_select_with_budget() {
+        let mut relays: Vec<Relay> = (0..100_000)
+            .map(|index| {
+                let mut relay = wireguard_relay(&format!("se-got-wg-{}", index), "se", "got");
+                relay.weight = index % 1000;
+                relay
+            })
+            .collect();
+        relays.push(openvpn_relay("se-got-001", "se", "got"));
+
+        let matcher = any_tunnel_matcher(Constraint::Only(TunnelType::Wireguard));
+
+        // No relay is visited without any budget
+        assert!(matcher
+            .select_with_budget(&relays, Duration::ZERO)
+            .is_none());
+
+        // A generous budget yields a matching relay
+        let relay = matcher
+            .select_with_budget(&relays, Duration::from_secs(3600))
+            .expect("No relay selected within budget");
+        assert!(!relay.tunnels.wireguard.is_empty());
+        assert!(relay.tunnels.openvpn.is_empty());
+
+        // The budget is enforced even if no relay matches
+        let unsatisfiable = any_tunnel_matcher(Constraint::Only(TunnelType::OpenVpn));
+        let mut wireguard_relays = relays.clone();
+        wireguard_relays.pop();
+        let started = Instant::now();
+        assert!(unsatisfiable
+            .select_with_budget(&wireguard_relays, Duration::ZERO)
+            .is_none());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_select_with_budget_is_weighted() {
+        let relays: Vec<Relay> = [0, 1, 9]
+            .iter()
+            .map(|&weight| {
+                let mut relay = wireguard_relay(&format!("se-got-wg-{}", weight), "se", "got");
+                relay.weight = weight;
+                relay
+            })
+            .collect();
+        let matcher = any_tunnel_matcher(Constraint::Only(TunnelType::Wireguard));
+
+        let mut counts: HashMap<u64, usize> = HashMap::new();
+        for _ in 0..1000 {
+            let relay = matcher
+                .select_with_budget(&relays, Duration::from_secs(3600))
+                .expect("No relay selected within budget");
+            *counts.entry(relay.weight).or_default() += 1;
+        }
+        assert_eq!(counts.get(&0), None);
+        assert!(counts[&1] > 0);
+        assert!(counts[&9] > 700);
+
+        // Relays with a weight of 0 are still picked if no relay has a weight
+        let zero_weight: Vec<Relay> = relays[..1].to_vec();
+        assert!(matcher
+            .select_with_budget(&zero_weight, Duration::from_secs(3600))
+            .is_some());
+    }
+
+    #[test]
+    fn test_openvpn_endpoint_honors_transport_protocol() {
+        let relay = openvpn_relay("se-got-001", "se", "got");
+        let mut matcher = any_tunnel_matcher(Constraint::Only(TunnelType::OpenVpn));
+        matcher.tunnel.openvpn.port = Constraint::Only(TransportPort {
+            protocol: TransportProtocol::Tcp,
+            port: Constraint::Any,
+        });
+
+        for _ in 0..100 {
+            // The endpoint must honor the constraints even if the relay hasn't been filtered
+            let endpoint = matcher
+                .mullvad_endpoint(&relay)
+                .expect("Failed to construct an OpenVPN endpoint")
+                .to_endpoint();
+            assert_eq!(endpoint.protocol, TransportProtocol::Tcp);
+
+            let filtered_relay = matcher.filter_matching_relay(&relay).unwrap();
+            let endpoint = matcher
+                .mullvad_endpoint(&filtered_relay)
+                .expect("Failed to construct an OpenVPN endpoint")
+                .to_endpoint();
+            assert_eq!(endpoint.protocol, TransportProtocol::Tcp);
+        }
+    }
+
+    #[test]
+    fn test_openvpn_endpoint_by_latency() {
+        let relay = openvpn_relay("se-got-001", "se", "got");
+        let matcher = OpenVpnMatcher::default();
+        let protocol = |latencies: &HashMap<(u16, TransportProtocol), Duration>| {
+            matcher
+                .select_endpoint_by_latency(&relay, latencies)
+                .map(|endpoint| endpoint.to_endpoint().protocol)
+        };
+
+        // UDP wins when it is slightly slower than TCP, but within the tolerance
+        let mut latencies: HashMap<(u16, TransportProtocol), Duration> = [
+            ((1194, TransportProtocol::Udp), Duration::from_millis(60)),
+            ((443, TransportProtocol::Tcp), Duration::from_millis(40)),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(protocol(&latencies), Some(TransportProtocol::Udp));
+
+        // TCP is chosen when UDP is far slower
+        latencies.insert((1194, TransportProtocol::Udp), Duration::from_millis(400));
+        assert_eq!(protocol(&latencies), Some(TransportProtocol::Tcp));
+
+        // Endpoints without latency hints or not matching the constraints are ignored
+        latencies.remove(&(443, TransportProtocol::Tcp));
+        assert_eq!(protocol(&latencies), Some(TransportProtocol::Udp));
+        let tcp_matcher = OpenVpnMatcher {
+            port: Constraint::Only(TransportPort {
+                protocol: TransportProtocol::Tcp,
+                port: Constraint::Any,
+            }),
+        };
+        assert!(tcp_matcher
+            .select_endpoint_by_latency(&relay, &latencies)
+            .is_none());
+    }
+
+    #[test]
+    fn test_resolve_prefix() {
+        let relays = vec![
+            wireguard_relay("se-got-wg-001", "se", "got"),
+            wireguard_relay("se-got-wg-0010", "se", "got"),
+            wireguard_relay("se-sto-wg-001", "se", "sto"),
+            openvpn_relay("se-mma-001", "se", "mma"),
+        ];
+        let matcher = any_tunnel_matcher(Constraint::Only(TunnelType::Wireguard));
+
+        match matcher.resolve_prefix(&relays, "se-sto") {
+            ResolveResult::Unique(relay) => assert_eq!(relay.hostname, "se-sto-wg-001"),
+            result => panic!("Expected a unique relay, got {:?}", result),
+        }
+        match matcher.resolve_prefix(&relays, "se-got-wg-001") {
+            ResolveResult::Unique(relay) => assert_eq!(relay.hostname, "se-got-wg-001"),
+            result => panic!("Expected a unique relay, got {:?}", result),
+        }
+        match matcher.resolve_prefix(&relays, "se-got") {
+            ResolveResult::Ambiguous(hostnames) => assert_eq!(
+                hostnames,
+                vec!["se-got-wg-001".to_string(), "se-got-wg-0010".to_string()]
+            ),
+            result => panic!("Expected an ambiguous result, got {:?}", result),
+        }
+        // The only relay in Malmö is filtered out by the tunnel constraint
+        assert!(matches!(
+            matcher.resolve_prefix(&relays, "se-mma"),
+            ResolveResult::NotFound
+        ));
+        assert!(matches!(
+            matcher.resolve_prefix(&relays, "no-osl"),
+            ResolveResult::NotFound
+        ));
+    }
+
+    #[test]
+    fn test_wireguard_ipv6_endpoint_requires_gateway() {
+        let mut relay = wireguard_relay("se-got-wg-001", "se", "got");
+        let mut matcher = WireguardMatcher {
+            peer: None,
+            port: Constraint::Any,
+            ip_version: Constraint::Only(IpVersion::V6),
+            key_index: None,
+            prefer_local_peer: false,
+            require_different_country: false,
+            require_daita: false,
+            port_scores: None,
+            source_port: None,
+            min_feature_version: 0,
+            port_usage: None,
+        };
+        assert!(matcher.mullvad_endpoint(&relay).is_some());
+
+        relay.tunnels.wireguard[0].gateways[0].1 = Ipv6Addr::UNSPECIFIED;
+        assert!(matcher.mullvad_endpoint(&relay).is_none());
+
+        // IPv4 endpoints do not depend on the IPv6 gateway
+        matcher.ip_version = Constraint::Only(IpVersion::V4);
+        assert!(matcher.mullvad_endpoint(&relay).is_some());
+
+        // Other gateway pairs are used if the primary pair has no IPv6 gateway
+        let secondary: (Ipv4Addr, Ipv6Addr) = (
+            "10.65.0.1".parse().unwrap(),
+            "fc00:bbbb:bbbb:bb02::1".parse().unwrap(),
+        );
+        relay.tunnels.wireguard[0].gateways.push(secondary);
+        let gateways = |matcher: &WireguardMatcher| {
+            let endpoint = matcher.mullvad_endpoint(&relay).unwrap();
+            let endpoint = endpoint.unwrap_wireguard();
+            (endpoint.ipv4_gateway, endpoint.ipv6_gateway)
+        };
+        assert_eq!(
+            gateways(&matcher),
+            ("10.64.0.1".parse().unwrap(), Ipv6Addr::UNSPECIFIED)
+        );
+        matcher.ip_version = Constraint::Only(IpVersion::V6);
+        assert_eq!(gateways(&matcher), secondary);
+    }
+
+    #[test]
+    fn test_wireguard_can_serve() {
+        let relay = wireguard_relay("se-got-wg-001", "se", "got");
+        let matcher = WireguardMatcher::default();
+        assert!(matcher.can_serve(&relay));
+        assert!(matcher.mullvad_endpoint(&relay).is_some());
+
+        // No WireGuard endpoints
+        assert!(!matcher.can_serve(&openvpn_relay("se-got-001", "se", "got")));
+
+        // No address of the required IP version
+        let v6_matcher = WireguardMatcher {
+            ip_version: Constraint::Only(IpVersion::V6),
+            ..WireguardMatcher::default()
+        };
+        assert!(v6_matcher.can_serve(&relay));
+        let mut ipv4_only_relay = relay.clone();
+        ipv4_only_relay.ipv6_addr_in = None;
+        assert!(!v6_matcher.can_serve(&ipv4_only_relay));
+        assert!(matcher.can_serve(&ipv4_only_relay));
+
+        // No gateway for the required IP version
+        let mut no_ipv6_gateway_relay = relay.clone();
+        no_ipv6_gateway_relay.tunnels.wireguard[0].gateways[0].1 = Ipv6Addr::UNSPECIFIED;
+        assert!(!v6_matcher.can_serve(&no_ipv6_gateway_relay));
+
+        // The port constraint cannot be satisfied
+        let port_matcher = WireguardMatcher {
+            port: Constraint::Only(33500),
+            ..WireguardMatcher::default()
+        };
+        assert!(!port_matcher.can_serve(&relay));
+        let mut no_ports_relay = relay.clone();
+        no_ports_relay.tunnels.wireguard[0].port_ranges = vec![];
+        assert!(!matcher.can_serve(&no_ports_relay));
+
+        // The endpoint does not match the other constraints
+        let key_index_matcher = WireguardMatcher {
+            key_index: Some(1),
+            ..WireguardMatcher::default()
+        };
+        assert!(!key_index_matcher.can_serve(&relay));
+        let daita_matcher = WireguardMatcher {
+            require_daita: true,
+            ..WireguardMatcher::default()
+        };
+        assert!(!daita_matcher.can_serve(&relay));
+
+        for matcher in [matcher, v6_matcher, port_matcher] {
+            for relay in [
+                &relay,
+                &ipv4_only_relay,
+                &no_ipv6_gateway_relay,
+                &no_ports_relay,
+            ] {
+                assert_eq!(
+                    matcher.can_serve(relay),
+                    matcher.mullvad_endpoint(relay).is_some()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_congestion() {
+        let mut relay = wireguard_relay("se-got-wg-001", "se", "got");
+        let mut matcher = any_tunnel_matcher(Constraint::Any);
+        matcher.max_congestion = Some(50);
+
+        // Relays with an unknown congestion score are not filtered
+        assert!(matcher.filter_matching_relay(&relay).is_some());
+
+        relay.congestion = Some(Congestion(50));
+        assert!(matcher.filter_matching_relay(&relay).is_some());
+
+        relay.congestion = Some(Congestion(51));
+        assert!(matcher.filter_matching_relay(&relay).is_none());
+
+        matcher.max_congestion = None;
+        assert!(matcher.filter_matching_relay(&relay).is_some());
+    }
+
+    #[test]
+    fn test_wireguard_key_index() {
+        let mut relay = wireguard_relay("se-got-wg-001", "se", "got");
+        let mut rotated_endpoint = relay.tunnels.wireguard[0].clone();
+        rotated_endpoint.public_key = wireguard::PublicKey::from([1u8; 32]);
+        relay.tunnels.wireguard.push(rotated_endpoint.clone());
+
+        let mut matcher = RelayMatcher::from(RelayConstraints::default()).into_wireguard_matcher();
+        matcher.tunnel.key_index = Some(1);
+        let filtered_relay = matcher
+            .filter_matching_relay(&relay)
+            .expect("Relay has a key at index 1");
+        match matcher.mullvad_endpoint(&filtered_relay) {
+            Some(MullvadEndpoint::Wireguard(endpoint)) => {
+                assert_eq!(endpoint.peer.public_key, rotated_endpoint.public_key)
+            }
+            endpoint => panic!("Expected a WireGuard endpoint, got {:?}", endpoint),
+        }
+
+        matcher.tunnel.key_index = Some(2);
+        assert!(matcher.filter_matching_relay(&relay).is_none());
+    }
+
+    #[test]
+    fn test_constraints_from_relay() {
+        let mut dual_relay = wireguard_relay("se-got-002", "se", "got");
+        dual_relay.tunnels.openvpn = openvpn_relay("se-got-002", "se", "got").tunnels.openvpn;
+        let relays = [
+            wireguard_relay("se-got-wg-001", "se", "got"),
+            Relay {
+                owned: false,
+                provider: "M247".to_string(),
+                ..wireguard_relay("se-got-wg-002", "se", "got")
+            },
+            wireguard_relay("se-sto-wg-001", "se", "sto"),
+            openvpn_relay("se-got-001", "se", "got"),
+            dual_relay,
+        ];
+
+        for relay in &relays {
+            let constraints = RelayConstraints::from_relay(relay);
+            let matcher = RelayMatcher::from(constraints);
+            let matching: Vec<Relay> = relays
+                .iter()
+                .filter_map(|relay| matcher.filter_matching_relay(relay))
+                .collect();
+            assert_eq!(matching.len(), 1);
+            assert_eq!(matching[0].hostname, relay.hostname);
+            assert!(matcher.mullvad_endpoint(&matching[0]).is_some());
+        }
+
+        let constraints = RelayConstraints::from_relay(&relays[1]);
+        assert_eq!(constraints.ownership, Constraint::Only(Ownership::Rented));
+        assert_eq!(
+            constraints.tunnel_protocol,
+            Constraint::Only(TunnelType::Wireguard)
+        );
+        assert_eq!(
+            RelayConstraints::from_relay(&relays[4]).tunnel_protocol,
+            Constraint::Any
+        );
+    }
+
+    #[test]
+    fn test_provider_globs() {
+        let relays = [
+            Relay {
+                provider: "31173".to_string(),
+                ..wireguard_relay("se-got-wg-001", "se", "got")
+            },
+            Relay {
+                provider: "M247".to_string(),
+                ..wireguard_relay("se-got-wg-002", "se", "got")
+            },
+            Relay {
+                provider: "M248".to_string(),
+                ..wireguard_relay("se-got-wg-003", "se", "got")
+            },
+            Relay {
+                provider: "31".to_string(),
+                ..wireguard_relay("se-got-wg-004", "se", "got")
+            },
+        ];
+        let matching_hostnames = |providers: &[&str]| -> Vec<String> {
+            let matcher = RelayMatcherBuilder::new()
+                .providers(
+                    Providers::new(providers.iter().map(|provider| provider.to_string())).unwrap(),
+                )
+                .wireguard(WireguardMatcher::default());
+            relays
+                .iter()
+                .filter_map(|relay| matcher.filter_matching_relay(relay))
+                .map(|relay| relay.hostname)
+                .collect()
+        };
+
+        assert_eq!(
+            matching_hostnames(&["31*"]),
+            vec!["se-got-wg-001", "se-got-wg-004"]
+        );
+        assert_eq!(
+            matching_hostnames(&["M24?"]),
+            vec!["se-got-wg-002", "se-got-wg-003"]
+        );
+        assert_eq!(matching_hostnames(&["M247"]), vec!["se-got-wg-002"]);
+        assert_eq!(
+            matching_hostnames(&["31", "M248"]),
+            vec!["se-got-wg-003", "se-got-wg-004"]
+        );
+    }
+
+    #[test]
+    fn test_reachable_transport() {
+        let wireguard = wireguard_relay("se-got-wg-001", "se", "got");
+        let wireguard_udp2tcp = Relay {
+            obfuscators: RelayObfuscators {
+                udp2tcp: vec![Udp2TcpEndpointData { port: 443 }],
+            },
+            ..wireguard_relay("se-got-wg-002", "se", "got")
+        };
+        let openvpn = openvpn_relay("se-got-001", "se", "got");
+        let relays = [wireguard, wireguard_udp2tcp, openvpn];
+
+        let mut matcher = any_tunnel_matcher(Constraint::Any);
+        let matching_relays = |matcher: &RelayMatcher<AnyTunnelMatcher>| -> Vec<Relay> {
+            relays
+                .iter()
+                .filter_map(|relay| matcher.filter_matching_relay(relay))
+                .collect()
+        };
+        assert_eq!(matching_relays(&matcher).len(), 3);
+
+        matcher.reachable_transport = Constraint::Only(TransportProtocol::Tcp);
+        let tcp_relays = matching_relays(&matcher);
+        let hostnames: Vec<&str> = tcp_relays
+            .iter()
+            .map(|relay| relay.hostname.as_str())
+            .collect();
+        assert_eq!(hostnames, vec!["se-got-wg-002", "se-got-001"]);
+        assert!(tcp_relays[1]
+            .tunnels
+            .openvpn
+            .iter()
+            .all(|endpoint| endpoint.protocol == TransportProtocol::Tcp));
+        for _ in 0..10 {
+            let endpoint = matcher.mullvad_endpoint(&tcp_relays[1]).unwrap();
+            assert_eq!(endpoint.to_endpoint().protocol, TransportProtocol::Tcp);
+        }
+
+        // WireGuard relays need a udp2tcp obfuscator even when OpenVPN is not allowed
+        let mut matcher = any_tunnel_matcher(Constraint::Only(TunnelType::Wireguard));
+        matcher.reachable_transport = Constraint::Only(TransportProtocol::Tcp);
+        let hostnames: Vec<String> = matching_relays(&matcher)
+            .into_iter()
+            .map(|relay| relay.hostname)
+            .collect();
+        assert_eq!(hostnames, vec!["se-got-wg-002"]);
+
+        let mut matcher = any_tunnel_matcher(Constraint::Only(TunnelType::OpenVpn));
+        matcher.reachable_transport = Constraint::Only(TransportProtocol::Udp);
+        let udp_relays = matching_relays(&matcher);
+        assert_eq!(udp_relays.len(), 1);
+        assert!(udp_relays[0]
+            .tunnels
+            .openvpn
+            .iter()
+            .all(|endpoint| endpoint.protocol == TransportProtocol::Udp));
+        assert_eq!(
+            matcher.unsatisfiable_reason(&relays[..2]),
+            Some(vec![MatchRejection::Tunnel])
+        );
+
+        let mut matcher = any_tunnel_matcher(Constraint::Only(TunnelType::Wireguard));
+        matcher.reachable_transport = Constraint::Only(TransportProtocol::Tcp);
+        assert_eq!(
+            matcher.unsatisfiable_reason(&relays[..1]),
+            Some(vec![MatchRejection::ReachableTransport])
+        );
+    }
+
+    #[test]
+    fn test_required_tags() {
+        let mut relay = wireguard_relay("se-got-wg-001", "se", "got");
+        relay.tags = vec!["experimental".to_string(), "highspeed".to_string()];
+
+        let mut matcher = any_tunnel_matcher(Constraint::Any);
+        assert!(matcher.filter_matching_relay(&relay).is_some());
+
+        matcher.required_tags = vec!["highspeed".to_string(), "experimental".to_string()];
+        assert!(matcher.filter_matching_relay(&relay).is_some());
+
+        matcher.required_tags.push("p2p".to_string());
+        assert!(matcher.filter_matching_relay(&relay).is_none());
+    }
+
+    #[test]
+    fn test_explain_selection() {
+        let relays = vec![
+            wireguard_relay("se-got-wg-001", "se", "got"),
+            openvpn_relay("se-got-001", "se", "got"),
+        ];
+        let mut matcher = any_tunnel_matcher(Constraint::Only(TunnelType::Wireguard));
+        matcher.tunnel.wireguard.port = Constraint::Only(53);
+
+        let explanation = matcher.explain_selection(&relays);
+        assert!(explanation.contains("1 with matching tunnel endpoints"));
+        assert!(explanation.contains("Picked se-got-wg-001"));
+        assert!(explanation.contains("Using port 53 over UDP"));
+
+        // The reported weight is the one used during selection
+        matcher.weight_floor = 5;
+        let explanation = matcher.explain_selection(&relays);
+        assert!(explanation.contains("with weight 5 out of a total weight of 5"));
+
+        // The stages are the checks of `filter_matching_relay`
+        let unstable_relay = Relay {
+            channel: Some("beta".to_string()),
+            ..relays[0].clone()
+        };
+        let explanation = matcher.explain_selection(&[unstable_relay]);
+        assert!(explanation.contains("0 in the stable channel"));
+        assert!(explanation.ends_with("No relay matches the constraints"));
+
+        matcher.location = Constraint::Only(LocationConstraint::Country("no".to_string()));
+        let explanation = matcher.explain_selection(&relays);
+        assert!(explanation.contains("0 matching the location"));
+        assert!(explanation.ends_with("No relay matches the constraints"));
+    }
+
+    #[test]
+    fn test_min_bandwidth() {
+        let mut relay = wireguard_relay("se-got-wg-001", "se", "got");
+        let mut matcher = any_tunnel_matcher(Constraint::Any);
+        assert!(matcher.filter_matching_relay(&relay).is_some());
+
+        matcher.min_bandwidth = Some(10_000);
+        // Relays with an unknown bandwidth are excluded
+        assert!(matcher.filter_matching_relay(&relay).is_none());
+
+        relay.bandwidth_mbps = Some(10_000);
+        assert!(matcher.filter_matching_relay(&relay).is_some());
+
+        relay.bandwidth_mbps = Some(1_000);
+        assert!(matcher.filter_matching_relay(&relay).is_none());
+    }
+
+    #[test]
+    fn test_builder_wireguard_only() {
+        let matcher = RelayMatcherBuilder::new()
+            .location(LocationConstraint::Country("se".to_string()))
+            .ownership(Ownership::MullvadOwned)
+            .wireguard(WireguardMatcher {
+                port: Constraint::Only(51820),
+                ..WireguardMatcher::default()
+            });
+
+        let relay = wireguard_relay("se-got-wg-001", "se", "got");
+        assert!(matcher.filter_matching_relay(&relay).is_some());
+        let endpoint = matcher.mullvad_endpoint(&relay).unwrap();
+        assert_eq!(endpoint.to_endpoint().address.port(), 51820);
+
+        assert!(matcher
+            .filter_matching_relay(&wireguard_relay("de-ber-wg-001", "de", "ber"))
+            .is_none());
+        assert!(matcher
+            .filter_matching_relay(&openvpn_relay("se-got-001", "se", "got"))
+            .is_none());
+
+        let matcher = RelayMatcherBuilder::new().any_tunnel(AnyTunnelMatcher::default());
+        assert!(matcher
+            .filter_matching_relay(&openvpn_relay("se-got-001", "se", "got"))
+            .is_some());
+        assert!(matcher.filter_matching_relay(&relay).is_some());
+    }
+
+    #[test]
+    fn test_latency_percentile() {
+        let mut relays: Vec<Relay> = (1..=4)
+            .map(|index| Relay {
+                rtt: Some(Duration::from_millis(10 * index)),
+                ..wireguard_relay(&format!("se-got-wg-00{}", index), "se", "got")
+            })
+            .collect();
+        relays.push(wireguard_relay("se-got-wg-005", "se", "got"));
+        let matcher = RelayMatcher::from(RelayConstraints::default()).into_wireguard_matcher();
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..20 {
+            let relay = matcher
+                .pick_within_latency_percentile(&relays, 50.0, &mut rng)
+                .unwrap();
+            assert!(["se-got-wg-001", "se-got-wg-002"].contains(&relay.hostname.as_str()));
+        }
+
+        let relay = matcher
+            .pick_within_latency_percentile(&relays, 0.0, &mut rng)
+            .unwrap();
+        assert_eq!(relay.hostname, "se-got-wg-001");
+
+        for _ in 0..20 {
+            let relay = matcher
+                .pick_within_latency_percentile(&relays, 100.0, &mut rng)
+                .unwrap();
+            assert_ne!(relay.hostname, "se-got-wg-005");
+        }
+
+        assert!(matcher
+            .pick_within_latency_percentile(&relays[4..], 100.0, &mut rng)
+            .is_none());
+
+        // The weight floor keeps a relay with a very low weight in rotation
+        let relays = [
+            Relay {
+                weight: 1,
+                rtt: Some(Duration::from_millis(10)),
+                ..wireguard_relay("se-got-wg-001", "se", "got")
+            },
+            Relay {
+                weight: 1000,
+                rtt: Some(Duration::from_millis(10)),
+                ..wireguard_relay("se-got-wg-002", "se", "got")
+            },
+        ];
+        let matcher = RelayMatcherBuilder::new()
+            .weight_floor(1000)
+            .wireguard(WireguardMatcher::default());
+        let low_weight_picks = (0..200)
+            .filter(|_| {
+                matcher
+                    .pick_within_latency_percentile(&relays, 100.0, &mut rng)
+                    .unwrap()
+                    .hostname
+                    == "se-got-wg-001"
+            })
+            .count();
+        assert!(low_weight_picks > 50);
+    }
+
+    #[test]
+    fn test_select_n_distinct_cities() {
+        let relays = vec![
+            wireguard_relay("se-got-wg-001", "se", "got"),
+            wireguard_relay("se-got-wg-002", "se", "got"),
+            wireguard_relay("se-sto-wg-001", "se", "sto"),
+            wireguard_relay("se-sto-wg-002", "se", "sto"),
+            wireguard_relay("de-ber-wg-001", "de", "ber"),
+            openvpn_relay("no-osl-001", "no", "osl"),
+        ];
+        let matcher = RelayMatcher::from(RelayConstraints::default()).into_wireguard_matcher();
+        let mut rng = rand::thread_rng();
+
+        let city = |relay: &Relay| {
+            let location = relay.location.as_ref().unwrap();
+            (location.country_code.clone(), location.city_code.clone())
+        };
+
+        for _ in 0..10 {
+            let selected = matcher.select_n_distinct_cities(&relays, 2, &mut rng);
+            assert_eq!(selected.len(), 2);
+            assert_ne!(city(&selected[0]), city(&selected[1]));
+        }
+
+        // Only three cities have WireGuard relays
+        let selected = matcher.select_n_distinct_cities(&relays, 5, &mut rng);
+        let mut cities: Vec<_> = selected.iter().map(city).collect();
+        cities.sort();
+        assert_eq!(
+            cities,
+            vec![
+                ("de".to_string(), "ber".to_string()),
+                ("se".to_string(), "got".to_string()),
+                ("se".to_string(), "sto".to_string()),
+            ]
+        );
+
+        assert!(matcher
+            .select_n_distinct_cities(&relays, 0, &mut rng)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_select_n() {
+        // Four countries with two cities that have two WireGuard relays each
+        let relays: Vec<Relay> = RelayList::mock(4, 2, 4).relays().cloned().collect();
+        let matcher = RelayMatcher::from(RelayConstraints::default()).into_wireguard_matcher();
+        let mut rng = rand::thread_rng();
+
+        let country_counts = |selected: &[Relay]| {
+            let mut counts: HashMap<String, usize> = HashMap::new();
+            for relay in selected {
+                *counts
+                    .entry(relay.location.as_ref().unwrap().country_code.clone())
+                    .or_default() += 1;
+            }
+            counts
+        };
+
+        for _ in 0..10 {
+            let selected = matcher.select_n(&relays, 6, Some(2), &mut rng);
+            assert_eq!(selected.len(), 6);
+            let counts = country_counts(&selected);
+            assert!(counts.values().all(|count| *count <= 2));
+            assert!(counts.len() >= 3);
+            let hostnames: HashSet<&str> = selected
+                .iter()
+                .map(|relay| relay.hostname.as_str())
+                .collect();
+            assert_eq!(hostnames.len(), selected.len());
+        }
+
+        // The batch is filled from the other countries
+        let selected = matcher.select_n(&relays, 10, Some(1), &mut rng);
+        assert_eq!(selected.len(), 4);
+        assert_eq!(country_counts(&selected).len(), 4);
+
+        // Without a cap, every matching relay may be picked
+        let selected = matcher.select_n(&relays, 100, None, &mut rng);
+        assert_eq!(selected.len(), 16);
+        assert!(selected
+            .iter()
+            .all(|relay| !relay.tunnels.wireguard.is_empty()));
+        assert!(matcher.select_n(&relays, 0, Some(1), &mut rng).is_empty());
+        assert!(matcher.select_n(&relays, 4, Some(0), &mut rng).is_empty());
+    }
+
+    #[test]
+    fn test_platform_tunnel_restriction() {
+        let mut relay = wireguard_relay("se-got-001", "se", "got");
+        relay.tunnels.openvpn = openvpn_relay("se-got-001", "se", "got").tunnels.openvpn;
+        let mut matcher = RelayMatcher::from(RelayConstraints::default()).tunnel;
+        let is_wireguard = |endpoint: Option<MullvadEndpoint>| {
+            matches!(endpoint, Some(MullvadEndpoint::Wireguard(_)))
+        };
+
+        // Only WireGuard is allowed on Android
+        let android = &[TunnelType::Wireguard];
+        for tunnel_type in [
+            Constraint::Any,
+            Constraint::Only(TunnelType::OpenVpn),
+            Constraint::Only(TunnelType::Wireguard),
+        ] {
+            matcher.tunnel_type = tunnel_type;
+            for _ in 0..10 {
+                assert!(is_wireguard(
+                    matcher.restricted_mullvad_endpoint(&relay, android)
+                ));
+            }
+        }
+
+        let all = &[TunnelType::OpenVpn, TunnelType::Wireguard];
+        matcher.tunnel_type = Constraint::Only(TunnelType::OpenVpn);
+        assert!(matches!(
+            matcher.restricted_mullvad_endpoint(&relay, all),
+            Some(MullvadEndpoint::OpenVpn(_))
+        ));
+        matcher.tunnel_type = Constraint::Only(TunnelType::Wireguard);
+        assert!(is_wireguard(
+            matcher.restricted_mullvad_endpoint(&relay, all)
+        ));
+
+        #[cfg(not(target_os = "android"))]
+        assert_eq!(platform_tunnel_restriction(), all);
+    }
+
+    #[test]
+    fn test_select_sticky() {
+        let mut relays: Vec<Relay> = (1..=4)
+            .map(|index| wireguard_relay(&format!("se-got-wg-00{}", index), "se", "got"))
+            .collect();
+        let matcher = RelayMatcher::from(RelayConstraints::default()).into_wireguard_matcher();
+
+        let relay = matcher.select_sticky(&relays, 1234).unwrap();
+        for _ in 0..10 {
+            assert_eq!(matcher.select_sticky(&relays, 1234).unwrap(), relay);
+        }
+        // The order of the relays does not matter
+        relays.reverse();
+        assert_eq!(matcher.select_sticky(&relays, 1234).unwrap(), relay);
+
+        let picks: HashSet<String> = (0..50)
+            .map(|token| matcher.select_sticky(&relays, token).unwrap().hostname)
+            .collect();
+        assert!(picks.len() > 1);
+
+        assert!(matcher.select_sticky(&[], 1234).is_none());
+
+        // Picks are weighted by the selection weight, which quadruples the weight of the
+        // dual-stack relay
+        let relays = [
+            Relay {
+                ipv6_addr_in: None,
+                ..wireguard_relay("se-got-wg-001", "se", "got")
+            },
+            wireguard_relay("se-got-wg-002", "se", "got"),
+        ];
+        let matcher = RelayMatcherBuilder::new()
+            .prefer_dual_stack(true)
+            .wireguard(WireguardMatcher::default());
+        let dual_stack_picks = (0..1000)
+            .filter(|token| matcher.select_sticky(&relays, *token).unwrap() == relays[1])
+            .count();
+        assert!(dual_stack_picks > 700);
+    }
+
+    #[test]
+    fn test_pick_geo_weighted() {
+        let gothenburg = Coordinates {
+            latitude: 57.70887,
+            longitude: 11.97456,
+        };
+        let near_relay = Relay {
+            weight: 100,
+            ..wireguard_relay("se-got-wg-001", "se", "got")
+        };
+        let mut far_relay = Relay {
+            weight: 1000,
+            ..wireguard_relay("us-nyc-wg-001", "us", "nyc")
+        };
+        if let Some(location) = far_relay.location.as_mut() {
+            location.latitude = 40.73061;
+            location.longitude = -73.935242;
+        }
+        let mut unknown_location_relay = wireguard_relay("se-sto-wg-001", "se", "sto");
+        unknown_location_relay.location = None;
+
+        let relays = vec![near_relay, far_relay, unknown_location_relay];
+        let matcher = RelayMatcher::from(RelayConstraints::default()).into_wireguard_matcher();
+        let mut rng = rand::thread_rng();
+
+        let mut near_picks = 0;
+        let mut far_picks = 0;
+        for _ in 0..1000 {
+            let relay = matcher
+                .pick_geo_weighted(&relays, &gothenburg, &mut rng)
+                .unwrap();
+            match relay.hostname.as_str() {
+                "se-got-wg-001" => near_picks += 1,
+                "us-nyc-wg-001" => far_picks += 1,
+                hostname => panic!("Picked relay without a location: {}", hostname),
+            }
+        }
+        // The effective weights are 100 and about 1000 / 6000
+        assert!(near_picks > 950);
+        assert_eq!(near_picks + far_picks, 1000);
+
+        assert!(matcher
+            .pick_geo_weighted(&relays[2..], &gothenburg, &mut rng)
+            .is_none());
+    }
+
+    #[test]
+    fn test_pick_blended() {
+        let relays = vec![
+            Relay {
+                weight: 1,
+                rtt: Some(Duration::from_millis(1)),
+                ..wireguard_relay("se-got-wg-001", "se", "got")
+            },
+            Relay {
+                weight: 5000,
+                rtt: Some(Duration::from_millis(5000)),
+                ..wireguard_relay("se-got-wg-002", "se", "got")
+            },
+            Relay {
+                weight: 0,
+                rtt: Some(Duration::from_millis(1)),
+                ..wireguard_relay("se-got-wg-003", "se", "got")
+            },
+        ];
+        let matcher = RelayMatcher::from(RelayConstraints::default()).into_wireguard_matcher();
+        let mut rng = rand::thread_rng();
+        let mut count_picks = |relays: &[Relay], alpha: f32, hostname: &str| {
+            (0..100)
+                .filter(|_| {
+                    matcher
+                        .pick_blended(relays, alpha, &mut rng)
+                        .unwrap()
+                        .hostname
+                        == hostname
+                })
+                .count()
+        };
+
+        // Only the weight is considered, so the heaviest relay is picked 5000 times as often
+        assert!(count_picks(&relays, 0.0, "se-got-wg-002") > 90);
+        // Only the latency is considered, so the fastest relay is picked 5000 times as often
+        assert!(count_picks(&relays, 1.0, "se-got-wg-001") > 90);
+        // Relays with weight 0 are never picked, regardless of their latency
+        for alpha in [0.0, 0.5, 1.0] {
+            assert_eq!(count_picks(&relays, alpha, "se-got-wg-003"), 0);
+        }
+
+        // Relays without a round-trip time are assumed to have the median round-trip time of
+        // 20 ms, which makes them half as likely to be picked as the 10 ms relay
+        let relays = vec![
+            Relay {
+                weight: 100,
+                rtt: Some(Duration::from_millis(10)),
+                ..wireguard_relay("se-got-wg-001", "se", "got")
+            },
+            Relay {
+                weight: 100,
+                rtt: Some(Duration::from_millis(30)),
+                ..wireguard_relay("se-got-wg-002", "se", "got")
+            },
+            Relay {
+                weight: 100,
+                rtt: None,
+                ..wireguard_relay("se-got-wg-003", "se", "got")
+            },
+        ];
+        let (fastest, unmeasured) = (0..300)
+            .map(|_| {
+                matcher
+                    .pick_blended(&relays, 1.0, &mut rng)
+                    .unwrap()
+                    .hostname
+            })
+            .fold((0, 0), |(fastest, unmeasured), hostname| {
+                match hostname.as_str() {
+                    "se-got-wg-001" => (fastest + 1, unmeasured),
+                    "se-got-wg-003" => (fastest, unmeasured + 1),
+                    _ => (fastest, unmeasured),
+                }
+            });
+        assert!(unmeasured > 20 && unmeasured < 160);
+        assert!(fastest > unmeasured);
+    }
+
+    #[test]
+    fn test_unsatisfiable_reason() {
+        let relays = vec![
+            wireguard_relay("se-got-wg-001", "se", "got"),
+            Relay {
+                owned: false,
+                ..wireguard_relay("se-sto-wg-001", "se", "sto")
+            },
+            openvpn_relay("se-got-001", "se", "got"),
+            Relay {
+                active: false,
+                ..wireguard_relay("no-osl-wg-001", "no", "osl")
+            },
+        ];
+        let mut matcher = RelayMatcher::from(RelayConstraints::default()).into_wireguard_matcher();
+        assert_eq!(matcher.unsatisfiable_reason(&relays), None);
+        assert_eq!(
+            matcher.unsatisfiable_reason(&[]),
+            Some(vec![MatchRejection::NoRelays])
+        );
+
+        // A single constraint rejects all relays
+        matcher.location = Constraint::Only(LocationConstraint::Country("de".to_string()));
+        assert_eq!(
+            matcher.unsatisfiable_reason(&relays),
+            Some(vec![MatchRejection::Location])
+        );
+
+        // No owned WireGuard relay in Stockholm
+        matcher.location = Constraint::Only(LocationConstraint::City(
+            "se".to_string(),
+            "sto".to_string(),
+        ));
+        matcher.ownership = Constraint::Only(Ownership::MullvadOwned);
+        assert_eq!(
+            matcher.unsatisfiable_reason(&relays),
+            Some(vec![MatchRejection::Location, MatchRejection::Ownership])
+        );
+
+        // No owned WireGuard relay in Gothenburg with the required tag
+        matcher.location = Constraint::Only(LocationConstraint::City(
+            "se".to_string(),
+            "got".to_string(),
+        ));
+        matcher.required_tags = vec!["fast".to_string()];
+        assert_eq!(
+            matcher.unsatisfiable_reason(&relays),
+            Some(vec![MatchRejection::Tags])
+        );
+    }
+
+    #[test]
+    fn test_excluded_countries() {
+        let relays = [
+            wireguard_relay("se-got-wg-001", "se", "got"),
+            wireguard_relay("us-nyc-wg-001", "us", "nyc"),
+        ];
+        let mut matcher = RelayMatcherBuilder::new()
+            .excluded_countries(["us".to_string()].into_iter().collect())
+            .wireguard(WireguardMatcher::default());
+        assert!(matcher.filter_matching_relay(&relays[0]).is_some());
+        assert!(matcher.filter_matching_relay(&relays[1]).is_none());
+
+        // Exclusion takes precedence over the location constraint
+        matcher.location = Constraint::Only(LocationConstraint::Country("us".to_string()));
+        assert!(matcher.filter_matching_relay(&relays[1]).is_none());
+        matcher.location = Constraint::Only(LocationConstraint::Hostname(
+            "us".to_string(),
+            "nyc".to_string(),
+            "us-nyc-wg-001".to_string(),
+        ));
+        assert!(matcher.filter_matching_relay(&relays[1]).is_none());
+
+        // Relays with an unknown location may be in an excluded country
+        matcher.location = Constraint::Any;
+        let mut relay = relays[0].clone();
+        relay.location = None;
+        assert!(matcher.filter_matching_relay(&relay).is_none());
+        matcher.excluded_countries.clear();
+        assert!(matcher.filter_matching_relay(&relay).is_some());
+    }
+
+    #[test]
+    fn test_min_feature_version() {
+        let mut relays: Vec<Relay> = (0..3)
+            .map(|index| wireguard_relay(&format!("se-got-wg-00{}", index), "se", "got"))
+            .collect();
+        for (index, relay) in relays.iter_mut().enumerate() {
+            relay.tunnels.wireguard[0].feature_version = index as u32;
+        }
+        let mut matcher = RelayMatcher::from(RelayConstraints::default()).into_wireguard_matcher();
+
+        let matching_hostnames = |matcher: &RelayMatcher<WireguardMatcher>| -> Vec<String> {
+            relays
+                .iter()
+                .filter_map(|relay| matcher.filter_matching_relay(relay))
+                .map(|relay| relay.hostname)
+                .collect()
+        };
+        assert_eq!(matching_hostnames(&matcher).len(), 3);
+
+        matcher.tunnel.min_feature_version = 1;
+        assert_eq!(
+            matching_hostnames(&matcher),
+            vec!["se-got-wg-001", "se-got-wg-002"]
+        );
+
+        matcher.tunnel.min_feature_version = 2;
+        assert_eq!(matching_hostnames(&matcher), vec!["se-got-wg-002"]);
+
+        matcher.tunnel.min_feature_version = 3;
+        assert!(matching_hostnames(&matcher).is_empty());
+    }
+
+    #[test]
+    fn test_source_port() {
+        let relay = wireguard_relay("se-got-wg-001", "se", "got");
+        let data = relay.tunnels.wireguard[0].clone();
+
+        let mut matcher = WireguardMatcher::default();
+        let endpoint = matcher.wg_data_to_endpoint(&relay, data.clone()).unwrap();
+        assert_eq!(endpoint.unwrap_wireguard().source_port, None);
+
+        matcher.source_port = Some(51821);
+        let endpoint = matcher.wg_data_to_endpoint(&relay, data).unwrap();
+        assert_eq!(endpoint.unwrap_wireguard().source_port, Some(51821));
+        let endpoint = matcher.mullvad_endpoint(&relay).unwrap();
+        assert_eq!(endpoint.unwrap_wireguard().source_port, Some(51821));
+    }
+
+    #[test]
+    fn test_allowed_cities() {
+        let relays = [
+            wireguard_relay("nl-ams-wg-001", "nl", "ams"),
+            wireguard_relay("nl-rtm-wg-001", "nl", "rtm"),
+            wireguard_relay("de-fra-wg-001", "de", "fra"),
+            wireguard_relay("de-ber-wg-001", "de", "ber"),
+            wireguard_relay("gb-lon-wg-001", "gb", "lon"),
+            Relay {
+                location: None,
+                ..wireguard_relay("gb-mnc-wg-001", "gb", "mnc")
+            },
+        ];
+        let matching_hostnames = |matcher: &RelayMatcher<WireguardMatcher>| -> Vec<String> {
+            relays
+                .iter()
+                .filter_map(|relay| matcher.filter_matching_relay(relay))
+                .map(|relay| relay.hostname)
+                .collect()
+        };
+        let cities = |cities: &[(&str, &str)]| -> HashSet<(CountryCode, CityCode)> {
+            cities
+                .iter()
+                .map(|(country, city)| (country.to_string(), city.to_string()))
+                .collect()
+        };
+
+        let mut matcher = RelayMatcherBuilder::new()
+            .allowed_cities(cities(&[("nl", "ams"), ("de", "fra"), ("gb", "lon")]))
+            .wireguard(WireguardMatcher::default());
+        assert_eq!(
+            matching_hostnames(&matcher),
+            vec!["nl-ams-wg-001", "de-fra-wg-001", "gb-lon-wg-001"]
+        );
+
+        // The city code alone is not enough
+        matcher.allowed_cities = cities(&[("de", "ams"), ("nl", "fra")]);
+        assert!(matching_hostnames(&matcher).is_empty());
+
+        // The location constraint still applies
+        matcher.allowed_cities = cities(&[("nl", "ams"), ("de", "fra"), ("de", "ber")]);
+        matcher.location = Constraint::Only(LocationConstraint::Country("de".to_string()));
+        assert_eq!(
+            matching_hostnames(&matcher),
+            vec!["de-fra-wg-001", "de-ber-wg-001"]
+        );
+
+        matcher.allowed_cities.clear();
+        matcher.location = Constraint::Any;
+        assert_eq!(matching_hostnames(&matcher).len(), relays.len());
+    }
+
+    #[test]
+    fn test_asn() {
+        let relays = [
+            Relay {
+                asn: Some(39351),
+                ..wireguard_relay("se-got-wg-001", "se", "got")
+            },
+            Relay {
+                asn: Some(42708),
+                ..wireguard_relay("se-sto-wg-001", "se", "sto")
+            },
+            wireguard_relay("se-mma-wg-001", "se", "mma"),
+        ];
+        let matching_hostnames = |matcher: &RelayMatcher<WireguardMatcher>| -> Vec<String> {
+            relays
+                .iter()
+                .filter_map(|relay| matcher.filter_matching_relay(relay))
+                .map(|relay| relay.hostname)
+                .collect()
+        };
+
+        let mut matcher = RelayMatcherBuilder::new().wireguard(WireguardMatcher::default());
+        assert_eq!(matching_hostnames(&matcher).len(), relays.len());
+
+        // Relays with an unknown ASN are excluded when the ASN is constrained
+        matcher.asn = Constraint::Only(39351);
+        assert_eq!(matching_hostnames(&matcher), vec!["se-got-wg-001"]);
+        matcher.asn = Constraint::Only(1);
+        assert!(matching_hostnames(&matcher).is_empty());
+
+        matcher.asn = Constraint::Any;
+        matcher.excluded_asns = HashSet::from([39351]);
+        assert_eq!(matching_hostnames(&matcher), vec!["se-sto-wg-001"]);
+        assert_eq!(
+            matcher.unsatisfiable_reason(&relays[..1]),
+            Some(vec![MatchRejection::Asn])
+        );
+
+        let matcher = RelayMatcherBuilder::new()
+            .asn(42708)
+            .excluded_asns(HashSet::from([42708]))
+            .wireguard(WireguardMatcher::default());
+        assert!(matching_hostnames(&matcher).is_empty());
+    }
+
+    #[test]
+    fn test_stable_channel() {
+        let relays = [
+            wireguard_relay("se-got-wg-001", "se", "got"),
+            Relay {
+                channel: Some(STABLE_CHANNEL.to_string()),
+                ..wireguard_relay("se-got-wg-002", "se", "got")
+            },
+            Relay {
+                channel: Some("unstable".to_string()),
+                ..wireguard_relay("se-got-wg-003", "se", "got")
+            },
+        ];
+        let matching_hostnames = |matcher: &RelayMatcher<WireguardMatcher>| -> Vec<String> {
+            relays
+                .iter()
+                .filter_map(|relay| matcher.filter_matching_relay(relay))
+                .map(|relay| relay.hostname)
+                .collect()
+        };
+
+        // Unstable relays are excluded by default
+        let matcher = any_tunnel_matcher(Constraint::Any).into_wireguard_matcher();
+        assert!(matcher.require_stable);
+        assert_eq!(
+            matching_hostnames(&matcher),
+            vec!["se-got-wg-001", "se-got-wg-002"]
+        );
+        assert_eq!(
+            matcher.unsatisfiable_reason(&relays[2..]),
+            Some(vec![MatchRejection::UnstableChannel])
+        );
+        let matcher = RelayMatcherBuilder::new().wireguard(WireguardMatcher::default());
+        assert_eq!(matching_hostnames(&matcher).len(), 2);
+
+        let matcher = RelayMatcherBuilder::new()
+            .require_stable(false)
+            .wireguard(WireguardMatcher::default());
+        assert_eq!(matching_hostnames(&matcher).len(), relays.len());
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_hostname_exclude() {
+        let relays = [
+            openvpn_relay("se-got-ovpn-001", "se", "got"),
+            wireguard_relay("se-got-wg-001", "se", "got"),
+            openvpn_relay("de-ber-ovpn-101", "de", "ber"),
+        ];
+        let matching_hostnames = |matcher: &RelayMatcher<AnyTunnelMatcher>| -> Vec<String> {
+            relays
+                .iter()
+                .filter_map(|relay| matcher.filter_matching_relay(relay))
+                .map(|relay| relay.hostname)
+                .collect()
+        };
+
+        let matcher = RelayMatcherBuilder::new()
+            .hostname_exclude(hostname_exclude_regex("-ovpn-").unwrap())
+            .any_tunnel(AnyTunnelMatcher::default());
+        assert_eq!(matching_hostnames(&matcher), vec!["se-got-wg-001"]);
+        assert_eq!(
+            matcher.unsatisfiable_reason(&[relays[0].clone(), relays[2].clone()]),
+            Some(vec![MatchRejection::ExcludedHostname])
+        );
+
+        // A pattern that matches no hostname excludes nothing
+        let matcher = RelayMatcherBuilder::new()
+            .hostname_exclude(hostname_exclude_regex("^us-").unwrap())
+            .any_tunnel(AnyTunnelMatcher::default());
+        assert_eq!(matching_hostnames(&matcher).len(), relays.len());
+
+        assert!(hostname_exclude_regex("(").is_err());
+        assert!(hostname_exclude_regex("((a{1000}){1000}){1000}").is_err());
+    }
+
+    #[test]
+    fn test_wireguard_ip_version_matrix() {
+        let relay = wireguard_relay("se-got-wg-001", "se", "got");
+        let ipv4 = IpAddr::from(relay.ipv4_addr_in);
+        let ipv6 = IpAddr::from(relay.ipv6_addr_in.unwrap());
+        let mut ipv4_only_relay = relay.clone();
+        ipv4_only_relay.ipv6_addr_in = None;
+
+        let endpoint_address = |ip_version, relay: &Relay| {
+            let matcher = WireguardMatcher {
+                ip_version,
+                ..WireguardMatcher::default()
+            };
+            assert_eq!(
+                matcher.get_address_for_wireguard_relay(relay),
+                matcher
+                    .mullvad_endpoint(relay)
+                    .map(|endpoint| endpoint.to_endpoint().address.ip())
+            );
+            matcher.get_address_for_wireguard_relay(relay)
+        };
+
+        for _ in 0..10 {
+            assert_eq!(endpoint_address(Constraint::Any, &relay), Some(ipv4));
+            assert_eq!(
+                endpoint_address(Constraint::Only(IpVersion::V4), &relay),
+                Some(ipv4)
+            );
+            assert_eq!(
+                endpoint_address(Constraint::Only(IpVersion::V6), &relay),
+                Some(ipv6)
+            );
+        }
+
+        assert_eq!(
+            endpoint_address(Constraint::Any, &ipv4_only_relay),
+            Some(ipv4)
+        );
+        assert_eq!(
+            endpoint_address(Constraint::Only(IpVersion::V4), &ipv4_only_relay),
+            Some(ipv4)
+        );
+        assert_eq!(
+            endpoint_address(Constraint::Only(IpVersion::V6), &ipv4_only_relay),
+            None
+        );
+    }
+
+    #[test]
+    fn test_wireguard_fallback_addresses() {
+        let relay = wireguard_relay("se-got-wg-001", "se", "got");
+        let ipv4 = IpAddr::from(relay.ipv4_addr_in);
+        let ipv6 = IpAddr::from(relay.ipv6_addr_in.unwrap());
+        let mut ipv4_only_relay = relay.clone();
+        ipv4_only_relay.ipv6_addr_in = None;
+
+        let addresses = |ip_version, relay: &Relay| {
+            let matcher = WireguardMatcher {
+                ip_version,
+                ..WireguardMatcher::default()
+            };
+            let addresses = matcher.get_addresses_for_wireguard_relay(relay);
+            assert_eq!(
+                addresses.first().copied(),
+                matcher.get_address_for_wireguard_relay(relay)
+            );
+            addresses
+        };
+
+        assert_eq!(addresses(Constraint::Any, &relay), vec![ipv4, ipv6]);
+        assert_eq!(
+            addresses(Constraint::Only(IpVersion::V4), &relay),
+            vec![ipv4]
+        );
+        assert_eq!(
+            addresses(Constraint::Only(IpVersion::V6), &relay),
+            vec![ipv6]
+        );
+
+        assert_eq!(addresses(Constraint::Any, &ipv4_only_relay), vec![ipv4]);
+        assert_eq!(
+            addresses(Constraint::Only(IpVersion::V4), &ipv4_only_relay),
+            vec![ipv4]
+        );
+        assert!(addresses(Constraint::Only(IpVersion::V6), &ipv4_only_relay).is_empty());
+    }
+
+    #[test]
+    fn test_endpoint_still_valid() {
+        let mut relays = vec![
+            wireguard_relay("se-got-wg-001", "se", "got"),
+            openvpn_relay("se-got-001", "se", "got"),
+        ];
+        relays[1].ipv4_addr_in = "185.213.154.131".parse().unwrap();
+        let matcher = RelayMatcher::from(RelayConstraints::default());
+
+        let wireguard_endpoint = RelayMatcher {
+            tunnel: WireguardMatcher {
+                port: Constraint::Only(51820),
+                ..WireguardMatcher::default()
+            },
+            ..matcher.clone().into_wireguard_matcher()
+        }
+        .mullvad_endpoint(&relays[0])
+        .unwrap();
+        let openvpn_endpoint = matcher.tunnel.openvpn.mullvad_endpoint(&relays[1]).unwrap();
+        assert!(matcher.endpoint_still_valid(&wireguard_endpoint, &relays));
+        assert!(matcher.endpoint_still_valid(&openvpn_endpoint, &relays));
+
+        // The relay is no longer active
+        let mut updated_relays = relays.clone();
+        updated_relays[0].active = false;
+        assert!(!matcher.endpoint_still_valid(&wireguard_endpoint, &updated_relays));
+        assert!(matcher.endpoint_still_valid(&openvpn_endpoint, &updated_relays));
+
+        // The relay has rotated its key
+        let mut updated_relays = relays.clone();
+        updated_relays[0].tunnels.wireguard[0].public_key =
+            wireguard::PrivateKey::new_from_random().public_key();
+        assert!(!matcher.endpoint_still_valid(&wireguard_endpoint, &updated_relays));
+
+        // The relay no longer matches the constraints
+        let mut germany_matcher = matcher.clone();
+        germany_matcher.location = Constraint::Only(LocationConstraint::Country("de".to_string()));
+        assert!(!germany_matcher.endpoint_still_valid(&openvpn_endpoint, &relays));
+
+        // The relay was removed
+        assert!(!matcher.endpoint_still_valid(&openvpn_endpoint, &relays[..1]));
+    }
+
+    #[test]
+    fn test_selection_weight_prefers_dual_stack() {
+        let dual_stack_relay = wireguard_relay("se-got-wg-001", "se", "got");
+        let mut ipv4_only_relay = wireguard_relay("se-got-wg-002", "se", "got");
+        ipv4_only_relay.ipv6_addr_in = None;
+
+        let mut matcher = RelayMatcher::from(RelayConstraints::default());
+        assert_eq!(matcher.selection_weight(&dual_stack_relay), 1);
+        assert_eq!(matcher.selection_weight(&ipv4_only_relay), 1);
+
+        matcher.prefer_dual_stack = true;
+        assert_eq!(
+            matcher.selection_weight(&dual_stack_relay),
+            DUAL_STACK_WEIGHT_FACTOR
+        );
+        assert_eq!(matcher.selection_weight(&ipv4_only_relay), 1);
+        assert!(matcher.filter_matching_relay(&ipv4_only_relay).is_some());
+    }
+
+    #[test]
+    fn test_selection_weight_prefers_recently_added() {
+        let now = Utc::now();
+        let new_relay = Relay {
+            added_at: Some(now - chrono::Duration::days(2)),
+            ..wireguard_relay("se-got-wg-001", "se", "got")
+        };
+        let old_relay = Relay {
+            added_at: Some(now - chrono::Duration::days(100)),
+            ..wireguard_relay("se-got-wg-002", "se", "got")
+        };
+        let unknown_relay = wireguard_relay("se-got-wg-003", "se", "got");
+
+        let mut matcher = RelayMatcher::from(RelayConstraints::default());
+        for relay in [&new_relay, &old_relay, &unknown_relay] {
+            assert_eq!(matcher.selection_weight(relay), 1);
+        }
+
+        matcher.prefer_added_within = Some(Duration::from_secs(30 * 24 * 60 * 60));
+        assert_eq!(
+            matcher.selection_weight(&new_relay),
+            RECENTLY_ADDED_WEIGHT_FACTOR
+        );
+        assert_eq!(matcher.selection_weight(&old_relay), 1);
+        assert_eq!(matcher.selection_weight(&unknown_relay), 1);
+
+        matcher.prefer_dual_stack = true;
+        assert_eq!(
+            matcher.selection_weight(&new_relay),
+            RECENTLY_ADDED_WEIGHT_FACTOR * DUAL_STACK_WEIGHT_FACTOR
+        );
+    }
+
+    #[test]
+    fn test_port_scores() {
+        let mut rng = rand::thread_rng();
+        let port_ranges = [(53, 53), (443, 443), (51820, 51820)];
+        let port_scores: HashMap<u16, f32> = [(443, 10.0), (51820, 0.0), (1194, 100.0)]
+            .into_iter()
+            .collect();
+
+        let mut selections: HashMap<u16, usize> = HashMap::new();
+        for _ in 0..1000 {
+            let port =
+                WireguardMatcher::pick_scored_port(&port_ranges, &port_scores, &mut rng).unwrap();
+            *selections.entry(port).or_insert(0) += 1;
+        }
+        // The expected share of port 443 is 10/11
+        assert!(selections[&443] > 800);
+        assert!(selections[&53] > 0);
+        assert!(!selections.contains_key(&51820));
+        assert!(!selections.contains_key(&1194));
+
+        // Scored ports are skipped when picking among the unscored ports
+        let port_ranges = [(1000, 1009), (2000, 2001)];
+        let port_scores: HashMap<u16, f32> = [(1000, 0.0), (1005, 0.0), (2001, 0.0)]
+            .into_iter()
+            .collect();
+        for _ in 0..100 {
+            let port =
+                WireguardMatcher::pick_scored_port(&port_ranges, &port_scores, &mut rng).unwrap();
+            assert!(!port_scores.contains_key(&port));
+            assert!(port_ranges
+                .iter()
+                .any(|range| range.0 <= port && port <= range.1));
+        }
+
+        // Ports are picked uniformly if all of them have a score of 0
+        let port_scores: HashMap<u16, f32> = [(53, 0.0)].into_iter().collect();
+        assert_eq!(
+            WireguardMatcher::pick_scored_port(&[(53, 53)], &port_scores, &mut rng),
+            Some(53)
+        );
+        assert_eq!(
+            WireguardMatcher::pick_scored_port(&[], &port_scores, &mut rng),
+            None
+        );
+    }
+
+    #[test]
+    fn test_seeded_port() {
+        let relay = wireguard_relay("se-got-wg-001", "se", "got");
+        let data = &relay.tunnels.wireguard[0];
+        let mut matcher = WireguardMatcher::default();
+
+        let port = matcher
+            .get_port_for_wireguard_relay_seeded(data, 1234)
+            .unwrap();
+        for _ in 0..10 {
+            assert_eq!(
+                matcher.get_port_for_wireguard_relay_seeded(data, 1234),
+                Some(port)
+            );
+        }
+        let ports: HashSet<u16> = (0..20)
+            .filter_map(|seed| matcher.get_port_for_wireguard_relay_seeded(data, seed))
+            .collect();
+        assert!(ports.len() > 1);
+
+        matcher.port_scores = Some([(53, 100.0), (51820, 100.0)].into_iter().collect());
+        let port = matcher
+            .get_port_for_wireguard_relay_seeded(data, 1234)
+            .unwrap();
+        for _ in 0..10 {
+            assert_eq!(
+                matcher.get_port_for_wireguard_relay_seeded(data, 1234),
+                Some(port)
+            );
+        }
+
+        matcher.port = Constraint::Only(53);
+        assert_eq!(
+            matcher.get_port_for_wireguard_relay_seeded(data, 1234),
+            Some(53)
+        );
+    }
+
+    #[test]
+    fn test_port_usage() {
+        let mut relay = wireguard_relay("se-got-wg-001", "se", "got");
+        relay.tunnels.wireguard[0].port_ranges = vec![(53, 53), (4000, 4002)];
+        let port_usage = PortUsage::new();
+        let matcher = WireguardMatcher {
+            port_usage: Some(port_usage.clone()),
+            ..WireguardMatcher::default()
+        };
+        let port = |matcher: &WireguardMatcher| {
+            matcher
+                .mullvad_endpoint(&relay)
+                .unwrap()
+                .to_endpoint()
+                .address
+                .port()
+        };
+
+        // Every port is used before any port is reused
+        let ports: Vec<u16> = (0..4).map(|_| port(&matcher)).collect();
+        assert_eq!(
+            ports.iter().copied().collect::<HashSet<_>>(),
+            HashSet::from([53, 4000, 4001, 4002])
+        );
+        // Then the least recently used port is picked
+        for i in 0..20 {
+            assert_eq!(port(&matcher), ports[i % ports.len()]);
+        }
+        assert_eq!(port_usage.recent_ports(&relay.hostname).len(), 4);
+
+        // The state is per relay and can be cleared
+        assert!(port_usage.recent_ports("se-got-wg-002").is_empty());
+        port_usage.clear();
+        assert!(port_usage.recent_ports(&relay.hostname).is_empty());
+
+        // A constrained port is not affected
+        let matcher = WireguardMatcher {
+            port: Constraint::Only(53),
+            ..matcher
+        };
+        for _ in 0..3 {
+            assert_eq!(port(&matcher), 53);
+        }
+        assert!(port_usage.recent_ports(&relay.hostname).is_empty());
+    }
+
+    #[test]
+    fn test_exclude_ports() {
+        assert_eq!(
+            WireguardMatcher::exclude_ports(&[(53, 53), (4000, 4010)], &[53, 4000, 4005, 4010]),
+            vec![(4001, 4004), (4006, 4009)]
+        );
+        assert_eq!(
+            WireguardMatcher::exclude_ports(&[(0, u16::MAX)], &[u16::MAX, 0]),
+            vec![(1, u16::MAX - 1)]
+        );
+        assert_eq!(
+            WireguardMatcher::exclude_ports(&[(53, 53)], &[]),
+            vec![(53, 53)]
+        );
+    }
+
+    #[test]
+    fn test_select_ports() {
+        let data = wireguard_relay("se-got-wg-001", "se", "got")
+            .tunnels
+            .wireguard[0]
+            .clone();
+        let data = WireguardEndpointData {
+            port_ranges: vec![(53, 53), (4000, 4009)],
+            ..data
+        };
+        let mut matcher = WireguardMatcher::default();
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..10 {
+            let mut ports = matcher.select_ports(&data, 5, &mut rng);
+            assert_eq!(ports.len(), 5);
+            assert!(ports
+                .iter()
+                .all(|port| *port == 53 || (4000..=4009).contains(port)));
+            ports.sort_unstable();
+            ports.dedup();
+            assert_eq!(ports.len(), 5);
+        }
+
+        // There are only 11 ports to pick from
+        let mut ports = matcher.select_ports(&data, 20, &mut rng);
+        ports.sort_unstable();
+        let all_ports: Vec<u16> = std::iter::once(53).chain(4000..=4009).collect();
+        assert_eq!(ports, all_ports);
+        assert!(matcher.select_ports(&data, 0, &mut rng).is_empty());
+
+        matcher.port = Constraint::Only(4005);
+        assert_eq!(matcher.select_ports(&data, 5, &mut rng), vec![4005]);
+        matcher.port = Constraint::Only(51820);
+        assert!(matcher.select_ports(&data, 5, &mut rng).is_empty());
     }
 }
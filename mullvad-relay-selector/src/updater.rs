@@ -127,7 +127,10 @@ impl RelayListUpdater {
                     log::error!("Failed to update relay list cache: {}", err);
                 }
             }
-            Ok(None) => log::debug!("Relay list is up-to-date"),
+            Ok(None) => {
+                log::debug!("Relay list is up-to-date");
+                self.confirm_up_to_date().await;
+            }
             Err(error) => log::error!(
                 "{}",
                 error.display_chain_with_msg("Failed to fetch new relay list")
@@ -192,6 +195,22 @@ impl RelayListUpdater {
         Ok(())
     }
 
+    /// Marks the current relay list as fetched now, after the API has confirmed that it has not
+    /// been modified, so that it is not considered stale.
+    async fn confirm_up_to_date(&mut self) {
+        let relay_list = {
+            let mut parsed_relays = self.parsed_relays.lock();
+            parsed_relays.mark_fetched(SystemTime::now());
+            parsed_relays.locations().clone()
+        };
+        if let Err(error) = Self::cache_relays(&self.cache_path, &relay_list).await {
+            log::error!(
+                "{}",
+                error.display_chain_with_msg("Failed to update relay cache on disk")
+            );
+        }
+    }
+
     /// Write a `RelayList` to the cache file.
     async fn cache_relays(cache_path: &Path, relays: &RelayList) -> Result<(), Error> {
         log::debug!("Writing relays cache to {}", cache_path.display());
@@ -8,19 +8,27 @@ use talpid_types::net::{wireguard, Endpoint, TransportProtocol};
 use crate::relay_list::{OpenVpnEndpointData, WireguardEndpointData};
 
 /// Contains server data needed to connect to a single mullvad endpoint
-#[derive(Debug, Clone)]
+///
+/// The serialized representation is tagged with the lowercase name of the tunnel type and used
+/// over IPC, so it must be kept stable. Note that it includes the preshared keys of the peers.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub enum MullvadEndpoint {
+    #[serde(rename = "openvpn")]
     OpenVpn(Endpoint),
+    #[serde(rename = "wireguard")]
     Wireguard(MullvadWireguardEndpoint),
 }
 
 /// Contains WireGuard server data needed to connect to a WireGuard endpoint
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct MullvadWireguardEndpoint {
     pub peer: wireguard::PeerConfig,
     pub exit_peer: Option<wireguard::PeerConfig>,
     pub ipv4_gateway: Ipv4Addr,
     pub ipv6_gateway: Ipv6Addr,
+    /// Local port that the tunnel socket should be bound to. Any port is used if this is `None`.
+    #[serde(default)]
+    pub source_port: Option<u16>,
 }
 
 impl MullvadEndpoint {
@@ -45,6 +53,24 @@ impl MullvadEndpoint {
         }
     }
 }
+
+impl MullvadWireguardEndpoint {
+    /// Returns the public key of the relay that the tunnel connects to. When using multihop, this
+    /// is the entry relay.
+    pub fn peer_public_key(&self) -> &wireguard::PublicKey {
+        &self.peer.public_key
+    }
+
+    /// Returns the public key of the exit relay. This is the same as
+    /// [`MullvadWireguardEndpoint::peer_public_key`] unless multihop is used.
+    pub fn exit_public_key(&self) -> &wireguard::PublicKey {
+        match &self.exit_peer {
+            Some(exit_peer) => &exit_peer.public_key,
+            None => &self.peer.public_key,
+        }
+    }
+}
+
 /// TunnelEndpointData contains data required to connect to a given tunnel endpoint.
 /// Different endpoint types can require different types of data.
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
@@ -82,3 +108,43 @@ impl fmt::Display for TunnelEndpointData {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mullvad_endpoint_serialization() {
+        let openvpn = MullvadEndpoint::OpenVpn(Endpoint::new(
+            Ipv4Addr::new(185, 213, 154, 68),
+            1194,
+            TransportProtocol::Udp,
+        ));
+        let peer = |public_key: &str, endpoint: &str| wireguard::PeerConfig {
+            public_key: wireguard::PublicKey::from_base64(public_key).unwrap(),
+            allowed_ips: vec!["10.64.0.1/32".parse().unwrap()],
+            endpoint: endpoint.parse().unwrap(),
+            psk: Some(wireguard::PresharedKey::from([7; 32])),
+        };
+        let wireguard = MullvadEndpoint::Wireguard(MullvadWireguardEndpoint {
+            peer: peer(
+                "BLNHNoGO88LjV/wDBa7CUUwUzPq/fO2UwcGLy56hKy4=",
+                "185.213.154.68:51820",
+            ),
+            exit_peer: Some(peer(
+                "veGD6/aEY6sMfN3Ls7YWPmNgu3AheO7nQqsFT47YSws=",
+                "185.213.154.69:51820",
+            )),
+            ipv4_gateway: Ipv4Addr::new(10, 64, 0, 1),
+            ipv6_gateway: "fc00:bbbb:bbbb:bb01::1".parse().unwrap(),
+            source_port: Some(51821),
+        });
+
+        for (endpoint, tag) in [(openvpn, "openvpn"), (wireguard, "wireguard")] {
+            let serialized = serde_json::to_value(&endpoint).unwrap();
+            assert!(serialized.get(tag).is_some());
+            let deserialized: MullvadEndpoint = serde_json::from_value(serialized).unwrap();
+            assert_eq!(deserialized, endpoint);
+        }
+    }
+}
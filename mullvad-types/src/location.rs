@@ -9,6 +9,7 @@ pub type Hostname = String;
 
 /// Describes the physical location of a [`crate::relay_list::Relay`] as returned by the API.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Location {
     pub country: String,
     pub country_code: CountryCode,
@@ -57,6 +58,16 @@ impl From<Location> for Coordinates {
 }
 
 impl Coordinates {
+    /// Returns the distance in kilometers between `self` and `other`.
+    pub fn distance_from(&self, other: &Coordinates) -> f64 {
+        haversine_dist_deg(
+            self.latitude,
+            self.longitude,
+            other.latitude,
+            other.longitude,
+        )
+    }
+
     /// Computes the approximate midpoint of a set of locations.
     ///
     /// This works by calculating the mean Cartesian coordinates, and converting them
@@ -68,7 +79,7 @@ impl Coordinates {
         Self::midpoint_inner(locations.iter().map(Coordinates::from))
     }
 
-    fn midpoint_inner(locations: impl std::iter::Iterator<Item = Coordinates>) -> Self {
+    pub(crate) fn midpoint_inner(locations: impl std::iter::Iterator<Item = Coordinates>) -> Self {
         let mut x = 0f64;
         let mut y = 0f64;
         let mut z = 0f64;
@@ -2,6 +2,7 @@
 //! updated as well.
 
 use crate::{
+    endpoint::MullvadEndpoint,
     location::{CityCode, CountryCode, Hostname},
     relay_list::{OpenVpnEndpointData, Relay},
     CustomTunnelEndpoint,
@@ -9,7 +10,11 @@ use crate::{
 #[cfg(target_os = "android")]
 use jnix::{FromJava, IntoJava};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashSet, fmt};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    time::Duration,
+};
 use talpid_types::net::{openvpn::ProxySettings, IpVersion, TransportProtocol, TunnelType};
 
 pub trait Match<T> {
@@ -32,6 +37,11 @@ pub enum Constraint<T: fmt::Debug + Clone + Eq + PartialEq> {
 }
 
 impl<T: fmt::Debug + Clone + Eq + PartialEq> Constraint<T> {
+    /// Returns a constraint that only allows `value`. Shorthand for `Constraint::Only(value)`.
+    pub fn only(value: T) -> Self {
+        Constraint::Only(value)
+    }
+
     pub fn unwrap(self) -> T {
         match self {
             Constraint::Any => panic!("called `Constraint::unwrap()` on an `Any` value"),
@@ -104,6 +114,12 @@ impl<T: fmt::Debug + Clone + Eq + PartialEq> Default for Constraint<T> {
 
 impl<T: Copy + fmt::Debug + Clone + Eq + PartialEq> Copy for Constraint<T> {}
 
+impl<T: fmt::Debug + Clone + Eq + PartialEq> From<T> for Constraint<T> {
+    fn from(value: T) -> Self {
+        Constraint::Only(value)
+    }
+}
+
 impl<T: fmt::Debug + Clone + Eq + Match<U>, U> Match<U> for Constraint<T> {
     fn matches(&self, other: &U) -> bool {
         match *self {
@@ -209,6 +225,44 @@ impl Default for RelayConstraints {
 }
 
 impl RelayConstraints {
+    /// Returns the tightest constraints that still match `relay`, for locking the selection to
+    /// it. The location is constrained to the hostname of the relay if its location is known,
+    /// and the tunnel protocol is only constrained if the relay supports a single one.
+    pub fn from_relay(relay: &Relay) -> RelayConstraints {
+        let location = match &relay.location {
+            Some(location) => Constraint::Only(LocationConstraint::Hostname(
+                location.country_code.clone(),
+                location.city_code.clone(),
+                relay.hostname.clone(),
+            )),
+            None => Constraint::Any,
+        };
+        let ownership = if relay.owned {
+            Ownership::MullvadOwned
+        } else {
+            Ownership::Rented
+        };
+        let tunnel_protocol = match (
+            relay.tunnels.wireguard.is_empty(),
+            relay.tunnels.openvpn.is_empty(),
+        ) {
+            (false, true) => Constraint::Only(TunnelType::Wireguard),
+            (true, false) => Constraint::Only(TunnelType::OpenVpn),
+            _ => Constraint::Any,
+        };
+
+        RelayConstraints {
+            location,
+            providers: Providers::new(std::iter::once(relay.provider.clone()))
+                .map(Constraint::Only)
+                .unwrap_or(Constraint::Any),
+            ownership: Constraint::Only(ownership),
+            tunnel_protocol,
+            wireguard_constraints: WireguardConstraints::default(),
+            openvpn_constraints: OpenVpnConstraints::default(),
+        }
+    }
+
     pub fn merge(&self, update: RelayConstraintsUpdate) -> Self {
         RelayConstraints {
             location: update.location.unwrap_or_else(|| self.location.clone()),
@@ -223,6 +277,335 @@ impl RelayConstraints {
                 .unwrap_or(self.openvpn_constraints),
         }
     }
+
+    /// Encodes the constraints as a compact, URL-safe string that can be shared and later read
+    /// back with [`RelayConstraints::from_share_string`], e.g. `se/got@wireguard:51820/owned`.
+    ///
+    /// The string is `<location>@<tunnel>` followed by `/`-separated options. The location is
+    /// `any` or a country, city and hostname joined by `/`. The tunnel is `any`, `wireguard` or
+    /// `openvpn`, optionally followed by the port of that protocol. Constraints that are not set
+    /// are left out.
+    pub fn to_share_string(&self) -> String {
+        let mut share = match &self.location {
+            Constraint::Any => "any".to_string(),
+            Constraint::Only(location) => location_share_parts(location).join("/"),
+        };
+        share.push('@');
+
+        let wireguard_port = self.wireguard_constraints.port.option();
+        let openvpn_port = self.openvpn_constraints.port.option();
+        match self.tunnel_protocol {
+            Constraint::Any => share.push_str("any"),
+            Constraint::Only(TunnelType::Wireguard) => {
+                share.push_str("wireguard");
+                if let Some(port) = wireguard_port {
+                    share.push_str(&format!(":{}", port));
+                }
+            }
+            Constraint::Only(TunnelType::OpenVpn) => {
+                share.push_str("openvpn");
+                if let Some(port) = openvpn_port {
+                    share.push_str(&format!(":{}", openvpn_port_share_string(port)));
+                }
+            }
+        }
+
+        let mut options = Vec::new();
+        match self.ownership {
+            Constraint::Any => (),
+            Constraint::Only(Ownership::MullvadOwned) => options.push("owned".to_string()),
+            Constraint::Only(Ownership::Rented) => options.push("rented".to_string()),
+        }
+        if let Constraint::Only(providers) = &self.providers {
+            let mut providers: Vec<_> = providers
+                .providers
+                .iter()
+                .map(|provider| share_encode(provider))
+                .collect();
+            providers.sort();
+            options.push(format!("providers={}", providers.join(",")));
+        }
+        match self.wireguard_constraints.ip_version {
+            Constraint::Any => (),
+            Constraint::Only(IpVersion::V4) => options.push("ipv4".to_string()),
+            Constraint::Only(IpVersion::V6) => options.push("ipv6".to_string()),
+        }
+        if self.wireguard_constraints.use_multihop {
+            options.push("multihop".to_string());
+        }
+        if let Constraint::Only(entry) = &self.wireguard_constraints.entry_location {
+            options.push(format!("entry={}", location_share_parts(entry).join(",")));
+        }
+        if let Constraint::Only(port) = self.wireguard_constraints.exit_port {
+            options.push(format!("exit-port={}", port));
+        }
+        if self.wireguard_constraints.require_different_country {
+            options.push("different-country".to_string());
+        }
+        if self.tunnel_protocol != Constraint::Only(TunnelType::Wireguard) {
+            if let Some(port) = wireguard_port {
+                options.push(format!("wireguard={}", port));
+            }
+        }
+        if self.tunnel_protocol != Constraint::Only(TunnelType::OpenVpn) {
+            if let Some(port) = openvpn_port {
+                options.push(format!("openvpn={}", openvpn_port_share_string(port)));
+            }
+        }
+
+        for option in options {
+            share.push('/');
+            share.push_str(&option);
+        }
+        share
+    }
+
+    /// Parses a string created by [`RelayConstraints::to_share_string`]. Constraints that are
+    /// left out of the string are set to [`Constraint::Any`].
+    pub fn from_share_string(share: &str) -> Result<RelayConstraints, ParseShareStringError> {
+        let (location, rest) = share
+            .split_once('@')
+            .ok_or(ParseShareStringError::MissingTunnel)?;
+        let invalid_location = || ParseShareStringError::InvalidLocation(location.to_string());
+        let location = if location == "any" {
+            Constraint::Any
+        } else {
+            Constraint::Only(
+                parse_share_location(&location.split('/').collect::<Vec<_>>())
+                    .ok_or_else(invalid_location)?,
+            )
+        };
+
+        let mut constraints = RelayConstraints {
+            location,
+            providers: Constraint::Any,
+            ownership: Constraint::Any,
+            tunnel_protocol: Constraint::Any,
+            wireguard_constraints: WireguardConstraints::default(),
+            openvpn_constraints: OpenVpnConstraints::default(),
+        };
+
+        let mut parts = rest.split('/');
+        let tunnel = parts.next().unwrap_or_default();
+        let invalid_tunnel = || ParseShareStringError::InvalidTunnel(tunnel.to_string());
+        let (protocol, port) = match tunnel.split_once(':') {
+            Some((protocol, port)) => (protocol, Some(port)),
+            None => (tunnel, None),
+        };
+        match (protocol, port) {
+            ("any", None) => (),
+            ("wireguard", port) => {
+                constraints.tunnel_protocol = Constraint::Only(TunnelType::Wireguard);
+                if let Some(port) = port {
+                    let port = port.parse().map_err(|_| invalid_tunnel())?;
+                    constraints.wireguard_constraints.port = Constraint::Only(port);
+                }
+            }
+            ("openvpn", port) => {
+                constraints.tunnel_protocol = Constraint::Only(TunnelType::OpenVpn);
+                if let Some(port) = port {
+                    let port = parse_openvpn_share_port(port).ok_or_else(invalid_tunnel)?;
+                    constraints.openvpn_constraints.port = Constraint::Only(port);
+                }
+            }
+            _ => return Err(invalid_tunnel()),
+        }
+
+        let mut seen = HashSet::new();
+        for option in parts {
+            let invalid_option = || ParseShareStringError::InvalidOption(option.to_string());
+            let (key, value) = match option.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (option, None),
+            };
+            let setting = match key {
+                "owned" | "rented" => "ownership",
+                "ipv4" | "ipv6" => "ip_version",
+                key => key,
+            };
+            if !seen.insert(setting) {
+                return Err(ParseShareStringError::DuplicateOption(setting.to_string()));
+            }
+
+            match (key, value) {
+                ("owned", None) => {
+                    constraints.ownership = Constraint::Only(Ownership::MullvadOwned);
+                }
+                ("rented", None) => constraints.ownership = Constraint::Only(Ownership::Rented),
+                ("providers", Some(value)) => {
+                    let providers = value
+                        .split(',')
+                        .map(share_decode)
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(invalid_option)?;
+                    let providers =
+                        Providers::new(providers.into_iter()).map_err(|_| invalid_option())?;
+                    constraints.providers = Constraint::Only(providers);
+                }
+                ("ipv4", None) => {
+                    constraints.wireguard_constraints.ip_version = Constraint::Only(IpVersion::V4);
+                }
+                ("ipv6", None) => {
+                    constraints.wireguard_constraints.ip_version = Constraint::Only(IpVersion::V6);
+                }
+                ("multihop", None) => constraints.wireguard_constraints.use_multihop = true,
+                ("entry", Some(value)) => {
+                    let entry = parse_share_location(&value.split(',').collect::<Vec<_>>())
+                        .ok_or_else(invalid_option)?;
+                    constraints.wireguard_constraints.entry_location = Constraint::Only(entry);
+                }
+                ("exit-port", Some(value)) => {
+                    let port = value.parse().map_err(|_| invalid_option())?;
+                    constraints.wireguard_constraints.exit_port = Constraint::Only(port);
+                }
+                ("different-country", None) => {
+                    constraints.wireguard_constraints.require_different_country = true
+                }
+                ("wireguard", Some(value)) if constraints.wireguard_constraints.port.is_any() => {
+                    let port = value.parse().map_err(|_| invalid_option())?;
+                    constraints.wireguard_constraints.port = Constraint::Only(port);
+                }
+                ("openvpn", Some(value)) if constraints.openvpn_constraints.port.is_any() => {
+                    let port = parse_openvpn_share_port(value).ok_or_else(invalid_option)?;
+                    constraints.openvpn_constraints.port = Constraint::Only(port);
+                }
+                ("wireguard" | "openvpn", Some(_)) => {
+                    return Err(ParseShareStringError::DuplicateOption(key.to_string()));
+                }
+                _ => return Err(invalid_option()),
+            }
+        }
+
+        Ok(constraints)
+    }
+}
+
+/// Returned by [`RelayConstraints::from_share_string`] for malformed strings.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ParseShareStringError {
+    /// There was no `@` separating the location from the tunnel protocol.
+    MissingTunnel,
+    InvalidLocation(String),
+    InvalidTunnel(String),
+    InvalidOption(String),
+    /// The same constraint was given more than once.
+    DuplicateOption(String),
+}
+
+impl fmt::Display for ParseShareStringError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseShareStringError::MissingTunnel => {
+                write!(
+                    f,
+                    "Missing '@' between the location and the tunnel protocol"
+                )
+            }
+            ParseShareStringError::InvalidLocation(location) => {
+                write!(f, "Invalid location: {}", location)
+            }
+            ParseShareStringError::InvalidTunnel(tunnel) => {
+                write!(f, "Invalid tunnel protocol: {}", tunnel)
+            }
+            ParseShareStringError::InvalidOption(option) => {
+                write!(f, "Invalid option: {}", option)
+            }
+            ParseShareStringError::DuplicateOption(option) => {
+                write!(f, "Option given more than once: {}", option)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseShareStringError {}
+
+fn location_share_parts(location: &LocationConstraint) -> Vec<String> {
+    match location {
+        LocationConstraint::Country(country) => vec![share_encode(country)],
+        LocationConstraint::City(country, city) => vec![share_encode(country), share_encode(city)],
+        LocationConstraint::Hostname(country, city, hostname) => vec![
+            share_encode(country),
+            share_encode(city),
+            share_encode(hostname),
+        ],
+    }
+}
+
+fn parse_share_location(parts: &[&str]) -> Option<LocationConstraint> {
+    let parts = parts
+        .iter()
+        .map(|part| share_decode(part))
+        .collect::<Option<Vec<_>>>()?;
+    match <[String; 3]>::try_from(parts) {
+        Ok([country, city, hostname]) => {
+            Some(LocationConstraint::Hostname(country, city, hostname))
+        }
+        Err(parts) => match <[String; 2]>::try_from(parts) {
+            Ok([country, city]) => Some(LocationConstraint::City(country, city)),
+            Err(parts) => match <[String; 1]>::try_from(parts) {
+                Ok([country]) => Some(LocationConstraint::Country(country)),
+                Err(_) => None,
+            },
+        },
+    }
+}
+
+fn openvpn_port_share_string(port: TransportPort) -> String {
+    let protocol = match port.protocol {
+        TransportProtocol::Udp => "udp",
+        TransportProtocol::Tcp => "tcp",
+    };
+    match port.port {
+        Constraint::Any => protocol.to_string(),
+        Constraint::Only(port) => format!("{}:{}", protocol, port),
+    }
+}
+
+fn parse_openvpn_share_port(port: &str) -> Option<TransportPort> {
+    let (protocol, port) = match port.split_once(':') {
+        Some((protocol, port)) => (protocol, Constraint::Only(port.parse().ok()?)),
+        None => (port, Constraint::Any),
+    };
+    Some(TransportPort {
+        protocol: protocol.parse().ok()?,
+        port,
+    })
+}
+
+/// Percent-encodes everything but unreserved URL characters, so that values never contain the
+/// separators used in share strings.
+fn share_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Reverses [`share_encode`]. Empty values are rejected.
+fn share_decode(value: &str) -> Option<String> {
+    if value.is_empty() {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let mut hex_digit = || bytes.next().filter(u8::is_ascii_hexdigit);
+                let hex = [hex_digit()?, hex_digit()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            byte if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => decoded.push(byte),
+            _ => return None,
+        }
+    }
+    String::from_utf8(decoded).ok()
 }
 
 impl fmt::Display for RelayConstraints {
@@ -361,6 +744,7 @@ pub struct Providers {
 }
 
 /// Returned if the iterator contained no providers.
+#[derive(Debug)]
 pub struct NoProviders(());
 
 impl Providers {
@@ -375,10 +759,48 @@ impl Providers {
     }
 }
 
+/// Providers may be given as glob patterns, where `*` matches any sequence of characters and `?`
+/// matches a single character. Providers without these characters must match exactly.
 impl Match<Relay> for Providers {
     fn matches(&self, relay: &Relay) -> bool {
         self.providers.contains(&relay.provider)
+            || self
+                .providers
+                .iter()
+                .filter(|provider| provider.contains(['*', '?']))
+                .any(|pattern| glob_matches(pattern, &relay.provider))
+    }
+}
+
+/// Returns whether `text` matches the glob `pattern`, supporting `*` and `?`.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern and the text position it was tried at
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` consume one more character
+                Some((star_p, star_t)) => {
+                    backtrack = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
     }
+    pattern[p..].iter().all(|&c| c == '*')
 }
 
 impl From<Providers> for Vec<Provider> {
@@ -429,13 +851,14 @@ impl fmt::Display for OpenVpnConstraints {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         match self.port {
             Constraint::Any => write!(f, "any port"),
-            Constraint::Only(port) => {
-                match port.port {
-                    Constraint::Any => write!(f, "any port")?,
-                    Constraint::Only(port) => write!(f, "port {}", port)?,
-                }
-                write!(f, "/{}", port.protocol)
-            }
+            Constraint::Only(TransportPort {
+                protocol,
+                port: Constraint::Any,
+            }) => write!(f, "any {} port", protocol),
+            Constraint::Only(TransportPort {
+                protocol,
+                port: Constraint::Only(port),
+            }) => write!(f, "{} port {}", protocol, port),
         }
     }
 }
@@ -455,6 +878,56 @@ impl Match<OpenVpnEndpointData> for OpenVpnConstraints {
     }
 }
 
+/// How much higher the latency of a UDP endpoint may be than that of the fastest TCP endpoint for
+/// [`OpenVpnConstraints::select_endpoint_by_latency`] to still prefer UDP.
+pub const OPENVPN_UDP_LATENCY_TOLERANCE: Duration = Duration::from_millis(50);
+
+impl OpenVpnConstraints {
+    /// Picks the OpenVPN endpoint of `relay` with the lowest latency according to `latencies`,
+    /// which maps ports and transport protocols to latency hints. Since UDP is usually faster in
+    /// practice, the fastest UDP endpoint is picked unless its latency exceeds that of the fastest
+    /// TCP endpoint by more than [`OPENVPN_UDP_LATENCY_TOLERANCE`].
+    ///
+    /// Only endpoints matching the constraints and having a latency hint are considered. Returns
+    /// `None` if there are no such endpoints.
+    pub fn select_endpoint_by_latency(
+        &self,
+        relay: &Relay,
+        latencies: &HashMap<(u16, TransportProtocol), Duration>,
+    ) -> Option<MullvadEndpoint> {
+        let fastest = |protocol: TransportProtocol| {
+            relay
+                .tunnels
+                .openvpn
+                .iter()
+                .filter(|endpoint| endpoint.protocol == protocol && self.matches(*endpoint))
+                .filter_map(|endpoint| {
+                    latencies
+                        .get(&(endpoint.port, endpoint.protocol))
+                        .map(|latency| (endpoint, *latency))
+                })
+                .min_by_key(|(_, latency)| *latency)
+        };
+
+        let endpoint = match (
+            fastest(TransportProtocol::Udp),
+            fastest(TransportProtocol::Tcp),
+        ) {
+            (Some((udp, udp_latency)), Some((tcp, tcp_latency))) => {
+                if udp_latency <= tcp_latency + OPENVPN_UDP_LATENCY_TOLERANCE {
+                    udp
+                } else {
+                    tcp
+                }
+            }
+            (Some((udp, _)), None) => udp,
+            (None, Some((tcp, _))) => tcp,
+            (None, None) => return None,
+        };
+        Some(endpoint.into_mullvad_endpoint(relay.ipv4_addr_in.into()))
+    }
+}
+
 /// [`Constraint`]s applicable to WireGuard relay servers.
 #[derive(Debug, Default, Clone, Eq, PartialEq, Deserialize, Serialize)]
 #[serde(default)]
@@ -463,6 +936,11 @@ pub struct WireguardConstraints {
     pub ip_version: Constraint<IpVersion>,
     pub use_multihop: bool,
     pub entry_location: Constraint<LocationConstraint>,
+    /// The port that the exit relay is connected to on through the entry relay when multihop is
+    /// used. The default WireGuard port is used if this is unconstrained.
+    pub exit_port: Constraint<u16>,
+    /// Only select entry and exit relays in different countries when multihop is used.
+    pub require_different_country: bool,
 }
 
 impl fmt::Display for WireguardConstraints {
@@ -478,9 +956,16 @@ impl fmt::Display for WireguardConstraints {
         }
         if self.use_multihop {
             match &self.entry_location {
-                Constraint::Any => write!(f, " (via any location)"),
-                Constraint::Only(location) => write!(f, " (via {})", location),
+                Constraint::Any => write!(f, " (via any location")?,
+                Constraint::Only(location) => write!(f, " (via {}", location)?,
+            }
+            if let Constraint::Only(port) = self.exit_port {
+                write!(f, ", exit port {}", port)?;
             }
+            if self.require_different_country {
+                write!(f, ", exit in another country")?;
+            }
+            write!(f, ")")
         } else {
             Ok(())
         }
@@ -545,6 +1030,43 @@ pub struct ObfuscationSettings {
     pub udp2tcp: Udp2TcpObfuscationSettings,
 }
 
+/// A combination of constraints where one of the constraints has no effect.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ConstraintConflict {
+    /// The WireGuard traffic is sent to the port of the udp2tcp obfuscator instead, so the pinned
+    /// WireGuard port is only used internally by the relay.
+    WireguardPortWithUdp2Tcp(u16),
+}
+
+impl fmt::Display for ConstraintConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConstraintConflict::WireguardPortWithUdp2Tcp(port) => write!(
+                f,
+                "WireGuard port {} has no effect when udp2tcp obfuscation is enabled",
+                port
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConstraintConflict {}
+
+/// Checks that a pinned WireGuard port is actually used with the given obfuscation settings, so
+/// that frontends can warn about constraints that have no effect. Automatic obfuscation is not
+/// considered a conflict, since the port is used for the attempts that are not obfuscated.
+pub fn validate_port_constraints(
+    wg_port: Constraint<u16>,
+    obfuscation: &ObfuscationSettings,
+) -> Result<(), ConstraintConflict> {
+    match (wg_port, obfuscation.selected_obfuscation) {
+        (Constraint::Only(port), SelectedObfuscation::Udp2Tcp) => {
+            Err(ConstraintConflict::WireguardPortWithUdp2Tcp(port))
+        }
+        _ => Ok(()),
+    }
+}
+
 /// Limits the set of bridge servers to use in `mullvad-daemon`.
 #[derive(Debug, Default, Clone, Eq, PartialEq, Deserialize, Serialize)]
 #[serde(default)]
@@ -604,6 +1126,13 @@ pub struct InternalBridgeConstraints {
     pub providers: Constraint<Providers>,
     pub ownership: Constraint<Ownership>,
     pub transport_protocol: Constraint<TransportProtocol>,
+    /// Port of the Shadowsocks bridge to use. Relays without a bridge on this port are not
+    /// selected.
+    #[serde(default)]
+    pub port: Constraint<u16>,
+    /// Shadowsocks cipher to prefer if the selected bridge supports it.
+    #[serde(default)]
+    pub preferred_cipher: Option<String>,
 }
 
 /// Used to update the [`RelaySettings`] used in `mullvad-daemon`.
@@ -656,3 +1185,277 @@ pub struct RelayConstraintsUpdate {
     #[cfg_attr(target_os = "android", jnix(default))]
     pub openvpn_constraints: Option<OpenVpnConstraints>,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_constraint_constructors() {
+        assert_eq!(Constraint::only(53u16), Constraint::Only(53));
+        assert_eq!(Constraint::from(53u16), Constraint::Only(53));
+        let port: Constraint<u16> = 53.into();
+        assert_eq!(port, Constraint::Only(53));
+        let location: Constraint<LocationConstraint> =
+            LocationConstraint::Country("se".to_string()).into();
+        assert!(location.is_only());
+
+        assert_eq!(Constraint::<u16>::from(Some(53)), Constraint::Only(53));
+        let port: Constraint<u16> = None.into();
+        assert!(port.is_any());
+        for constraint in [Constraint::Any, Constraint::Only(53u16)] {
+            assert_eq!(Constraint::from(constraint.option()), constraint);
+        }
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("31*", "31173"));
+        assert!(glob_matches("31*", "31"));
+        assert!(!glob_matches("31*", "M31"));
+        assert!(glob_matches("M24?", "M247"));
+        assert!(!glob_matches("M24?", "M24"));
+        assert!(!glob_matches("M24?", "M2470"));
+        assert!(glob_matches("*a*b", "xaxxab"));
+        assert!(!glob_matches("*a*b", "xaxxba"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("DataPacket", "DataPacket"));
+        assert!(!glob_matches("DataPacket", "DataPacke"));
+    }
+
+    #[test]
+    fn test_openvpn_constraints_display() {
+        let mut constraints = OpenVpnConstraints::default();
+        assert_eq!(constraints.to_string(), "any port");
+
+        constraints.port = Constraint::Only(TransportPort {
+            protocol: TransportProtocol::Tcp,
+            port: Constraint::Any,
+        });
+        assert_eq!(constraints.to_string(), "any TCP port");
+
+        constraints.port = Constraint::Only(TransportPort {
+            protocol: TransportProtocol::Udp,
+            port: Constraint::Only(1301),
+        });
+        assert_eq!(constraints.to_string(), "UDP port 1301");
+    }
+
+    #[test]
+    fn test_wireguard_constraints_display() {
+        let mut constraints = WireguardConstraints::default();
+        assert_eq!(constraints.to_string(), "any port over IPv4 or IPv6");
+
+        constraints.port = Constraint::Only(51820);
+        constraints.ip_version = Constraint::Only(IpVersion::V6);
+        assert_eq!(constraints.to_string(), "port 51820 over IPv6");
+
+        constraints.use_multihop = true;
+        constraints.entry_location = Constraint::Only(LocationConstraint::Country("se".to_owned()));
+        assert_eq!(
+            constraints.to_string(),
+            "port 51820 over IPv6 (via country se)"
+        );
+
+        constraints.exit_port = Constraint::Only(4000);
+        assert_eq!(
+            constraints.to_string(),
+            "port 51820 over IPv6 (via country se, exit port 4000)"
+        );
+
+        constraints.require_different_country = true;
+        assert_eq!(
+            constraints.to_string(),
+            "port 51820 over IPv6 (via country se, exit port 4000, exit in another country)"
+        );
+    }
+
+    #[test]
+    fn test_validate_port_constraints() {
+        let obfuscation = |selected_obfuscation| ObfuscationSettings {
+            selected_obfuscation,
+            udp2tcp: Udp2TcpObfuscationSettings {
+                port: Constraint::Only(443),
+            },
+        };
+
+        assert_eq!(
+            validate_port_constraints(
+                Constraint::Only(51820),
+                &obfuscation(SelectedObfuscation::Udp2Tcp)
+            ),
+            Err(ConstraintConflict::WireguardPortWithUdp2Tcp(51820))
+        );
+        assert_eq!(
+            validate_port_constraints(Constraint::Any, &obfuscation(SelectedObfuscation::Udp2Tcp)),
+            Ok(())
+        );
+        for selected_obfuscation in [SelectedObfuscation::Off, SelectedObfuscation::Auto] {
+            assert_eq!(
+                validate_port_constraints(
+                    Constraint::Only(51820),
+                    &obfuscation(selected_obfuscation)
+                ),
+                Ok(())
+            );
+        }
+    }
+
+    #[test]
+    fn test_share_string_round_trip() {
+        let constraints = RelayConstraints {
+            location: Constraint::Only(LocationConstraint::City(
+                "se".to_string(),
+                "got".to_string(),
+            )),
+            providers: Constraint::Any,
+            ownership: Constraint::Only(Ownership::MullvadOwned),
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            wireguard_constraints: WireguardConstraints {
+                port: Constraint::Only(51820),
+                ..Default::default()
+            },
+            openvpn_constraints: OpenVpnConstraints::default(),
+        };
+        assert_eq!(
+            constraints.to_share_string(),
+            "se/got@wireguard:51820/owned"
+        );
+        assert_eq!(
+            RelayConstraints::from_share_string("se/got@wireguard:51820/owned").unwrap(),
+            constraints
+        );
+
+        let unconstrained = RelayConstraints {
+            location: Constraint::Any,
+            providers: Constraint::Any,
+            ownership: Constraint::Any,
+            tunnel_protocol: Constraint::Any,
+            wireguard_constraints: WireguardConstraints::default(),
+            openvpn_constraints: OpenVpnConstraints::default(),
+        };
+        assert_eq!(unconstrained.to_share_string(), "any@any");
+
+        let everything = RelayConstraints {
+            location: Constraint::Only(LocationConstraint::Hostname(
+                "se".to_string(),
+                "got".to_string(),
+                "se-got-wg-001".to_string(),
+            )),
+            providers: Constraint::Only(
+                Providers::new(["M247".to_string(), "31*/x,y".to_string()].into_iter()).unwrap(),
+            ),
+            ownership: Constraint::Only(Ownership::Rented),
+            tunnel_protocol: Constraint::Any,
+            wireguard_constraints: WireguardConstraints {
+                port: Constraint::Only(53),
+                ip_version: Constraint::Only(IpVersion::V6),
+                use_multihop: true,
+                entry_location: Constraint::Only(LocationConstraint::Country("de".to_string())),
+                exit_port: Constraint::Only(4000),
+                require_different_country: true,
+            },
+            openvpn_constraints: OpenVpnConstraints {
+                port: Constraint::Only(TransportPort {
+                    protocol: TransportProtocol::Tcp,
+                    port: Constraint::Any,
+                }),
+            },
+        };
+        let openvpn = RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::OpenVpn),
+            location: Constraint::Only(LocationConstraint::Country("de".to_string())),
+            openvpn_constraints: OpenVpnConstraints {
+                port: Constraint::Only(TransportPort {
+                    protocol: TransportProtocol::Udp,
+                    port: Constraint::Only(1194),
+                }),
+            },
+            ..unconstrained.clone()
+        };
+        assert_eq!(openvpn.to_share_string(), "de@openvpn:udp:1194");
+
+        for constraints in [constraints, unconstrained, everything, openvpn] {
+            let share = constraints.to_share_string();
+            assert!(share
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-._~%/@:=,".contains(c)));
+            assert_eq!(
+                RelayConstraints::from_share_string(&share),
+                Ok(constraints),
+                "{}",
+                share
+            );
+        }
+    }
+
+    #[test]
+    fn test_share_string_rejects_malformed() {
+        for (share, error) in [
+            ("se/got", ParseShareStringError::MissingTunnel),
+            (
+                "se/got/host/extra@any",
+                ParseShareStringError::InvalidLocation("se/got/host/extra".to_string()),
+            ),
+            (
+                "se//host@any",
+                ParseShareStringError::InvalidLocation("se//host".to_string()),
+            ),
+            (
+                "@any",
+                ParseShareStringError::InvalidLocation("".to_string()),
+            ),
+            (
+                "se%2@any",
+                ParseShareStringError::InvalidLocation("se%2".to_string()),
+            ),
+            (
+                "se@ikev2",
+                ParseShareStringError::InvalidTunnel("ikev2".to_string()),
+            ),
+            (
+                "se@any:53",
+                ParseShareStringError::InvalidTunnel("any:53".to_string()),
+            ),
+            (
+                "se@wireguard:70000",
+                ParseShareStringError::InvalidTunnel("wireguard:70000".to_string()),
+            ),
+            (
+                "se@openvpn:quic:443",
+                ParseShareStringError::InvalidTunnel("openvpn:quic:443".to_string()),
+            ),
+            (
+                "se@any/fast",
+                ParseShareStringError::InvalidOption("fast".to_string()),
+            ),
+            (
+                "se@any/owned=yes",
+                ParseShareStringError::InvalidOption("owned=yes".to_string()),
+            ),
+            (
+                "se@any/providers=",
+                ParseShareStringError::InvalidOption("providers=".to_string()),
+            ),
+            (
+                "se@any/",
+                ParseShareStringError::InvalidOption("".to_string()),
+            ),
+            (
+                "se@any/owned/rented",
+                ParseShareStringError::DuplicateOption("ownership".to_string()),
+            ),
+            (
+                "se@wireguard:53/wireguard=51820",
+                ParseShareStringError::DuplicateOption("wireguard".to_string()),
+            ),
+        ] {
+            assert_eq!(
+                RelayConstraints::from_share_string(share),
+                Err(error),
+                "{}",
+                share
+            );
+        }
+    }
+}
@@ -1,28 +1,49 @@
 use crate::{
-    endpoint::MullvadEndpoint,
-    location::{CityCode, CountryCode, Location},
+    endpoint::{MullvadEndpoint, MullvadWireguardEndpoint},
+    location::{CityCode, Coordinates, CountryCode, Location},
+    relay_constraints::{LocationConstraint, Match},
 };
+use chrono::{DateTime, Utc};
 #[cfg(target_os = "android")]
 use jnix::IntoJava;
 use serde::{Deserialize, Serialize};
 use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
     fmt,
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{self, BufReader, BufWriter, Write},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    time::{Duration, SystemTime},
 };
 use talpid_types::net::{
+    all_of_the_internet,
     openvpn::{ProxySettings, ShadowsocksProxySettings},
-    wireguard, Endpoint, TransportProtocol,
+    wireguard, Endpoint, TransportProtocol, TunnelType,
 };
 
+/// Placeholder for secrets removed by [`RelayList::redacted`].
+pub const REDACTED: &str = "<redacted>";
+
+/// Release channel of relays that are not experimental. See [`Relay::channel`].
+pub const STABLE_CHANNEL: &str = "stable";
+
 /// Stores a list of relays for each country obtained from the API using
 /// `mullvad_api::RelayListProxy`. This can also be passed to frontends.
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(target_os = "android", derive(IntoJava))]
 #[cfg_attr(target_os = "android", jnix(package = "net.mullvad.mullvadvpn.model"))]
 pub struct RelayList {
     #[cfg_attr(target_os = "android", jnix(skip))]
     pub etag: Option<String>,
     pub countries: Vec<RelayListCountry>,
+    /// When the relay list was fetched from the API, if known.
+    #[serde(default)]
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub fetched_at: Option<SystemTime>,
 }
 
 impl RelayList {
@@ -30,12 +51,739 @@ impl RelayList {
         Self {
             etag: None,
             countries: Vec::new(),
+            fetched_at: None,
+        }
+    }
+
+    /// Returns a copy of the relay list without any relays hosted by `provider`. Cities and
+    /// countries that are left without relays are removed as well.
+    pub fn without_provider(&self, provider: &str) -> RelayList {
+        self.filter_map_relays(|relay| {
+            if relay.provider == provider {
+                return None;
+            }
+            Some(relay.clone())
+        })
+    }
+
+    /// Returns a copy of the relay list that only contains relays with WireGuard endpoints, e.g.
+    /// for platforms that only support WireGuard. The OpenVPN endpoints and bridges of the
+    /// remaining relays are removed, and cities and countries that end up without relays are
+    /// left out.
+    pub fn wireguard_only(&self) -> RelayList {
+        self.filter_map_relays(|relay| {
+            if relay.tunnels.wireguard.is_empty() {
+                return None;
+            }
+            let mut relay = relay.clone();
+            relay.tunnels.openvpn.clear();
+            relay.bridges.clear();
+            Some(relay)
+        })
+    }
+
+    /// Returns a copy of the relay list with secrets, i.e. the Shadowsocks passwords, replaced by
+    /// [`REDACTED`], so that it can be included in logs and problem reports.
+    pub fn redacted(&self) -> RelayList {
+        self.filter_map_relays(|relay| {
+            let mut relay = relay.clone();
+            for bridge in &mut relay.bridges.shadowsocks {
+                bridge.password = REDACTED.to_string();
+            }
+            Some(relay)
+        })
+    }
+
+    /// Returns a copy of the relay list with the last octet of the IPv4 address and the last
+    /// segment of the IPv6 address of each relay set to zero. Can be combined with
+    /// [`RelayList::redacted`] to also remove secrets.
+    pub fn with_masked_addresses(&self) -> RelayList {
+        self.filter_map_relays(|relay| {
+            let mut relay = relay.clone();
+            let [a, b, c, _] = relay.ipv4_addr_in.octets();
+            relay.ipv4_addr_in = Ipv4Addr::new(a, b, c, 0);
+            relay.ipv6_addr_in = relay.ipv6_addr_in.map(|addr| {
+                let mut segments = addr.segments();
+                segments[7] = 0;
+                Ipv6Addr::from(segments)
+            });
+            Some(relay)
+        })
+    }
+
+    /// Returns a copy of the relay list with each relay replaced by the result of `f`. Relays for
+    /// which `f` returns `None` are removed, along with cities and countries that end up without
+    /// relays.
+    fn filter_map_relays(&self, f: impl Fn(&Relay) -> Option<Relay>) -> RelayList {
+        let countries = self
+            .countries
+            .iter()
+            .filter_map(|country| {
+                let cities: Vec<RelayListCity> = country
+                    .cities
+                    .iter()
+                    .filter_map(|city| {
+                        let relays: Vec<Relay> = city.relays.iter().filter_map(&f).collect();
+                        if relays.is_empty() {
+                            return None;
+                        }
+                        Some(RelayListCity {
+                            name: city.name.clone(),
+                            code: city.code.clone(),
+                            latitude: city.latitude,
+                            longitude: city.longitude,
+                            relays,
+                        })
+                    })
+                    .collect();
+                if cities.is_empty() {
+                    return None;
+                }
+                Some(RelayListCountry {
+                    name: country.name.clone(),
+                    code: country.code.clone(),
+                    cities,
+                })
+            })
+            .collect();
+
+        RelayList {
+            etag: self.etag.clone(),
+            countries,
+            fetched_at: self.fetched_at,
+        }
+    }
+
+    /// Merges `other` into this relay list. Countries and cities are matched by their codes and
+    /// relays by their hostnames. `other` is considered to be newer, so on a hostname collision
+    /// the relay from `other` is kept, including its location, but it also gets the endpoints
+    /// and obfuscators of the replaced relay that it lacks. Since the merged list does not
+    /// correspond to any list served by the API, the etag is cleared. The merged list is only
+    /// as recent as the oldest of the two lists, so it keeps the earlier fetch time, or none if
+    /// either fetch time is unknown.
+    pub fn merge(&mut self, other: RelayList) {
+        let mut replaced_relays: HashMap<String, Relay> = HashMap::new();
+        let new_hostnames: HashSet<String> =
+            other.relays().map(|relay| relay.hostname.clone()).collect();
+        for city in self
+            .countries
+            .iter_mut()
+            .flat_map(|country| country.cities.iter_mut())
+        {
+            let (replaced, kept) = std::mem::take(&mut city.relays)
+                .into_iter()
+                .partition(|relay| new_hostnames.contains(&relay.hostname));
+            city.relays = kept;
+            replaced_relays.extend(
+                replaced
+                    .into_iter()
+                    .map(|relay: Relay| (relay.hostname.clone(), relay)),
+            );
+        }
+
+        for other_country in other.countries {
+            let country_index = match self
+                .countries
+                .iter()
+                .position(|country| country.code == other_country.code)
+            {
+                Some(index) => index,
+                None => {
+                    self.countries.push(RelayListCountry {
+                        name: other_country.name.clone(),
+                        code: other_country.code.clone(),
+                        cities: vec![],
+                    });
+                    self.countries.len() - 1
+                }
+            };
+            let country = &mut self.countries[country_index];
+            country.name = other_country.name;
+
+            for other_city in other_country.cities {
+                let city_index = match country
+                    .cities
+                    .iter()
+                    .position(|city| city.code == other_city.code)
+                {
+                    Some(index) => index,
+                    None => {
+                        country.cities.push(RelayListCity {
+                            relays: vec![],
+                            ..other_city.clone()
+                        });
+                        country.cities.len() - 1
+                    }
+                };
+                let city = &mut country.cities[city_index];
+                city.name = other_city.name;
+                city.latitude = other_city.latitude;
+                city.longitude = other_city.longitude;
+
+                for mut relay in other_city.relays {
+                    if let Some(replaced_relay) = replaced_relays.remove(&relay.hostname) {
+                        relay.union_endpoints(replaced_relay);
+                    }
+                    city.relays.push(relay);
+                }
+            }
+        }
+
+        // Relays may have moved to another city
+        for country in &mut self.countries {
+            country.cities.retain(|city| !city.relays.is_empty());
+        }
+        self.countries.retain(|country| !country.cities.is_empty());
+        self.etag = None;
+        self.fetched_at = match (self.fetched_at, other.fetched_at) {
+            (Some(fetched_at), Some(other_fetched_at)) => Some(fetched_at.min(other_fetched_at)),
+            _ => None,
+        };
+    }
+
+    /// Returns an iterator over all relays in the list.
+    pub fn relays(&self) -> impl Iterator<Item = &Relay> {
+        self.countries
+            .iter()
+            .flat_map(|country| country.cities.iter())
+            .flat_map(|city| city.relays.iter())
+    }
+
+    /// Returns an iterator over all relays in the list, along with the country and city that
+    /// each relay is in.
+    pub fn relays_with_location(
+        &self,
+    ) -> impl Iterator<Item = (&RelayListCountry, &RelayListCity, &Relay)> {
+        self.countries.iter().flat_map(|country| {
+            country
+                .cities
+                .iter()
+                .flat_map(move |city| city.relays.iter().map(move |relay| (country, city, relay)))
+        })
+    }
+
+    /// Returns whether the relay with the given hostname is active, or `None` if there is no such
+    /// relay in the list.
+    pub fn is_active(&self, hostname: &str) -> Option<bool> {
+        self.relays()
+            .find(|relay| relay.hostname == hostname)
+            .map(|relay| relay.active)
+    }
+
+    /// Returns the number of relays that support each tunnel type, regardless of whether they are
+    /// active. A relay that supports both OpenVPN and WireGuard is counted for both.
+    pub fn counts_by_tunnel_type(&self) -> HashMap<TunnelType, usize> {
+        let mut counts = HashMap::new();
+        for relay in self.relays() {
+            if !relay.tunnels.openvpn.is_empty() {
+                *counts.entry(TunnelType::OpenVpn).or_insert(0) += 1;
+            }
+            if !relay.tunnels.wireguard.is_empty() {
+                *counts.entry(TunnelType::Wireguard).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    /// Returns the union of the port ranges of all WireGuard endpoints in the list, regardless of
+    /// whether the relays are active. Overlapping and adjacent ranges are coalesced, and the
+    /// ranges are sorted.
+    pub fn all_wireguard_ports(&self) -> Vec<(u16, u16)> {
+        coalesce_port_ranges(
+            self.relays()
+                .flat_map(|relay| relay.tunnels.wireguard.iter())
+                .flat_map(|endpoint| endpoint.port_ranges.iter().copied())
+                .collect(),
+        )
+    }
+
+    /// Returns the number of relays that can be used as bridges, regardless of whether they are
+    /// active.
+    pub fn bridge_count(&self) -> usize {
+        self.relays()
+            .filter(|relay| !relay.bridges.is_empty())
+            .count()
+    }
+
+    /// Returns the countries that have at least `min` cities, in the order they appear in the
+    /// list.
+    pub fn countries_with_min_cities(&self, min: usize) -> Vec<&RelayListCountry> {
+        self.countries
+            .iter()
+            .filter(|country| country.cities.len() >= min)
+            .collect()
+    }
+
+    /// Returns the hostnames of all relays covered by `location`, regardless of whether they are
+    /// active. Like when matching relays, relays that are not included in their country are
+    /// only covered by city and hostname constraints.
+    pub fn hostnames_for_location(&self, location: &LocationConstraint) -> Vec<String> {
+        self.relays_for_location(location)
+            .map(|relay| relay.hostname.clone())
+            .collect()
+    }
+
+    /// Returns whether there is an active relay covered by `location` that can be used as a
+    /// bridge.
+    pub fn has_bridge_in(&self, location: &LocationConstraint) -> bool {
+        self.relays_for_location(location)
+            .any(|relay| relay.active && !relay.bridges.is_empty())
+    }
+
+    /// Returns an iterator over all relays covered by `location`. See
+    /// [`RelayList::hostnames_for_location`].
+    fn relays_for_location<'a>(
+        &'a self,
+        location: &'a LocationConstraint,
+    ) -> impl Iterator<Item = &'a Relay> {
+        let (country_code, city_code, hostname) = match location {
+            LocationConstraint::Country(country) => (country, None, None),
+            LocationConstraint::City(country, city) => (country, Some(city), None),
+            LocationConstraint::Hostname(country, city, hostname) => {
+                (country, Some(city), Some(hostname))
+            }
+        };
+
+        self.countries
+            .iter()
+            .filter(|country| country.code == *country_code)
+            .flat_map(|country| country.cities.iter())
+            .filter(move |city| city_code.map_or(true, |code| city.code == *code))
+            .flat_map(|city| city.relays.iter())
+            .filter(move |relay| match (city_code, hostname) {
+                (None, _) => relay.include_in_country,
+                (Some(_), None) => true,
+                (Some(_), Some(hostname)) => relay.hostname == *hostname,
+            })
+    }
+
+    /// Returns up to `n` cities ordered by their distance from `origin`, closest first. Each city
+    /// is returned along with a representative relay, which is the active relay in the city with
+    /// the highest weight that is matched by `matcher`. Cities without any such relay are skipped.
+    pub fn nearest_cities(
+        &self,
+        origin: &Coordinates,
+        n: usize,
+        matcher: &impl Match<Relay>,
+    ) -> Vec<(RelayListCity, Relay)> {
+        let mut cities: Vec<(f64, RelayListCity, Relay)> = self
+            .countries
+            .iter()
+            .flat_map(|country| {
+                country.cities.iter().filter_map(move |city| {
+                    let relay = city
+                        .relays
+                        .iter()
+                        .filter(|relay| relay.active)
+                        .map(|relay| {
+                            let mut relay = relay.clone();
+                            relay.location = Some(Location {
+                                country: country.name.clone(),
+                                country_code: country.code.clone(),
+                                city: city.name.clone(),
+                                city_code: city.code.clone(),
+                                latitude: city.latitude,
+                                longitude: city.longitude,
+                            });
+                            relay
+                        })
+                        .filter(|relay| matcher.matches(relay))
+                        .fold(None, |best: Option<Relay>, relay| match best {
+                            Some(best) if best.weight >= relay.weight => Some(best),
+                            _ => Some(relay),
+                        })?;
+                    let distance = origin.distance_from(&Coordinates {
+                        latitude: city.latitude,
+                        longitude: city.longitude,
+                    });
+                    Some((distance, city.clone(), relay))
+                })
+            })
+            .collect();
+
+        cities.sort_by(|(a, ..), (b, ..)| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        cities
+            .into_iter()
+            .take(n)
+            .map(|(_distance, city, relay)| (city, relay))
+            .collect()
+    }
+
+    /// Returns the relay closest to `origin` in any country, such as for recommending a location
+    /// that may be faster than the selected one. Like [`RelayList::nearest_cities`], only active
+    /// relays that are matched by `matcher` are considered, and the relay with the highest weight
+    /// is picked within the nearest city. The matcher decides which locations are considered, so
+    /// it should not have a location constraint.
+    pub fn globally_nearest(
+        &self,
+        origin: &Coordinates,
+        matcher: &impl Match<Relay>,
+    ) -> Option<Relay> {
+        self.nearest_cities(origin, 1, matcher)
+            .pop()
+            .map(|(_city, relay)| relay)
+    }
+
+    /// Returns the relay closest to the geographic midpoint of the cities in the country with the
+    /// given code. Like [`RelayList::nearest_cities`], only active relays that are matched by
+    /// `matcher` are considered, and the relay with the highest weight is picked within a city.
+    pub fn central_relay_in_country(
+        &self,
+        code: &CountryCode,
+        matcher: &impl Match<Relay>,
+    ) -> Option<Relay> {
+        let country = self
+            .countries
+            .iter()
+            .find(|country| country.code == *code)?;
+        if country.cities.is_empty() {
+            return None;
+        }
+        let centroid = Coordinates::midpoint_inner(country.cities.iter().map(|city| Coordinates {
+            latitude: city.latitude,
+            longitude: city.longitude,
+        }));
+
+        let country_list = RelayList {
+            etag: None,
+            countries: vec![country.clone()],
+            fetched_at: self.fetched_at,
+        };
+        country_list
+            .nearest_cities(&centroid, 1, matcher)
+            .pop()
+            .map(|(_city, relay)| relay)
+    }
+
+    /// Reconstructs the endpoint of a previously selected relay, using the exact port and
+    /// transport protocol of the endpoint. Returns `None` if the relay no longer exists or no
+    /// longer has a matching endpoint. The endpoint always uses the IPv4 address of the relay,
+    /// and WireGuard endpoints are always single-hop.
+    pub fn rebuild_endpoint(
+        &self,
+        hostname: &str,
+        port: u16,
+        protocol: TransportProtocol,
+        tunnel_type: TunnelType,
+    ) -> Option<MullvadEndpoint> {
+        let relay = self.relays().find(|relay| relay.hostname == hostname)?;
+        let host = IpAddr::from(relay.ipv4_addr_in);
+        match tunnel_type {
+            TunnelType::OpenVpn => relay
+                .tunnels
+                .openvpn
+                .iter()
+                .find(|endpoint| endpoint.port == port && endpoint.protocol == protocol)
+                .map(|endpoint| endpoint.into_mullvad_endpoint(host)),
+            TunnelType::Wireguard if protocol == TransportProtocol::Udp => relay
+                .tunnels
+                .wireguard
+                .iter()
+                .find(|endpoint| {
+                    endpoint
+                        .port_ranges
+                        .iter()
+                        .any(|(start, end)| (*start..=*end).contains(&port))
+                })
+                .map(|endpoint| endpoint.clone().into_mullvad_endpoint(host, port)),
+            TunnelType::Wireguard => None,
+        }
+    }
+
+    /// Compares the relays in `self` to the relays in a newer relay list, `other`. Relays are
+    /// identified by their hostname.
+    pub fn diff(&self, other: &RelayList) -> RelayListDiff {
+        let old_relays: HashMap<&str, bool> = self
+            .relays()
+            .map(|relay| (relay.hostname.as_str(), relay.active))
+            .collect();
+        let new_relays: HashMap<&str, bool> = other
+            .relays()
+            .map(|relay| (relay.hostname.as_str(), relay.active))
+            .collect();
+
+        let mut diff = RelayListDiff::default();
+        for relay in other.relays() {
+            match old_relays.get(relay.hostname.as_str()) {
+                None => diff.added.push(relay.hostname.clone()),
+                Some(false) if relay.active => diff.activated.push(relay.hostname.clone()),
+                Some(true) if !relay.active => diff.deactivated.push(relay.hostname.clone()),
+                Some(_) => (),
+            }
+        }
+        diff.removed = self
+            .relays()
+            .filter(|relay| !new_relays.contains_key(relay.hostname.as_str()))
+            .map(|relay| relay.hostname.clone())
+            .collect();
+        diff
+    }
+
+    /// Reads a relay list in the JSON format used by the relay cache from `path`. The file is
+    /// deserialized as it is read. Malformed JSON results in an error of kind
+    /// [`io::ErrorKind::InvalidData`], or [`io::ErrorKind::UnexpectedEof`] if the file is
+    /// truncated, describing where parsing failed.
+    pub fn from_file(path: &Path) -> io::Result<RelayList> {
+        let file = File::open(path)?;
+        serde_json::from_reader(BufReader::new(file)).map_err(io::Error::from)
+    }
+
+    /// Writes the relay list to `path` in the JSON format read by [`RelayList::from_file`].
+    pub fn to_file(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()
+    }
+
+    /// Returns the issues found in the endpoint data of active relays that make them fully or
+    /// partially unusable. Inactive relays are never selected, so they are not audited.
+    pub fn audit(&self) -> Vec<RelayAuditIssue> {
+        let mut issues = vec![];
+        for relay in self.relays().filter(|relay| relay.active) {
+            let hostname = || relay.hostname.clone();
+            if relay.tunnels.is_empty() && relay.bridges.is_empty() {
+                issues.push(RelayAuditIssue::NoEndpoints(hostname()));
+            }
+            for endpoint in &relay.tunnels.wireguard {
+                if endpoint.port_ranges.is_empty() {
+                    issues.push(RelayAuditIssue::EmptyWireguardPortRanges(hostname()));
+                }
+                if endpoint.gateways.is_empty()
+                    || endpoint
+                        .gateways
+                        .iter()
+                        .any(|(ipv4_gateway, _)| ipv4_gateway.is_unspecified())
+                {
+                    issues.push(RelayAuditIssue::UnspecifiedWireguardGateway(hostname()));
+                }
+            }
+            if relay
+                .tunnels
+                .openvpn
+                .iter()
+                .any(|endpoint| endpoint.port == 0)
+            {
+                issues.push(RelayAuditIssue::InvalidOpenVpnPort(hostname()));
+            }
+        }
+        issues
+    }
+
+    /// Serializes the relay list using `postcard`. The result is a lot more compact than the
+    /// JSON representation, which makes it better suited for IPC.
+    #[cfg(feature = "postcard")]
+    pub fn to_bytes(&self) -> Result<Vec<u8>, postcard::Error> {
+        postcard::to_allocvec(&BinaryRelayList::from(self.clone()))
+    }
+
+    /// Deserializes a relay list that was serialized using [`RelayList::to_bytes`].
+    #[cfg(feature = "postcard")]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, postcard::Error> {
+        postcard::from_bytes::<BinaryRelayList>(bytes).map(RelayList::from)
+    }
+
+    /// Returns a JSON Schema describing the serialized relay list.
+    #[cfg(feature = "schema")]
+    pub fn json_schema() -> schemars::schema::RootSchema {
+        schemars::schema_for!(RelayList)
+    }
+
+    /// Generates a synthetic relay list for use in tests, with `countries` countries that each
+    /// have `cities_per` cities with `relays_per` relays. The list is the same for the same
+    /// arguments. Every other relay in a city is a WireGuard relay, starting with the first one,
+    /// and the others are OpenVPN relays. All relays are active, and their locations are set.
+    #[cfg(any(test, feature = "mock"))]
+    pub fn mock(countries: usize, cities_per: usize, relays_per: usize) -> RelayList {
+        // Two or three letter codes, like real country and city codes
+        let code = |prefix: &str, index: usize| {
+            let letter = |value: usize| char::from(b'a' + (value % 26) as u8);
+            format!("{}{}{}", prefix, letter(index / 26), letter(index))
+        };
+
+        let mut relay_index = 0;
+        let countries = (0..countries)
+            .map(|country_index| {
+                let country_code = code("", country_index);
+                let country_name = format!("Country {}", country_index);
+                let cities = (0..cities_per)
+                    .map(|city_index| {
+                        let location = Location {
+                            country: country_name.clone(),
+                            country_code: country_code.clone(),
+                            city: format!("City {}", city_index),
+                            city_code: code("c", city_index),
+                            latitude: ((country_index * 7 + city_index * 3) % 120) as f64 - 60.0,
+                            longitude: ((country_index * 37 + city_index * 11) % 360) as f64
+                                - 180.0,
+                        };
+                        let relays = (0..relays_per)
+                            .map(|index| {
+                                relay_index += 1;
+                                Relay::mock(&location, index, relay_index - 1)
+                            })
+                            .collect();
+                        RelayListCity {
+                            name: location.city,
+                            code: location.city_code,
+                            latitude: location.latitude,
+                            longitude: location.longitude,
+                            relays,
+                        }
+                    })
+                    .collect();
+                RelayListCountry {
+                    name: country_name,
+                    code: country_code,
+                    cities,
+                }
+            })
+            .collect();
+
+        RelayList {
+            etag: None,
+            countries,
+            fetched_at: None,
+        }
+    }
+}
+
+impl Relay {
+    /// Generates the relay at position `index` in a city of [`RelayList::mock`]. `relay_index`
+    /// is the position of the relay in the whole list, and determines its addresses, public key
+    /// and provider.
+    #[cfg(any(test, feature = "mock"))]
+    fn mock(location: &Location, index: usize, relay_index: u32) -> Relay {
+        const PROVIDERS: [&str; 3] = ["31173", "M247", "xtom"];
+
+        let is_wireguard = index % 2 == 0;
+        let tunnels = if is_wireguard {
+            let mut public_key = [0u8; 32];
+            public_key[..4].copy_from_slice(&relay_index.to_be_bytes());
+            RelayTunnels {
+                openvpn: vec![],
+                wireguard: vec![WireguardEndpointData {
+                    port_ranges: vec![(53, 53), (4000, 33433)],
+                    gateways: vec![(
+                        Ipv4Addr::new(10, 64, 0, 1),
+                        "fc00:bbbb:bbbb:bb01::1".parse().unwrap(),
+                    )],
+                    public_key: wireguard::PublicKey::from(public_key),
+                    daita: false,
+                    feature_version: 0,
+                }],
+            }
+        } else {
+            RelayTunnels {
+                openvpn: vec![
+                    OpenVpnEndpointData {
+                        port: 1194,
+                        protocol: TransportProtocol::Udp,
+                    },
+                    OpenVpnEndpointData {
+                        port: 443,
+                        protocol: TransportProtocol::Tcp,
+                    },
+                ],
+                wireguard: vec![],
+            }
+        };
+
+        let [_, a, b, c] = relay_index.to_be_bytes();
+        Relay {
+            hostname: format!(
+                "{}-{}-{}-{:03}",
+                location.country_code,
+                location.city_code,
+                if is_wireguard { "wg" } else { "ovpn" },
+                index + 1
+            ),
+            ipv4_addr_in: Ipv4Addr::new(10, a, b, c),
+            ipv6_addr_in: Some(Ipv6Addr::new(
+                0xfd00,
+                0,
+                0,
+                0,
+                0,
+                0,
+                (relay_index >> 16) as u16,
+                relay_index as u16,
+            )),
+            include_in_country: true,
+            active: true,
+            owned: relay_index % 2 == 0,
+            provider: PROVIDERS[relay_index as usize % PROVIDERS.len()].to_string(),
+            weight: 100,
+            tags: vec![],
+            bandwidth_mbps: None,
+            asn: None,
+            channel: None,
+            tunnels,
+            bridges: RelayBridges::default(),
+            obfuscators: RelayObfuscators::default(),
+            location: Some(location.clone()),
+            congestion: None,
+            rtt: None,
+            added_at: None,
+        }
+    }
+}
+
+/// Hostnames of relays that differ between two [`RelayList`]s. Returned by [`RelayList::diff`].
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct RelayListDiff {
+    /// Relays that only exist in the new list.
+    pub added: Vec<String>,
+    /// Relays that only exist in the old list.
+    pub removed: Vec<String>,
+    /// Relays that are active in the new list but were inactive in the old list.
+    pub activated: Vec<String>,
+    /// Relays that are inactive in the new list but were active in the old list.
+    pub deactivated: Vec<String>,
+}
+
+impl RelayListDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.activated.is_empty()
+            && self.deactivated.is_empty()
+    }
+}
+
+/// An issue with an active relay found by [`RelayList::audit`]. Contains the hostname of the
+/// relay.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RelayAuditIssue {
+    /// The relay has no tunnel endpoints and is not a bridge.
+    NoEndpoints(String),
+    /// A WireGuard endpoint of the relay has no port ranges.
+    EmptyWireguardPortRanges(String),
+    /// A WireGuard endpoint of the relay has an unspecified IPv4 gateway.
+    UnspecifiedWireguardGateway(String),
+    /// An OpenVPN endpoint of the relay uses port 0.
+    InvalidOpenVpnPort(String),
+}
+
+impl fmt::Display for RelayAuditIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            RelayAuditIssue::NoEndpoints(hostname) => write!(f, "{} has no endpoints", hostname),
+            RelayAuditIssue::EmptyWireguardPortRanges(hostname) => {
+                write!(f, "{} has a WireGuard endpoint without ports", hostname)
+            }
+            RelayAuditIssue::UnspecifiedWireguardGateway(hostname) => {
+                write!(f, "{} has a WireGuard endpoint without a gateway", hostname)
+            }
+            RelayAuditIssue::InvalidOpenVpnPort(hostname) => {
+                write!(f, "{} has an OpenVPN endpoint using port 0", hostname)
+            }
         }
     }
 }
 
 /// A list of [`RelayListCity`]s within a country. Used by [`RelayList`].
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(target_os = "android", derive(IntoJava))]
 #[cfg_attr(target_os = "android", jnix(package = "net.mullvad.mullvadvpn.model"))]
 pub struct RelayListCountry {
@@ -46,6 +794,7 @@ pub struct RelayListCountry {
 
 /// A list of [`Relay`]s within a city. Used by [`RelayListCountry`].
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(target_os = "android", derive(IntoJava))]
 #[cfg_attr(target_os = "android", jnix(package = "net.mullvad.mullvadvpn.model"))]
 pub struct RelayListCity {
@@ -58,9 +807,45 @@ pub struct RelayListCity {
     pub relays: Vec<Relay>,
 }
 
+impl RelayListCity {
+    /// Checks that the latitude of the city is within [-90, 90] degrees and that its longitude
+    /// is within [-180, 180] degrees. Distances computed from coordinates outside these ranges
+    /// are meaningless.
+    pub fn validate(&self) -> Result<(), InvalidCoordinates> {
+        if (-90.0..=90.0).contains(&self.latitude) && (-180.0..=180.0).contains(&self.longitude) {
+            Ok(())
+        } else {
+            Err(InvalidCoordinates {
+                latitude: self.latitude,
+                longitude: self.longitude,
+            })
+        }
+    }
+}
+
+/// Coordinates that are out of range, or not numbers. Returned by [`RelayListCity::validate`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InvalidCoordinates {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl fmt::Display for InvalidCoordinates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "Invalid coordinates: latitude {}, longitude {}",
+            self.latitude, self.longitude
+        )
+    }
+}
+
+impl std::error::Error for InvalidCoordinates {}
+
 /// Stores information for a relay returned by the API at `v1/relays` using
 /// `mullvad_api::RelayListProxy`.
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(target_os = "android", derive(IntoJava))]
 #[cfg_attr(target_os = "android", jnix(package = "net.mullvad.mullvadvpn.model"))]
 pub struct Relay {
@@ -78,6 +863,23 @@ pub struct Relay {
     pub provider: String,
     #[cfg_attr(target_os = "android", jnix(skip))]
     pub weight: u64,
+    /// Free-form tags assigned to the relay, such as `experimental`.
+    #[serde(default)]
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub tags: Vec<String>,
+    /// Advertised bandwidth capacity of the relay in Mbps, if known.
+    #[serde(default)]
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub bandwidth_mbps: Option<u32>,
+    /// Number of the autonomous system that the relay is hosted in, if known.
+    #[serde(default)]
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub asn: Option<u32>,
+    /// Release channel of the relay, such as [`STABLE_CHANNEL`]. Relays without a channel are
+    /// considered stable.
+    #[serde(default)]
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub channel: Option<String>,
     #[serde(skip_serializing_if = "RelayTunnels::is_empty", default)]
     pub tunnels: RelayTunnels,
     #[serde(skip_serializing_if = "RelayBridges::is_empty", default)]
@@ -88,10 +890,429 @@ pub struct Relay {
     pub obfuscators: RelayObfuscators,
     #[cfg_attr(target_os = "android", jnix(skip))]
     pub location: Option<Location>,
+    /// Congestion score of the relay, if known. This is not part of the relay list returned by
+    /// the API but is annotated locally.
+    #[serde(skip)]
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub congestion: Option<Congestion>,
+    /// Measured round-trip time to the relay, if known. Like `congestion`, this is annotated
+    /// locally.
+    #[serde(skip)]
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub rtt: Option<Duration>,
+    /// When the relay was introduced, if known.
+    #[serde(default)]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<String>"))]
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub added_at: Option<DateTime<Utc>>,
+}
+
+/// The form of [`RelayList`] serialized by [`RelayList::to_bytes`]. `postcard` is not
+/// self-describing, so unlike the JSON representation, no fields may be skipped when they are
+/// empty.
+#[cfg(feature = "postcard")]
+#[derive(Deserialize, Serialize)]
+struct BinaryRelayList {
+    etag: Option<String>,
+    countries: Vec<BinaryRelayListCountry>,
+    fetched_at: Option<SystemTime>,
+}
+
+#[cfg(feature = "postcard")]
+#[derive(Deserialize, Serialize)]
+struct BinaryRelayListCountry {
+    name: String,
+    code: CountryCode,
+    cities: Vec<BinaryRelayListCity>,
+}
+
+#[cfg(feature = "postcard")]
+#[derive(Deserialize, Serialize)]
+struct BinaryRelayListCity {
+    name: String,
+    code: CityCode,
+    latitude: f64,
+    longitude: f64,
+    relays: Vec<BinaryRelay>,
+}
+
+/// The form of [`Relay`] serialized by [`RelayList::to_bytes`]. Locally annotated data, such as
+/// the congestion, is left out just like in the JSON representation.
+#[cfg(feature = "postcard")]
+#[derive(Deserialize, Serialize)]
+struct BinaryRelay {
+    hostname: String,
+    ipv4_addr_in: Ipv4Addr,
+    ipv6_addr_in: Option<Ipv6Addr>,
+    include_in_country: bool,
+    active: bool,
+    owned: bool,
+    provider: String,
+    weight: u64,
+    tags: Vec<String>,
+    bandwidth_mbps: Option<u32>,
+    asn: Option<u32>,
+    channel: Option<String>,
+    tunnels: RelayTunnels,
+    bridges: RelayBridges,
+    obfuscators: RelayObfuscators,
+    location: Option<Location>,
+    added_at: Option<DateTime<Utc>>,
+}
+
+#[cfg(feature = "postcard")]
+impl From<RelayList> for BinaryRelayList {
+    fn from(list: RelayList) -> Self {
+        BinaryRelayList {
+            etag: list.etag,
+            countries: list
+                .countries
+                .into_iter()
+                .map(|country| BinaryRelayListCountry {
+                    name: country.name,
+                    code: country.code,
+                    cities: country
+                        .cities
+                        .into_iter()
+                        .map(|city| BinaryRelayListCity {
+                            name: city.name,
+                            code: city.code,
+                            latitude: city.latitude,
+                            longitude: city.longitude,
+                            relays: city.relays.into_iter().map(BinaryRelay::from).collect(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+            fetched_at: list.fetched_at,
+        }
+    }
+}
+
+#[cfg(feature = "postcard")]
+impl From<BinaryRelayList> for RelayList {
+    fn from(list: BinaryRelayList) -> Self {
+        RelayList {
+            etag: list.etag,
+            countries: list
+                .countries
+                .into_iter()
+                .map(|country| RelayListCountry {
+                    name: country.name,
+                    code: country.code,
+                    cities: country
+                        .cities
+                        .into_iter()
+                        .map(|city| RelayListCity {
+                            name: city.name,
+                            code: city.code,
+                            latitude: city.latitude,
+                            longitude: city.longitude,
+                            relays: city.relays.into_iter().map(Relay::from).collect(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+            fetched_at: list.fetched_at,
+        }
+    }
+}
+
+#[cfg(feature = "postcard")]
+impl From<Relay> for BinaryRelay {
+    fn from(relay: Relay) -> Self {
+        BinaryRelay {
+            hostname: relay.hostname,
+            ipv4_addr_in: relay.ipv4_addr_in,
+            ipv6_addr_in: relay.ipv6_addr_in,
+            include_in_country: relay.include_in_country,
+            active: relay.active,
+            owned: relay.owned,
+            provider: relay.provider,
+            weight: relay.weight,
+            tags: relay.tags,
+            bandwidth_mbps: relay.bandwidth_mbps,
+            asn: relay.asn,
+            channel: relay.channel,
+            tunnels: relay.tunnels,
+            bridges: relay.bridges,
+            obfuscators: relay.obfuscators,
+            location: relay.location,
+            added_at: relay.added_at,
+        }
+    }
+}
+
+#[cfg(feature = "postcard")]
+impl From<BinaryRelay> for Relay {
+    fn from(relay: BinaryRelay) -> Self {
+        Relay {
+            hostname: relay.hostname,
+            ipv4_addr_in: relay.ipv4_addr_in,
+            ipv6_addr_in: relay.ipv6_addr_in,
+            include_in_country: relay.include_in_country,
+            active: relay.active,
+            owned: relay.owned,
+            provider: relay.provider,
+            weight: relay.weight,
+            tags: relay.tags,
+            bandwidth_mbps: relay.bandwidth_mbps,
+            asn: relay.asn,
+            channel: relay.channel,
+            tunnels: relay.tunnels,
+            bridges: relay.bridges,
+            obfuscators: relay.obfuscators,
+            location: relay.location,
+            congestion: None,
+            rtt: None,
+            added_at: relay.added_at,
+        }
+    }
+}
+
+impl Relay {
+    /// Returns whether the relay is in the stable release channel. Relays without a channel are
+    /// considered stable.
+    pub fn is_stable(&self) -> bool {
+        self.channel
+            .as_deref()
+            .map(|channel| channel == STABLE_CHANNEL)
+            .unwrap_or(true)
+    }
+
+    /// Returns the distinct transport protocols that can be used to connect to the relay, with
+    /// UDP before TCP. WireGuard endpoints are reached over UDP, or over TCP if the relay has a
+    /// udp2tcp obfuscator, and OpenVPN endpoints over the protocols of their ports.
+    pub fn supported_transport_protocols(&self) -> Vec<TransportProtocol> {
+        let has_wireguard = !self.tunnels.wireguard.is_empty();
+        let supports = |protocol| {
+            let wireguard = has_wireguard
+                && match protocol {
+                    TransportProtocol::Udp => true,
+                    TransportProtocol::Tcp => !self.obfuscators.udp2tcp.is_empty(),
+                };
+            wireguard
+                || self
+                    .tunnels
+                    .openvpn
+                    .iter()
+                    .any(|endpoint| endpoint.protocol == protocol)
+        };
+        [TransportProtocol::Udp, TransportProtocol::Tcp]
+            .into_iter()
+            .filter(|protocol| supports(*protocol))
+            .collect()
+    }
+
+    /// Classifies the round-trip time of the relay using the default [`LatencyThresholds`].
+    /// Returns `None` if the round-trip time is unknown.
+    pub fn latency_bucket(&self) -> Option<LatencyBucket> {
+        self.latency_bucket_with(&LatencyThresholds::default())
+    }
+
+    /// Classifies the round-trip time of the relay using `thresholds`. Returns `None` if the
+    /// round-trip time is unknown.
+    pub fn latency_bucket_with(&self, thresholds: &LatencyThresholds) -> Option<LatencyBucket> {
+        let rtt = self.rtt?;
+        Some(if rtt <= thresholds.good {
+            LatencyBucket::Good
+        } else if rtt <= thresholds.medium {
+            LatencyBucket::Medium
+        } else {
+            LatencyBucket::Poor
+        })
+    }
+
+    /// Returns every concrete way of connecting to the relay: each address of the relay combined
+    /// with each OpenVPN port and protocol, and with each port in the WireGuard port ranges.
+    /// WireGuard port ranges can span thousands of ports, so ranges with more than
+    /// [`MAX_PORTS_PER_WIREGUARD_RANGE`] ports are sampled at evenly spaced ports, always including
+    /// the first and last port of the range. Duplicates are removed.
+    pub fn connection_options(&self) -> Vec<ConnectionOption> {
+        let addresses: Vec<IpAddr> = std::iter::once(IpAddr::from(self.ipv4_addr_in))
+            .chain(self.ipv6_addr_in.map(IpAddr::from))
+            .collect();
+
+        let openvpn_ports = self
+            .tunnels
+            .openvpn
+            .iter()
+            .map(|endpoint| (TunnelType::OpenVpn, endpoint.port, endpoint.protocol));
+        let wireguard_ports = self
+            .tunnels
+            .wireguard
+            .iter()
+            .flat_map(|endpoint| endpoint.port_ranges.iter())
+            .flat_map(|&(first, last)| sample_port_range(first, last))
+            .map(|port| (TunnelType::Wireguard, port, TransportProtocol::Udp));
+
+        let mut seen = HashSet::new();
+        openvpn_ports
+            .chain(wireguard_ports)
+            .flat_map(|(tunnel_type, port, protocol)| {
+                addresses.iter().map(move |address| ConnectionOption {
+                    tunnel_type,
+                    endpoint: Endpoint::new(*address, port, protocol),
+                })
+            })
+            .filter(|option| seen.insert(*option))
+            .collect()
+    }
+
+    /// Adds the tunnel endpoints, bridges and obfuscators of `other` that this relay does not
+    /// already have.
+    fn union_endpoints(&mut self, other: Relay) {
+        fn union<T: PartialEq>(items: &mut Vec<T>, other_items: Vec<T>) {
+            for item in other_items {
+                if !items.contains(&item) {
+                    items.push(item);
+                }
+            }
+        }
+        union(&mut self.tunnels.openvpn, other.tunnels.openvpn);
+        union(&mut self.tunnels.wireguard, other.tunnels.wireguard);
+        union(&mut self.bridges.shadowsocks, other.bridges.shadowsocks);
+        union(&mut self.obfuscators.udp2tcp, other.obfuscators.udp2tcp);
+    }
+
+    /// Orders relays by descending weight, using the hostname as a tiebreaker. This is the order
+    /// in which relays are preferred during selection, as opposed to the [`Ord`] implementation
+    /// of `Relay`, which only considers the hostname.
+    pub fn by_weight(a: &Relay, b: &Relay) -> Ordering {
+        b.weight
+            .cmp(&a.weight)
+            .then_with(|| a.hostname.cmp(&b.hostname))
+    }
+}
+
+/// Relays are identified by their hostname, so two relays are equal if their hostnames are.
+impl PartialEq for Relay {
+    fn eq(&self, other: &Self) -> bool {
+        self.hostname == other.hostname
+    }
+}
+
+impl Eq for Relay {}
+
+/// Relays are ordered by their hostname, which is consistent with their [`Eq`] implementation.
+/// See [`Relay::by_weight`] for ordering relays by preference.
+impl PartialOrd for Relay {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Relay {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.hostname.cmp(&other.hostname)
+    }
+}
+
+/// Sorts the inclusive port ranges and merges the ones that overlap or are adjacent.
+fn coalesce_port_ranges(mut port_ranges: Vec<(u16, u16)>) -> Vec<(u16, u16)> {
+    port_ranges.sort_unstable();
+    let mut coalesced: Vec<(u16, u16)> = Vec::with_capacity(port_ranges.len());
+    for range in port_ranges {
+        match coalesced.last_mut() {
+            Some(last) if range.0 <= last.1.saturating_add(1) => last.1 = last.1.max(range.1),
+            _ => coalesced.push(range),
+        }
+    }
+    coalesced
+}
+
+/// The maximum number of ports that [`Relay::connection_options`] enumerates for a single
+/// WireGuard port range.
+pub const MAX_PORTS_PER_WIREGUARD_RANGE: u16 = 8;
+
+/// Returns the ports in the inclusive range, sampled at evenly spaced ports if there are more
+/// than [`MAX_PORTS_PER_WIREGUARD_RANGE`].
+fn sample_port_range(first: u16, last: u16) -> Vec<u16> {
+    if last < first {
+        return vec![];
+    }
+    let span = u32::from(last - first);
+    if span < u32::from(MAX_PORTS_PER_WIREGUARD_RANGE) {
+        return (first..=last).collect();
+    }
+    let steps = u32::from(MAX_PORTS_PER_WIREGUARD_RANGE - 1);
+    (0..=steps)
+        .map(|step| first + (span * step / steps) as u16)
+        .collect()
+}
+
+/// A single way of connecting to a [`Relay`], as returned by [`Relay::connection_options`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionOption {
+    pub tunnel_type: TunnelType,
+    /// The address, port and transport protocol to connect to.
+    pub endpoint: Endpoint,
+}
+
+/// Measures how diverse a set of relays is, as returned by [`diversity_score`]. Scores compare
+/// by the number of distinct countries first, followed by cities, providers and autonomous
+/// systems, so a greater score means a more diverse set.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DiversityScore {
+    pub countries: usize,
+    pub cities: usize,
+    pub providers: usize,
+    pub asns: usize,
+}
+
+/// Counts the distinct countries, cities, providers and autonomous systems of `relays`. Relays
+/// without a known location or ASN do not count towards those.
+pub fn diversity_score(relays: &[Relay]) -> DiversityScore {
+    let locations = || relays.iter().filter_map(|relay| relay.location.as_ref());
+    let countries: HashSet<&str> = locations()
+        .map(|location| location.country_code.as_str())
+        .collect();
+    let cities: HashSet<(&str, &str)> = locations()
+        .map(|location| (location.country_code.as_str(), location.city_code.as_str()))
+        .collect();
+    let providers: HashSet<&str> = relays.iter().map(|relay| relay.provider.as_str()).collect();
+    let asns: HashSet<u32> = relays.iter().filter_map(|relay| relay.asn).collect();
+
+    DiversityScore {
+        countries: countries.len(),
+        cities: cities.len(),
+        providers: providers.len(),
+        asns: asns.len(),
+    }
+}
+
+/// A congestion score for a [`Relay`], where a higher value means a more congested relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Congestion(pub u8);
+
+/// Classification of the round-trip time of a [`Relay`], e.g. for quality indicators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatencyBucket {
+    Good,
+    Medium,
+    Poor,
+}
+
+/// Upper bounds, inclusive, of the round-trip times in each [`LatencyBucket`]. Round-trip times
+/// above `medium` are [`LatencyBucket::Poor`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyThresholds {
+    pub good: Duration,
+    pub medium: Duration,
+}
+
+impl Default for LatencyThresholds {
+    fn default() -> Self {
+        LatencyThresholds {
+            good: Duration::from_millis(100),
+            medium: Duration::from_millis(250),
+        }
+    }
 }
 
 /// Provides protocol-specific information about a [`Relay`].
 #[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(default)]
 #[cfg_attr(target_os = "android", derive(IntoJava))]
 #[cfg_attr(target_os = "android", jnix(package = "net.mullvad.mullvadvpn.model"))]
@@ -114,8 +1335,13 @@ impl RelayTunnels {
 
 /// Data needed to connect to an OpenVPN endpoint at a [`Relay`].
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct OpenVpnEndpointData {
     pub port: u16,
+    #[cfg_attr(
+        feature = "schema",
+        schemars(schema_with = "transport_protocol_schema")
+    )]
     pub protocol: TransportProtocol,
 }
 
@@ -123,6 +1349,25 @@ impl OpenVpnEndpointData {
     pub fn into_mullvad_endpoint(self, host: IpAddr) -> MullvadEndpoint {
         MullvadEndpoint::OpenVpn(Endpoint::new(host, self.port, self.protocol))
     }
+
+    /// Groups the ports of `endpoints` by transport protocol. The ports of each protocol are
+    /// sorted and deduplicated.
+    pub fn grouped_by_protocol(
+        endpoints: &[OpenVpnEndpointData],
+    ) -> HashMap<TransportProtocol, Vec<u16>> {
+        let mut groups: HashMap<TransportProtocol, Vec<u16>> = HashMap::new();
+        for endpoint in endpoints {
+            groups
+                .entry(endpoint.protocol)
+                .or_default()
+                .push(endpoint.port);
+        }
+        for ports in groups.values_mut() {
+            ports.sort_unstable();
+            ports.dedup();
+        }
+        groups
+    }
 }
 
 impl fmt::Display for OpenVpnEndpointData {
@@ -132,27 +1377,207 @@ impl fmt::Display for OpenVpnEndpointData {
 }
 
 /// Data needed to connect to a WireGuard endpoint at a [`Relay`].
-#[derive(Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Debug)]
+#[derive(Clone, Eq, Deserialize, Serialize, Debug)]
+#[serde(
+    from = "SerializedWireguardEndpointData",
+    into = "SerializedWireguardEndpointData"
+)]
 #[cfg_attr(target_os = "android", derive(IntoJava))]
 #[cfg_attr(target_os = "android", jnix(package = "net.mullvad.mullvadvpn.model"))]
 #[cfg_attr(target_os = "android", jnix(skip_all))]
 pub struct WireguardEndpointData {
     /// Port to connect to
     pub port_ranges: Vec<(u16, u16)>,
-    /// Gateways to be used with the tunnel
-    pub ipv4_gateway: Ipv4Addr,
-    pub ipv6_gateway: Ipv6Addr,
+    /// Pairs of IPv4 and IPv6 gateways that may be used with the tunnel. The first pair is the
+    /// primary one.
+    pub gateways: Vec<(Ipv4Addr, Ipv6Addr)>,
     /// The peer's public key
     pub public_key: wireguard::PublicKey,
+    /// Whether the relay supports DAITA (Defense Against AI-guided Traffic Analysis)
+    #[serde(default)]
+    pub daita: bool,
+    /// The version of the WireGuard features supported by the relay. Relays that do not
+    /// advertise a version are assumed to be at version 0.
+    #[serde(default)]
+    pub feature_version: u32,
+}
+
+/// The serialized form of [`WireguardEndpointData`]. The primary gateways are stored in
+/// `ipv4_gateway` and `ipv6_gateway`, as they were before relays could have multiple gateways,
+/// so that relay lists remain readable in both directions. `gateways` is empty in relay lists
+/// stored before it was added.
+#[derive(Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "schema", schemars(rename = "WireguardEndpointData"))]
+struct SerializedWireguardEndpointData {
+    port_ranges: Vec<(u16, u16)>,
+    ipv4_gateway: Ipv4Addr,
+    ipv6_gateway: Ipv6Addr,
+    #[serde(default)]
+    gateways: Vec<(Ipv4Addr, Ipv6Addr)>,
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
+    public_key: wireguard::PublicKey,
+    #[serde(default)]
+    daita: bool,
+    #[serde(default)]
+    feature_version: u32,
+}
+
+impl From<SerializedWireguardEndpointData> for WireguardEndpointData {
+    fn from(data: SerializedWireguardEndpointData) -> Self {
+        let gateways = if data.gateways.is_empty() {
+            vec![(data.ipv4_gateway, data.ipv6_gateway)]
+        } else {
+            data.gateways
+        };
+        WireguardEndpointData {
+            port_ranges: data.port_ranges,
+            gateways,
+            public_key: data.public_key,
+            daita: data.daita,
+            feature_version: data.feature_version,
+        }
+    }
+}
+
+impl From<WireguardEndpointData> for SerializedWireguardEndpointData {
+    fn from(data: WireguardEndpointData) -> Self {
+        SerializedWireguardEndpointData {
+            ipv4_gateway: data.ipv4_gateway(),
+            ipv6_gateway: data.ipv6_gateway(),
+            port_ranges: data.port_ranges,
+            gateways: data.gateways,
+            public_key: data.public_key,
+            daita: data.daita,
+            feature_version: data.feature_version,
+        }
+    }
+}
+
+#[cfg(feature = "schema")]
+impl schemars::JsonSchema for WireguardEndpointData {
+    fn schema_name() -> String {
+        SerializedWireguardEndpointData::schema_name()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        SerializedWireguardEndpointData::json_schema(gen)
+    }
+}
+
+impl WireguardEndpointData {
+    /// The order of the port ranges is not significant, so they are compared and hashed in
+    /// sorted order.
+    fn sorted_port_ranges(&self) -> Vec<(u16, u16)> {
+        let mut port_ranges = self.port_ranges.clone();
+        port_ranges.sort_unstable();
+        port_ranges
+    }
+
+    /// Returns the primary IPv4 gateway, or an unspecified address if there are no gateways.
+    pub fn ipv4_gateway(&self) -> Ipv4Addr {
+        self.gateways
+            .first()
+            .map(|(ipv4_gateway, _)| *ipv4_gateway)
+            .unwrap_or(Ipv4Addr::UNSPECIFIED)
+    }
+
+    /// Returns the primary IPv6 gateway, or an unspecified address if there are no gateways.
+    pub fn ipv6_gateway(&self) -> Ipv6Addr {
+        self.gateways
+            .first()
+            .map(|(_, ipv6_gateway)| *ipv6_gateway)
+            .unwrap_or(Ipv6Addr::UNSPECIFIED)
+    }
+
+    /// Returns the gateways to use when connecting to `host`. This is the first pair of gateways,
+    /// except when connecting over IPv6, in which case the first pair with an IPv6 gateway is
+    /// used. Returns `None` if there is no such pair.
+    pub fn gateways_for(&self, host: IpAddr) -> Option<(Ipv4Addr, Ipv6Addr)> {
+        match host {
+            IpAddr::V4(_) => self.gateways.first().copied(),
+            IpAddr::V6(_) => self
+                .gateways
+                .iter()
+                .find(|(_, ipv6_gateway)| !ipv6_gateway.is_unspecified())
+                .copied(),
+        }
+    }
+
+    /// Returns a copy of the endpoint data whose port ranges only contain the ports that are also
+    /// in `allowed`. Both `self.port_ranges` and `allowed` are inclusive ranges. This can be used
+    /// to remove ports that are known to be blocked before selecting an endpoint.
+    pub fn intersect_ports(&self, allowed: &[(u16, u16)]) -> WireguardEndpointData {
+        let port_ranges: Vec<(u16, u16)> = self
+            .port_ranges
+            .iter()
+            .flat_map(|range| {
+                allowed.iter().filter_map(move |allowed| {
+                    let first = range.0.max(allowed.0);
+                    let last = range.1.min(allowed.1);
+                    (first <= last).then(|| (first, last))
+                })
+            })
+            .collect();
+
+        WireguardEndpointData {
+            // Ranges overlap if the allowed ranges overlap
+            port_ranges: coalesce_port_ranges(port_ranges),
+            ..self.clone()
+        }
+    }
+
+    /// Constructs an endpoint for connecting to `host` on `port`, using the gateways given by
+    /// [`WireguardEndpointData::gateways_for`], or the primary gateways if there are none.
+    pub fn into_mullvad_endpoint(self, host: IpAddr, port: u16) -> MullvadEndpoint {
+        let (ipv4_gateway, ipv6_gateway) = self
+            .gateways_for(host)
+            .unwrap_or_else(|| (self.ipv4_gateway(), self.ipv6_gateway()));
+        MullvadEndpoint::Wireguard(MullvadWireguardEndpoint {
+            peer: wireguard::PeerConfig {
+                public_key: self.public_key,
+                endpoint: SocketAddr::new(host, port),
+                allowed_ips: all_of_the_internet(),
+                psk: None,
+            },
+            exit_peer: None,
+            ipv4_gateway,
+            ipv6_gateway,
+            source_port: None,
+        })
+    }
+}
+
+impl PartialEq for WireguardEndpointData {
+    fn eq(&self, other: &Self) -> bool {
+        self.sorted_port_ranges() == other.sorted_port_ranges()
+            && self.gateways == other.gateways
+            && self.public_key == other.public_key
+            && self.daita == other.daita
+            && self.feature_version == other.feature_version
+    }
+}
+
+impl Hash for WireguardEndpointData {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.sorted_port_ranges().hash(state);
+        self.gateways.hash(state);
+        self.public_key.hash(state);
+        self.daita.hash(state);
+        self.feature_version.hash(state);
+    }
 }
 
 impl fmt::Display for WireguardEndpointData {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         write!(
             f,
-            "gateways {} - {} port_ranges {{ {} }} public_key {}",
-            self.ipv4_gateway,
-            self.ipv6_gateway,
+            "gateways {} port_ranges {{ {} }} public_key {}",
+            self.gateways
+                .iter()
+                .map(|(ipv4_gateway, ipv6_gateway)| format!("{} - {}", ipv4_gateway, ipv6_gateway))
+                .collect::<Vec<_>>()
+                .join(","),
             self.port_ranges
                 .iter()
                 .map(|range| format!("[{} - {}]", range.0, range.1))
@@ -165,6 +1590,7 @@ impl fmt::Display for WireguardEndpointData {
 
 /// Used by `mullvad_api::RelayListProxy` to store bridge servers for a [`Relay`].
 #[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(default)]
 pub struct RelayBridges {
     pub shadowsocks: Vec<ShadowsocksEndpointData>,
@@ -182,10 +1608,15 @@ impl RelayBridges {
 
 /// Data needed to connect to a Shadowsocks endpoint at a [`Relay`].
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct ShadowsocksEndpointData {
     pub port: u16,
     pub cipher: String,
     pub password: String,
+    #[cfg_attr(
+        feature = "schema",
+        schemars(schema_with = "transport_protocol_schema")
+    )]
     pub protocol: TransportProtocol,
 }
 
@@ -200,6 +1631,7 @@ impl ShadowsocksEndpointData {
 }
 
 #[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(default)]
 pub struct RelayObfuscators {
     pub udp2tcp: Vec<Udp2TcpEndpointData>,
@@ -216,6 +1648,1122 @@ impl RelayObfuscators {
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Udp2TcpEndpointData {
     pub port: u16,
 }
+
+/// Schema for [`TransportProtocol`], which is defined in `talpid-types`.
+#[cfg(feature = "schema")]
+fn transport_protocol_schema(
+    _gen: &mut schemars::gen::SchemaGenerator,
+) -> schemars::schema::Schema {
+    schemars::schema::SchemaObject {
+        instance_type: Some(schemars::schema::InstanceType::String.into()),
+        enum_values: Some(vec!["udp".into(), "tcp".into()]),
+        ..Default::default()
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::relay_constraints::{Constraint, Ownership};
+
+    fn relay_list() -> RelayList {
+        RelayList {
+            etag: Some("\"a1b2c3\"".to_string()),
+            fetched_at: None,
+            countries: vec![RelayListCountry {
+                name: "Sweden".to_string(),
+                code: "se".to_string(),
+                cities: vec![RelayListCity {
+                    name: "Gothenburg".to_string(),
+                    code: "got".to_string(),
+                    latitude: 57.70887,
+                    longitude: 11.97456,
+                    relays: vec![
+                        Relay {
+                            hostname: "se9-wireguard".to_string(),
+                            ipv4_addr_in: "185.213.154.68".parse().unwrap(),
+                            ipv6_addr_in: Some("2a03:1b20:5:f011::a09f".parse().unwrap()),
+                            include_in_country: true,
+                            active: true,
+                            owned: true,
+                            provider: "31173".to_string(),
+                            weight: 1,
+                            tags: vec![],
+                            bandwidth_mbps: None,
+                            asn: None,
+                            channel: None,
+                            tunnels: RelayTunnels {
+                                openvpn: vec![],
+                                wireguard: vec![WireguardEndpointData {
+                                    port_ranges: vec![(53, 53), (4000, 33433)],
+                                    gateways: vec![(
+                                        "10.64.0.1".parse().unwrap(),
+                                        "fc00:bbbb:bbbb:bb01::1".parse().unwrap(),
+                                    )],
+                                    public_key: wireguard::PublicKey::from_base64(
+                                        "BLNHNoGO88LjV/wDBa7CUUwUzPq/fO2UwcGLy56hKy4=",
+                                    )
+                                    .unwrap(),
+                                    daita: false,
+                                    feature_version: 0,
+                                }],
+                            },
+                            bridges: RelayBridges::default(),
+                            obfuscators: RelayObfuscators {
+                                udp2tcp: vec![Udp2TcpEndpointData { port: 443 }],
+                            },
+                            location: Some(Location {
+                                country: "Sweden".to_string(),
+                                country_code: "se".to_string(),
+                                city: "Gothenburg".to_string(),
+                                city_code: "got".to_string(),
+                                latitude: 57.70887,
+                                longitude: 11.97456,
+                            }),
+                            congestion: None,
+                            rtt: None,
+                            added_at: None,
+                        },
+                        Relay {
+                            hostname: "se-got-001".to_string(),
+                            ipv4_addr_in: "185.213.154.131".parse().unwrap(),
+                            ipv6_addr_in: None,
+                            include_in_country: false,
+                            active: false,
+                            owned: false,
+                            provider: "M247".to_string(),
+                            weight: 100,
+                            tags: vec![],
+                            bandwidth_mbps: None,
+                            asn: None,
+                            channel: None,
+                            tunnels: RelayTunnels {
+                                openvpn: vec![
+                                    OpenVpnEndpointData {
+                                        port: 1194,
+                                        protocol: TransportProtocol::Udp,
+                                    },
+                                    OpenVpnEndpointData {
+                                        port: 443,
+                                        protocol: TransportProtocol::Tcp,
+                                    },
+                                ],
+                                wireguard: vec![],
+                            },
+                            bridges: RelayBridges {
+                                shadowsocks: vec![ShadowsocksEndpointData {
+                                    port: 443,
+                                    cipher: "aes-256-gcm".to_string(),
+                                    password: "mullvad".to_string(),
+                                    protocol: TransportProtocol::Tcp,
+                                }],
+                            },
+                            obfuscators: RelayObfuscators::default(),
+                            location: None,
+                            congestion: None,
+                            rtt: None,
+                            added_at: None,
+                        },
+                    ],
+                }],
+            }],
+        }
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn test_binary_round_trip() {
+        let relay_list = relay_list();
+
+        let bytes = relay_list
+            .to_bytes()
+            .expect("Failed to serialize relay list");
+        let decoded = RelayList::from_bytes(&bytes).expect("Failed to deserialize relay list");
+
+        assert_eq!(format!("{:?}", relay_list), format!("{:?}", decoded));
+    }
+
+    #[test]
+    fn test_json_skips_empty_relay_data() {
+        let relay_list = relay_list();
+        let relays = &relay_list.countries[0].cities[0].relays;
+
+        let serialized = serde_json::to_value(&relays[0]).unwrap();
+        assert!(serialized.get("bridges").is_none());
+        assert!(serialized.get("obfuscators").is_some());
+
+        let serialized = serde_json::to_value(&relays[1]).unwrap();
+        assert!(serialized.get("bridges").is_some());
+        assert!(serialized.get("obfuscators").is_none());
+    }
+
+    #[test]
+    fn test_diff() {
+        let old_list = relay_list();
+        assert!(old_list.diff(&old_list).is_empty());
+
+        let mut new_list = old_list.clone();
+        let relays = &mut new_list.countries[0].cities[0].relays;
+        // se9-wireguard is deactivated and se-got-001 is activated
+        relays[0].active = false;
+        relays[1].active = true;
+        let diff = old_list.diff(&new_list);
+        assert_eq!(diff.activated, vec!["se-got-001".to_string()]);
+        assert_eq!(diff.deactivated, vec!["se9-wireguard".to_string()]);
+        assert!(diff.added.is_empty());
+        assert!(diff.removed.is_empty());
+
+        let relays = &mut new_list.countries[0].cities[0].relays;
+        let mut added_relay = relays.remove(0);
+        added_relay.hostname = "se10-wireguard".to_string();
+        relays.push(added_relay);
+        let diff = old_list.diff(&new_list);
+        assert_eq!(diff.added, vec!["se10-wireguard".to_string()]);
+        assert_eq!(diff.removed, vec!["se9-wireguard".to_string()]);
+        assert_eq!(diff.activated, vec!["se-got-001".to_string()]);
+        assert!(diff.deactivated.is_empty());
+    }
+
+    #[test]
+    fn test_has_bridge_in() {
+        let mut relay_list = relay_list();
+        let sweden = LocationConstraint::Country("se".to_string());
+        let gothenburg = LocationConstraint::City("se".to_string(), "got".to_string());
+        let se9 = LocationConstraint::Hostname(
+            "se".to_string(),
+            "got".to_string(),
+            "se9-wireguard".to_string(),
+        );
+
+        // se-got-001 is the only bridge, and it is inactive
+        assert!(!relay_list.has_bridge_in(&gothenburg));
+
+        relay_list.countries[0].cities[0].relays[1].active = true;
+        assert!(relay_list.has_bridge_in(&gothenburg));
+        assert!(!relay_list.has_bridge_in(&se9));
+        assert!(!relay_list.has_bridge_in(&LocationConstraint::Country("no".to_string())));
+        // se-got-001 is not included in the country
+        assert!(!relay_list.has_bridge_in(&sweden));
+        relay_list.countries[0].cities[0].relays[1].include_in_country = true;
+        assert!(relay_list.has_bridge_in(&sweden));
+    }
+
+    #[test]
+    fn test_hostnames_for_location() {
+        let mut relay_list = relay_list();
+        let mut stockholm = relay_list.countries[0].cities[0].clone();
+        stockholm.name = "Stockholm".to_string();
+        stockholm.code = "sto".to_string();
+        stockholm.relays.truncate(1);
+        stockholm.relays[0].hostname = "se-sto-wg-001".to_string();
+        relay_list.countries[0].cities.push(stockholm);
+
+        // se-got-001 is not included in the country
+        assert_eq!(
+            relay_list.hostnames_for_location(&LocationConstraint::Country("se".to_string())),
+            vec!["se9-wireguard".to_string(), "se-sto-wg-001".to_string()]
+        );
+        assert_eq!(
+            relay_list.hostnames_for_location(&LocationConstraint::City(
+                "se".to_string(),
+                "got".to_string()
+            )),
+            vec!["se9-wireguard".to_string(), "se-got-001".to_string()]
+        );
+        assert_eq!(
+            relay_list.hostnames_for_location(&LocationConstraint::Hostname(
+                "se".to_string(),
+                "got".to_string(),
+                "se-got-001".to_string()
+            )),
+            vec!["se-got-001".to_string()]
+        );
+        assert!(relay_list
+            .hostnames_for_location(&LocationConstraint::Country("no".to_string()))
+            .is_empty());
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn test_json_schema() {
+        let schema = RelayList::json_schema();
+        let properties = &schema
+            .schema
+            .object
+            .as_ref()
+            .expect("Relay list schema is not an object")
+            .properties;
+        assert!(properties.contains_key("etag"));
+        assert!(properties.contains_key("countries"));
+        for definition in [
+            "Relay",
+            "RelayListCity",
+            "WireguardEndpointData",
+            "Location",
+        ] {
+            assert!(schema.definitions.contains_key(definition));
+        }
+    }
+
+    #[test]
+    fn test_wireguard_endpoint_eq_and_hash_ignore_port_range_order() {
+        use std::collections::hash_map::DefaultHasher;
+
+        let hash = |data: &WireguardEndpointData| {
+            let mut hasher = DefaultHasher::new();
+            data.hash(&mut hasher);
+            hasher.finish()
+        };
+
+        let relay_list = relay_list();
+        let endpoint = &relay_list.countries[0].cities[0].relays[0]
+            .tunnels
+            .wireguard[0];
+        let mut reordered_endpoint = endpoint.clone();
+        reordered_endpoint.port_ranges.reverse();
+        assert_ne!(endpoint.port_ranges, reordered_endpoint.port_ranges);
+        assert_eq!(endpoint, &reordered_endpoint);
+        assert_eq!(hash(endpoint), hash(&reordered_endpoint));
+        assert_eq!(
+            HashSet::from([endpoint.clone(), reordered_endpoint.clone()]).len(),
+            1
+        );
+
+        reordered_endpoint.port_ranges.pop();
+        assert_ne!(endpoint, &reordered_endpoint);
+        assert_ne!(hash(endpoint), hash(&reordered_endpoint));
+    }
+
+    #[test]
+    fn test_nearest_cities() {
+        let mut relay_list = relay_list();
+        let gothenburg = relay_list.countries[0].cities[0].clone();
+        for (name, code, latitude, longitude) in [
+            ("Stockholm", "sto", 59.3289, 18.0649),
+            ("Malmö", "mma", 55.607075, 13.002716),
+        ] {
+            let mut city = gothenburg.clone();
+            city.name = name.to_string();
+            city.code = code.to_string();
+            city.latitude = latitude;
+            city.longitude = longitude;
+            for relay in &mut city.relays {
+                relay.hostname = relay.hostname.replace("got", code);
+            }
+            relay_list.countries[0].cities.push(city);
+        }
+        // Copenhagen
+        let origin = Coordinates {
+            latitude: 55.6761,
+            longitude: 12.5683,
+        };
+
+        let nearest_cities = relay_list.nearest_cities(&origin, 2, &Constraint::<Ownership>::Any);
+        let city_codes: Vec<_> = nearest_cities
+            .iter()
+            .map(|(city, _relay)| city.code.as_str())
+            .collect();
+        assert_eq!(city_codes, vec!["mma", "got"]);
+        // se-got-001 is inactive, so the other relay is always the representative
+        for (_city, relay) in &nearest_cities {
+            assert_eq!(relay.hostname, "se9-wireguard");
+            assert!(relay.location.is_some());
+        }
+
+        // Skip cities without matching relays
+        relay_list.countries[0].cities[2].relays[0].owned = false;
+        let nearest_cities =
+            relay_list.nearest_cities(&origin, 3, &Constraint::Only(Ownership::MullvadOwned));
+        let city_codes: Vec<_> = nearest_cities
+            .iter()
+            .map(|(city, _relay)| city.code.as_str())
+            .collect();
+        assert_eq!(city_codes, vec!["got", "sto"]);
+    }
+
+    #[test]
+    fn test_connection_options() {
+        let relay_list = relay_list();
+        let relays = &relay_list.countries[0].cities[0].relays;
+
+        let wireguard_options = relays[0].connection_options();
+        assert!(wireguard_options
+            .iter()
+            .all(|option| option.tunnel_type == TunnelType::Wireguard
+                && option.endpoint.protocol == TransportProtocol::Udp));
+        let ports = |address: IpAddr| -> Vec<u16> {
+            wireguard_options
+                .iter()
+                .filter(|option| option.endpoint.address.ip() == address)
+                .map(|option| option.endpoint.address.port())
+                .collect()
+        };
+        // The range 4000-33433 is sampled
+        let expected_ports = vec![53, 4000, 8204, 12409, 16614, 20818, 25023, 29228, 33433];
+        assert_eq!(ports(relays[0].ipv4_addr_in.into()), expected_ports);
+        assert_eq!(
+            ports(relays[0].ipv6_addr_in.unwrap().into()),
+            expected_ports
+        );
+        assert_eq!(wireguard_options.len(), 2 * expected_ports.len());
+
+        let openvpn_options = relays[1].connection_options();
+        let address = IpAddr::from(relays[1].ipv4_addr_in);
+        assert_eq!(
+            openvpn_options,
+            vec![
+                ConnectionOption {
+                    tunnel_type: TunnelType::OpenVpn,
+                    endpoint: Endpoint::new(address, 1194, TransportProtocol::Udp),
+                },
+                ConnectionOption {
+                    tunnel_type: TunnelType::OpenVpn,
+                    endpoint: Endpoint::new(address, 443, TransportProtocol::Tcp),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_active() {
+        let relay_list = relay_list();
+        assert_eq!(relay_list.is_active("se9-wireguard"), Some(true));
+        assert_eq!(relay_list.is_active("se-got-001"), Some(false));
+        assert_eq!(relay_list.is_active("se-got-002"), None);
+    }
+
+    #[test]
+    fn test_countries_with_min_cities() {
+        let mut relay_list = relay_list();
+        let sweden = relay_list.countries[0].clone();
+        let mut norway = sweden.clone();
+        norway.name = "Norway".to_string();
+        norway.code = "no".to_string();
+        norway.cities.clear();
+        let mut germany = sweden.clone();
+        germany.name = "Germany".to_string();
+        germany.code = "de".to_string();
+        for code in ["ber", "fra", "dus"] {
+            let mut city = sweden.cities[0].clone();
+            city.code = code.to_string();
+            germany.cities.push(city);
+        }
+        relay_list.countries.extend([norway, germany]);
+
+        let country_codes = |min| -> Vec<&str> {
+            relay_list
+                .countries_with_min_cities(min)
+                .into_iter()
+                .map(|country| country.code.as_str())
+                .collect()
+        };
+        assert_eq!(country_codes(0), vec!["se", "no", "de"]);
+        assert_eq!(country_codes(1), vec!["se", "de"]);
+        assert_eq!(country_codes(2), vec!["de"]);
+        assert_eq!(country_codes(4), vec!["de"]);
+        assert!(country_codes(5).is_empty());
+    }
+
+    #[test]
+    fn test_central_relay_in_country() {
+        let mut relay_list = relay_list();
+        let gothenburg = relay_list.countries[0].cities[0].clone();
+        for (code, latitude, longitude) in [
+            ("sto", 59.3289, 18.0649),
+            ("mma", 55.607075, 13.002716),
+            ("lla", 65.584816, 22.156704),
+        ] {
+            let mut city = gothenburg.clone();
+            city.code = code.to_string();
+            city.latitude = latitude;
+            city.longitude = longitude;
+            for relay in &mut city.relays {
+                relay.hostname = format!("{}-{}", relay.hostname, code);
+                relay.location = None;
+            }
+            relay_list.countries[0].cities.push(city);
+        }
+        let se = "se".to_string();
+
+        let relay = relay_list
+            .central_relay_in_country(&se, &Constraint::<Ownership>::Any)
+            .unwrap();
+        assert_eq!(relay.location.unwrap().city_code, "sto");
+
+        // Pick the next closest city if the central one has no matching relays
+        relay_list.countries[0].cities[1].relays[0].owned = false;
+        let relay = relay_list
+            .central_relay_in_country(&se, &Constraint::Only(Ownership::MullvadOwned))
+            .unwrap();
+        assert_eq!(relay.location.unwrap().city_code, "got");
+
+        assert!(relay_list
+            .central_relay_in_country(&"no".to_string(), &Constraint::<Ownership>::Any)
+            .is_none());
+    }
+
+    #[test]
+    fn test_without_provider() {
+        let mut relay_list = relay_list();
+        let mut stockholm = relay_list.countries[0].cities[0].clone();
+        stockholm.name = "Stockholm".to_string();
+        stockholm.code = "sto".to_string();
+        stockholm.relays.remove(0);
+        relay_list.countries[0].cities.push(stockholm);
+        let mut norway = relay_list.countries[0].clone();
+        norway.name = "Norway".to_string();
+        norway.code = "no".to_string();
+        norway.cities.remove(0);
+        relay_list.countries.push(norway);
+
+        let filtered_list = relay_list.without_provider("M247");
+        assert!(filtered_list.relays().all(|relay| relay.provider != "M247"));
+        assert_eq!(filtered_list.countries.len(), 1);
+        let cities = &filtered_list.countries[0].cities;
+        assert_eq!(cities.len(), 1);
+        assert_eq!(cities[0].code, "got");
+        assert_eq!(cities[0].relays.len(), 1);
+        assert_eq!(cities[0].relays[0].hostname, "se9-wireguard");
+    }
+
+    #[test]
+    fn test_wireguard_only() {
+        let mut relay_list = relay_list();
+        let mut stockholm = relay_list.countries[0].cities[0].clone();
+        stockholm.name = "Stockholm".to_string();
+        stockholm.code = "sto".to_string();
+        stockholm.relays.remove(0);
+        relay_list.countries[0].cities.push(stockholm);
+        // A relay with both OpenVPN and WireGuard endpoints
+        let mut relay = relay_list.countries[0].cities[0].relays[1].clone();
+        relay.hostname = "se-got-002".to_string();
+        relay.tunnels.wireguard = relay_list.countries[0].cities[0].relays[0]
+            .tunnels
+            .wireguard
+            .clone();
+        relay_list.countries[0].cities[0].relays.push(relay);
+
+        let wireguard_list = relay_list.wireguard_only();
+        assert_eq!(wireguard_list.etag, relay_list.etag);
+        assert!(wireguard_list.relays().all(|relay| {
+            !relay.tunnels.wireguard.is_empty()
+                && relay.tunnels.openvpn.is_empty()
+                && relay.bridges.is_empty()
+        }));
+        assert_eq!(wireguard_list.countries.len(), 1);
+        let cities = &wireguard_list.countries[0].cities;
+        assert_eq!(cities.len(), 1);
+        let hostnames: Vec<&str> = cities[0]
+            .relays
+            .iter()
+            .map(|relay| relay.hostname.as_str())
+            .collect();
+        assert_eq!(hostnames, vec!["se9-wireguard", "se-got-002"]);
+        assert_eq!(
+            cities[0].relays[0].tunnels.wireguard,
+            relay_list.countries[0].cities[0].relays[0]
+                .tunnels
+                .wireguard
+        );
+        assert_eq!(cities[0].relays[0].obfuscators.udp2tcp.len(), 1);
+    }
+
+    #[test]
+    fn test_relay_ordering() {
+        let relays = &relay_list().countries[0].cities[0].relays;
+        let (se9, se_got) = (&relays[0], &relays[1]);
+        let mut se10 = se9.clone();
+        se10.hostname = "se10-wireguard".to_string();
+
+        // Relays are ordered and compared by hostname only
+        assert!(*se_got < se10);
+        assert!(se10 < *se9);
+        let mut reweighted_se9 = se9.clone();
+        reweighted_se9.weight = 1000;
+        assert_eq!(*se9, reweighted_se9);
+        assert_eq!(se9.cmp(&reweighted_se9), Ordering::Equal);
+        assert_ne!(*se9, se10);
+
+        // se-got-001 has the highest weight, and hostname breaks the tie between the others
+        let mut sorted_relays = vec![se9.clone(), se10.clone(), se_got.clone()];
+        sorted_relays.sort_by(Relay::by_weight);
+        let hostnames: Vec<_> = sorted_relays
+            .iter()
+            .map(|relay| relay.hostname.as_str())
+            .collect();
+        assert_eq!(
+            hostnames,
+            vec!["se-got-001", "se10-wireguard", "se9-wireguard"]
+        );
+
+        let relay_set: std::collections::BTreeSet<_> = sorted_relays.into_iter().collect();
+        assert_eq!(
+            relay_set.iter().next().map(|relay| relay.hostname.as_str()),
+            Some("se-got-001")
+        );
+    }
+
+    #[test]
+    fn test_rebuild_endpoint() {
+        let relay_list = relay_list();
+
+        match relay_list.rebuild_endpoint(
+            "se9-wireguard",
+            4001,
+            TransportProtocol::Udp,
+            TunnelType::Wireguard,
+        ) {
+            Some(MullvadEndpoint::Wireguard(endpoint)) => {
+                assert_eq!(
+                    endpoint.peer.endpoint,
+                    "185.213.154.68:4001".parse().unwrap()
+                );
+                assert_eq!(
+                    endpoint.peer.public_key,
+                    relay_list.countries[0].cities[0].relays[0]
+                        .tunnels
+                        .wireguard[0]
+                        .public_key
+                );
+            }
+            endpoint => panic!("Expected a WireGuard endpoint, got {:?}", endpoint),
+        }
+        match relay_list.rebuild_endpoint(
+            "se-got-001",
+            443,
+            TransportProtocol::Tcp,
+            TunnelType::OpenVpn,
+        ) {
+            Some(MullvadEndpoint::OpenVpn(endpoint)) => assert_eq!(
+                endpoint,
+                Endpoint::new(
+                    "185.213.154.131".parse::<Ipv4Addr>().unwrap(),
+                    443,
+                    TransportProtocol::Tcp
+                )
+            ),
+            endpoint => panic!("Expected an OpenVPN endpoint, got {:?}", endpoint),
+        }
+
+        // The port is no longer used by the relay
+        assert!(relay_list
+            .rebuild_endpoint(
+                "se9-wireguard",
+                80,
+                TransportProtocol::Udp,
+                TunnelType::Wireguard
+            )
+            .is_none());
+        // The relay is gone
+        assert!(relay_list
+            .rebuild_endpoint(
+                "se-got-002",
+                443,
+                TransportProtocol::Tcp,
+                TunnelType::OpenVpn
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn test_intersect_ports() {
+        let relay_list = relay_list();
+        let endpoint = relay_list.countries[0].cities[0].relays[0]
+            .tunnels
+            .wireguard[0]
+            .clone();
+        let endpoint = WireguardEndpointData {
+            port_ranges: vec![(53, 53), (4000, 33433), (33565, 51820)],
+            ..endpoint
+        };
+
+        // Overlapping
+        let intersection = endpoint.intersect_ports(&[(1, 5000), (51000, 60000)]);
+        assert_eq!(
+            intersection.port_ranges,
+            vec![(53, 53), (4000, 5000), (51000, 51820)]
+        );
+        assert_eq!(intersection.public_key, endpoint.public_key);
+
+        // Disjoint
+        assert!(endpoint
+            .intersect_ports(&[(54, 3999), (60000, 65535)])
+            .port_ranges
+            .is_empty());
+
+        // Fully contained
+        assert_eq!(
+            endpoint
+                .intersect_ports(&[(443, 443), (5000, 6000), (5500, 7000)])
+                .port_ranges,
+            vec![(5000, 7000)]
+        );
+        assert_eq!(
+            endpoint.intersect_ports(&[(1, 65535)]).port_ranges,
+            endpoint.port_ranges
+        );
+    }
+
+    #[test]
+    fn test_all_wireguard_ports() {
+        let mut relay_list = relay_list();
+        assert_eq!(
+            relay_list.all_wireguard_ports(),
+            vec![(53, 53), (4000, 33433)]
+        );
+
+        let relays = &mut relay_list.countries[0].cities[0].relays;
+        let mut endpoint = relays[0].tunnels.wireguard[0].clone();
+        // Overlapping, adjacent and disjoint ranges
+        endpoint.port_ranges = vec![(30000, 40000), (54, 60), (40001, 40010), (65535, 65535)];
+        relays[1].tunnels.wireguard.push(endpoint);
+        assert_eq!(
+            relay_list.all_wireguard_ports(),
+            vec![(53, 60), (4000, 40010), (65535, 65535)]
+        );
+
+        relay_list.countries.clear();
+        assert!(relay_list.all_wireguard_ports().is_empty());
+    }
+
+    #[test]
+    fn test_counts_by_tunnel_type() {
+        let mut relay_list = relay_list();
+        let relays = &mut relay_list.countries[0].cities[0].relays;
+        let mut relay = relays[0].clone();
+        relay.hostname = "se-got-002".to_string();
+        relay.tunnels.openvpn = relays[1].tunnels.openvpn.clone();
+        relays.push(relay);
+
+        let counts = relay_list.counts_by_tunnel_type();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[&TunnelType::Wireguard], 2);
+        assert_eq!(counts[&TunnelType::OpenVpn], 2);
+        assert_eq!(relay_list.bridge_count(), 1);
+
+        assert!(RelayList::empty().counts_by_tunnel_type().is_empty());
+        assert_eq!(RelayList::empty().bridge_count(), 0);
+    }
+
+    #[test]
+    fn test_supported_transport_protocols() {
+        use TransportProtocol::{Tcp, Udp};
+        let relay_list = relay_list();
+        let relays = &relay_list.countries[0].cities[0].relays;
+
+        let mut wireguard = relays[0].clone();
+        assert_eq!(wireguard.supported_transport_protocols(), vec![Udp, Tcp]);
+        wireguard.obfuscators.clear();
+        assert_eq!(wireguard.supported_transport_protocols(), vec![Udp]);
+
+        let mut openvpn = relays[1].clone();
+        assert_eq!(openvpn.supported_transport_protocols(), vec![Udp, Tcp]);
+        openvpn
+            .tunnels
+            .openvpn
+            .retain(|endpoint| endpoint.protocol == Tcp);
+        assert_eq!(openvpn.supported_transport_protocols(), vec![Tcp]);
+        // The udp2tcp obfuscator is only used for WireGuard
+        openvpn.tunnels.openvpn.clear();
+        openvpn.obfuscators = relays[0].obfuscators.clone();
+        assert!(openvpn.supported_transport_protocols().is_empty());
+    }
+
+    #[test]
+    fn test_openvpn_ports_grouped_by_protocol() {
+        let endpoints = [
+            (1302, TransportProtocol::Udp),
+            (443, TransportProtocol::Tcp),
+            (1301, TransportProtocol::Udp),
+            (80, TransportProtocol::Tcp),
+            (1302, TransportProtocol::Udp),
+        ]
+        .map(|(port, protocol)| OpenVpnEndpointData { port, protocol });
+
+        let groups = OpenVpnEndpointData::grouped_by_protocol(&endpoints);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[&TransportProtocol::Udp], vec![1301, 1302]);
+        assert_eq!(groups[&TransportProtocol::Tcp], vec![80, 443]);
+
+        assert!(OpenVpnEndpointData::grouped_by_protocol(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_relay_list_file() {
+        let path = std::env::temp_dir().join(format!(
+            "mullvad-types-relay-list-{}.json",
+            std::process::id()
+        ));
+
+        let relay_list = relay_list();
+        relay_list.to_file(&path).unwrap();
+        let read_relay_list = RelayList::from_file(&path).unwrap();
+        assert_eq!(
+            serde_json::to_value(&read_relay_list).unwrap(),
+            serde_json::to_value(&relay_list).unwrap()
+        );
+
+        std::fs::write(&path, r#"{"etag": null, "countries": [{"name": "Sweden""#).unwrap();
+        let error = RelayList::from_file(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        std::fs::write(&path, r#"{"etag": null, "countries": [}"#).unwrap();
+        let error = RelayList::from_file(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        std::fs::write(&path, r#"{"etag": null, "countries": 1}"#).unwrap();
+        let error = RelayList::from_file(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("line 1"));
+
+        std::fs::remove_file(&path).unwrap();
+        let error = RelayList::from_file(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut relay_list = relay_list();
+
+        let mut other = relay_list.clone();
+        other.etag = Some("\"d4e5f6\"".to_string());
+        let gothenburg = &mut other.countries[0].cities[0];
+        gothenburg.relays.remove(0);
+        let updated_relay = &mut gothenburg.relays[0];
+        updated_relay.weight = 200;
+        updated_relay.tunnels.openvpn.truncate(1);
+        updated_relay.obfuscators.udp2tcp = vec![Udp2TcpEndpointData { port: 80 }];
+        let mut berlin = gothenburg.clone();
+        berlin.name = "Berlin".to_string();
+        berlin.code = "ber".to_string();
+        berlin.relays[0].hostname = "de-ber-001".to_string();
+        other.countries.push(RelayListCountry {
+            name: "Germany".to_string(),
+            code: "de".to_string(),
+            cities: vec![berlin],
+        });
+
+        relay_list.merge(other);
+
+        assert_eq!(relay_list.etag, None);
+        let mut hostnames: Vec<_> = relay_list
+            .relays()
+            .map(|relay| relay.hostname.as_str())
+            .collect();
+        hostnames.sort_unstable();
+        assert_eq!(hostnames, vec!["de-ber-001", "se-got-001", "se9-wireguard"]);
+        let country_codes: Vec<_> = relay_list
+            .countries
+            .iter()
+            .map(|country| country.code.as_str())
+            .collect();
+        assert_eq!(country_codes, vec!["se", "de"]);
+        assert_eq!(relay_list.countries[0].cities.len(), 1);
+
+        let merged_relay = relay_list
+            .relays()
+            .find(|relay| relay.hostname == "se-got-001")
+            .unwrap();
+        assert_eq!(merged_relay.weight, 200);
+        assert_eq!(merged_relay.tunnels.openvpn.len(), 2);
+        assert_eq!(merged_relay.bridges.shadowsocks.len(), 1);
+        assert_eq!(
+            merged_relay.obfuscators.udp2tcp,
+            vec![Udp2TcpEndpointData { port: 80 }]
+        );
+    }
+
+    #[test]
+    fn test_latency_bucket() {
+        let mut relay = relay_list().countries[0].cities[0].relays[0].clone();
+        assert_eq!(relay.latency_bucket(), None);
+
+        let mut bucket = |millis| {
+            relay.rtt = Some(Duration::from_millis(millis));
+            relay.latency_bucket()
+        };
+        assert_eq!(bucket(0), Some(LatencyBucket::Good));
+        assert_eq!(bucket(100), Some(LatencyBucket::Good));
+        assert_eq!(bucket(101), Some(LatencyBucket::Medium));
+        assert_eq!(bucket(250), Some(LatencyBucket::Medium));
+        assert_eq!(bucket(251), Some(LatencyBucket::Poor));
+
+        let thresholds = LatencyThresholds {
+            good: Duration::from_millis(20),
+            medium: Duration::from_millis(50),
+        };
+        relay.rtt = Some(Duration::from_millis(20));
+        assert_eq!(
+            relay.latency_bucket_with(&thresholds),
+            Some(LatencyBucket::Good)
+        );
+        relay.rtt = Some(Duration::from_millis(50));
+        assert_eq!(
+            relay.latency_bucket_with(&thresholds),
+            Some(LatencyBucket::Medium)
+        );
+        relay.rtt = Some(Duration::from_millis(51));
+        assert_eq!(
+            relay.latency_bucket_with(&thresholds),
+            Some(LatencyBucket::Poor)
+        );
+    }
+
+    #[test]
+    fn test_diversity_score() {
+        let template = relay_list().countries[0].cities[0].relays[0].clone();
+        let relay = |hostname: &str, country_code: &str, city_code: &str, provider: &str| Relay {
+            hostname: hostname.to_string(),
+            provider: provider.to_string(),
+            asn: Some(39351),
+            location: Some(Location {
+                country_code: country_code.to_string(),
+                city_code: city_code.to_string(),
+                ..template.location.clone().unwrap()
+            }),
+            ..template.clone()
+        };
+
+        let same_country = [
+            relay("se-got-wg-001", "se", "got", "31173"),
+            relay("se-got-wg-002", "se", "got", "31173"),
+            relay("se-sto-wg-001", "se", "sto", "31173"),
+        ];
+        let mut spread_out = [
+            relay("se-got-wg-001", "se", "got", "31173"),
+            relay("de-ber-wg-001", "de", "ber", "M247"),
+            relay("us-nyc-wg-001", "us", "nyc", "xtom"),
+        ];
+        spread_out[2].asn = Some(8849);
+
+        let same_country_score = diversity_score(&same_country);
+        assert_eq!(
+            same_country_score,
+            DiversityScore {
+                countries: 1,
+                cities: 2,
+                providers: 1,
+                asns: 1,
+            }
+        );
+        let spread_out_score = diversity_score(&spread_out);
+        assert_eq!(
+            spread_out_score,
+            DiversityScore {
+                countries: 3,
+                cities: 3,
+                providers: 3,
+                asns: 2,
+            }
+        );
+        assert!(spread_out_score > same_country_score);
+
+        // Relays without a known location or ASN only count towards the providers
+        let mut unknown = template.clone();
+        unknown.location = None;
+        assert_eq!(
+            diversity_score(&[unknown]),
+            DiversityScore {
+                providers: 1,
+                ..DiversityScore::default()
+            }
+        );
+        assert_eq!(diversity_score(&[]), DiversityScore::default());
+    }
+
+    #[test]
+    fn test_audit() {
+        let mut relay_list = relay_list();
+        assert_eq!(relay_list.audit(), vec![]);
+
+        let relays = &mut relay_list.countries[0].cities[0].relays;
+        relays[0].tunnels.wireguard[0].port_ranges.clear();
+        relays[0].tunnels.wireguard[0].gateways[0].0 = Ipv4Addr::UNSPECIFIED;
+        // Issues with inactive relays are not reported
+        relays[1].tunnels.openvpn[0].port = 0;
+        let mut relay = relays[1].clone();
+        relay.hostname = "se-got-002".to_string();
+        relay.active = true;
+        relays.push(relay.clone());
+        relay.hostname = "se-got-003".to_string();
+        relay.tunnels.clear();
+        relay.bridges.clear();
+        relays.push(relay);
+
+        assert_eq!(
+            relay_list.audit(),
+            vec![
+                RelayAuditIssue::EmptyWireguardPortRanges("se9-wireguard".to_string()),
+                RelayAuditIssue::UnspecifiedWireguardGateway("se9-wireguard".to_string()),
+                RelayAuditIssue::InvalidOpenVpnPort("se-got-002".to_string()),
+                RelayAuditIssue::NoEndpoints("se-got-003".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_redacted() {
+        let mut relay_list = relay_list();
+        relay_list.countries[0].cities[0].relays[1]
+            .bridges
+            .shadowsocks[0]
+            .password = "s3cr3t-p4ssw0rd".to_string();
+
+        let redacted = relay_list.redacted();
+        let serialized = serde_json::to_string(&redacted).unwrap();
+        assert!(!serialized.contains("s3cr3t-p4ssw0rd"));
+        assert!(serialized.contains(REDACTED));
+        // Addresses are only masked on request
+        assert!(serialized.contains("185.213.154.68"));
+
+        let masked = redacted.with_masked_addresses();
+        let relay = &masked.countries[0].cities[0].relays[0];
+        assert_eq!(relay.ipv4_addr_in, Ipv4Addr::new(185, 213, 154, 0));
+        assert_eq!(
+            relay.ipv6_addr_in,
+            Some("2a03:1b20:5:f011::".parse().unwrap())
+        );
+        let serialized = serde_json::to_string(&masked).unwrap();
+        assert!(!serialized.contains("s3cr3t-p4ssw0rd"));
+        assert!(!serialized.contains("185.213.154.68"));
+    }
+
+    #[test]
+    fn test_validate_city_coordinates() {
+        let mut city = relay_list().countries[0].cities[0].clone();
+        assert_eq!(city.validate(), Ok(()));
+
+        for (latitude, longitude) in [(90.0, 180.0), (-90.0, -180.0), (0.0, 0.0)] {
+            city.latitude = latitude;
+            city.longitude = longitude;
+            assert_eq!(city.validate(), Ok(()));
+        }
+        for (latitude, longitude) in [
+            (90.1, 0.0),
+            (-91.0, 0.0),
+            (0.0, 180.5),
+            (0.0, -360.0),
+            (f64::INFINITY, 0.0),
+        ] {
+            city.latitude = latitude;
+            city.longitude = longitude;
+            assert_eq!(
+                city.validate(),
+                Err(InvalidCoordinates {
+                    latitude,
+                    longitude
+                })
+            );
+        }
+        city.latitude = f64::NAN;
+        assert!(city.validate().is_err());
+    }
+
+    #[test]
+    fn test_mock() {
+        let relay_list = RelayList::mock(3, 2, 5);
+        assert_eq!(relay_list.countries.len(), 3);
+        assert!(relay_list
+            .countries
+            .iter()
+            .all(|country| country.cities.len() == 2));
+        assert!(relay_list
+            .countries
+            .iter()
+            .flat_map(|country| &country.cities)
+            .all(|city| city.relays.len() == 5));
+        assert_eq!(relay_list.relays().count(), 30);
+        assert_eq!(relay_list.audit(), vec![]);
+
+        // Hostnames, country codes and city codes are unique
+        let hostnames: HashSet<&str> = relay_list
+            .relays()
+            .map(|relay| relay.hostname.as_str())
+            .collect();
+        assert_eq!(hostnames.len(), 30);
+        let country_codes: HashSet<&str> = relay_list
+            .countries
+            .iter()
+            .map(|country| country.code.as_str())
+            .collect();
+        assert_eq!(country_codes.len(), 3);
+
+        let wireguard_relays = relay_list
+            .relays()
+            .filter(|relay| !relay.tunnels.wireguard.is_empty())
+            .count();
+        let openvpn_relays = relay_list
+            .relays()
+            .filter(|relay| !relay.tunnels.openvpn.is_empty())
+            .count();
+        assert_eq!((wireguard_relays, openvpn_relays), (18, 12));
+
+        // The list is deterministic
+        assert_eq!(
+            format!("{:?}", relay_list),
+            format!("{:?}", RelayList::mock(3, 2, 5))
+        );
+        assert_eq!(RelayList::mock(0, 2, 5).relays().count(), 0);
+    }
+
+    #[test]
+    fn test_wireguard_gateways() {
+        // Relay lists stored before multiple gateways were supported have a single pair
+        let single: WireguardEndpointData = serde_json::from_value(serde_json::json!({
+            "port_ranges": [[53, 53]],
+            "ipv4_gateway": "10.64.0.1",
+            "ipv6_gateway": "fc00:bbbb:bbbb:bb01::1",
+            "public_key": "BLNHNoGO88LjV/wDBa7CUUwUzPq/fO2UwcGLy56hKy4=",
+        }))
+        .unwrap();
+        let primary: (Ipv4Addr, Ipv6Addr) = (
+            "10.64.0.1".parse().unwrap(),
+            "fc00:bbbb:bbbb:bb01::1".parse().unwrap(),
+        );
+        assert_eq!(single.gateways, vec![primary]);
+        assert_eq!(single.ipv4_gateway(), primary.0);
+        assert_eq!(single.ipv6_gateway(), primary.1);
+        assert_eq!(
+            single.gateways_for("185.213.154.68".parse().unwrap()),
+            Some(primary)
+        );
+
+        let mut multiple = single.clone();
+        let secondary: (Ipv4Addr, Ipv6Addr) = (
+            "10.65.0.1".parse().unwrap(),
+            "fc00:bbbb:bbbb:bb02::1".parse().unwrap(),
+        );
+        multiple.gateways = vec![(primary.0, Ipv6Addr::UNSPECIFIED), secondary];
+        assert_eq!(multiple.ipv4_gateway(), primary.0);
+        assert_eq!(
+            multiple.gateways_for("185.213.154.68".parse().unwrap()),
+            Some((primary.0, Ipv6Addr::UNSPECIFIED))
+        );
+        // Only pairs with an IPv6 gateway can be used over IPv6
+        let ipv6_host: IpAddr = "2a03:1b20:5:f011::a09f".parse().unwrap();
+        assert_eq!(multiple.gateways_for(ipv6_host), Some(secondary));
+        match multiple.clone().into_mullvad_endpoint(ipv6_host, 53) {
+            MullvadEndpoint::Wireguard(endpoint) => {
+                assert_eq!(endpoint.ipv4_gateway, secondary.0);
+                assert_eq!(endpoint.ipv6_gateway, secondary.1);
+            }
+            MullvadEndpoint::OpenVpn(_) => unreachable!(),
+        }
+
+        // The primary gateways are also stored in the old fields
+        let serialized = serde_json::to_value(&multiple).unwrap();
+        assert_eq!(serialized["ipv4_gateway"], "10.64.0.1");
+        assert_eq!(serialized["ipv6_gateway"], "::");
+        let deserialized: WireguardEndpointData = serde_json::from_value(serialized).unwrap();
+        assert_eq!(deserialized, multiple);
+
+        multiple.gateways.clear();
+        assert_eq!(multiple.gateways_for(ipv6_host), None);
+        assert_eq!(multiple.ipv4_gateway(), Ipv4Addr::UNSPECIFIED);
+    }
+}